@@ -0,0 +1,49 @@
+//! Benchmarks capturing the output of a command which produces a large amount of output.
+
+use std::{
+    fs::{read_to_string, write},
+    process::Command,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use lang_tester::{LangTester, Reporter};
+use tempfile::TempDir;
+
+/// The number of bytes the benchmarked command writes to stdout.
+const OUTPUT_BYTES: usize = 50 * 1024 * 1024;
+
+/// A reporter which prints nothing, so that only the cost of running the test is measured.
+struct Silent;
+
+impl Reporter for Silent {}
+
+fn capture_output(c: &mut Criterion) {
+    let test_dir = TempDir::new().unwrap();
+    write(test_dir.path().join("big.test"), "VM:\n  stdout: ...\n").unwrap();
+    let mut group = c.benchmark_group("output");
+    group.sample_size(10);
+    group.bench_function("capture_50MiB", |b| {
+        b.iter(|| {
+            let summary = LangTester::new()
+                .use_cmdline_args(false)
+                .test_dir(test_dir.path().to_str().unwrap())
+                .test_path_filter(|p| p.extension().and_then(|x| x.to_str()) == Some("test"))
+                .test_extract(|p| read_to_string(p).unwrap())
+                .test_cmds(|_| {
+                    let mut vm = Command::new("sh");
+                    vm.args([
+                        "-c",
+                        &format!("head -c {} /dev/zero | tr '\\0' a", OUTPUT_BYTES),
+                    ]);
+                    vec![("VM", vm)]
+                })
+                .reporter(Silent)
+                .run_with_summary();
+            assert_eq!(summary.passed(), 1);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, capture_output);
+criterion_main!(benches);
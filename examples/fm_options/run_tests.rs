@@ -30,7 +30,7 @@ fn main() {
         })
         .test_cmds(move |p| {
             let mut vm = Command::new("python3");
-            vm.args(&[p.to_str().unwrap()]);
+            vm.args([p.to_str().unwrap()]);
             vec![("VM", vm)]
         })
         .run();
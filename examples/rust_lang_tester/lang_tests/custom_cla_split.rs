@@ -0,0 +1,10 @@
+// Run-time:
+//   exec-arg: 1
+//   exec-args-split: 2 3 "4 5"
+
+use std::env;
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    assert_eq!(args, vec!["1", "2", "3", "4 5"]);
+}
@@ -2,10 +2,19 @@
 //   env-var: XYZ=123
 //   env-var: XYZ=456
 //   env-var: ABC=789 012
+//   env-var: DEF=345
+//   env-var: DEF
+//   env-var: HOME
 
 use std::env;
 
 fn main() {
     assert_eq!(env::var("XYZ").unwrap(), "456".to_owned());
     assert_eq!(env::var("ABC").unwrap(), "789 012");
+    assert!(env::var("DEF").is_err());
+    assert!(env::var("HOME").is_err());
+    assert_eq!(env::var("LANG_TESTER_TEST_NAME").unwrap(), "custom_env.rs");
+    assert!(env::var("LANG_TESTER_TEST_PATH")
+        .unwrap()
+        .ends_with("custom_env.rs"));
 }
@@ -0,0 +1,10 @@
+// Run-time:
+//   stdout-exact:
+//     a  
+//       b
+//   stderr-exact:
+
+fn main() {
+    println!("a  ");
+    println!("  b");
+}
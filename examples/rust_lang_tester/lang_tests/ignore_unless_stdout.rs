@@ -0,0 +1,9 @@
+// # Ignore this test unless the output of ignore-if matches.
+// ignore-if: echo version 1.2
+// ignore-unless-stdout: version 1...
+// Run-time:
+//   stdout: check
+
+fn main() {
+    println!("check");
+}
@@ -0,0 +1,9 @@
+// # Always ignore this test, since the output of ignore-if does not match.
+// ignore-if: echo version 1.2
+// ignore-unless-stdout: version 2...
+// Compiler:
+//   status: success
+
+fn main() {
+    panic!("Shouldn't happen.");
+}
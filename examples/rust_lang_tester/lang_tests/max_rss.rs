@@ -0,0 +1,9 @@
+// Run-time:
+//   max-rss: 268435456
+//   status: signal
+
+fn main() {
+    // Allocating 1GiB exceeds the limit, causing the allocator to abort.
+    let v = vec![1u8; 1 << 30];
+    println!("{}", v.len());
+}
@@ -0,0 +1,11 @@
+// Compiler:
+//   stderr:
+//     warning: unused variable: `x`
+//      --> $FILE:10:9
+//     ...
+//     warning: 1 warning emitted
+
+fn main() {
+    // $NAME and $DIR are also available.
+    let x = 0;
+}
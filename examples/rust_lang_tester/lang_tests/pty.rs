@@ -0,0 +1,18 @@
+// Run-time:
+//   pty: true
+//   stdin:
+//     a
+//     b
+//   stdout:
+//     terminal: true
+//     Hello a
+//     b
+
+use std::io::{IsTerminal, Read, stdin, stdout};
+
+fn main() {
+    println!("terminal: {}", stdin().is_terminal() && stdout().is_terminal());
+    let mut buf = String::new();
+    stdin().read_to_string(&mut buf).unwrap();
+    println!("Hello {}", buf);
+}
@@ -0,0 +1,10 @@
+// Run-time:
+//   stdout-regex:
+//     ^Hello [0-9]+$
+//     ^world\.$
+//   stderr-regex: \A\z
+
+fn main() {
+    println!("Hello 123");
+    println!("world.");
+}
@@ -4,13 +4,11 @@
 //!
 //! See the test files in `lang_tests/` for example.
 
-use std::{fs::read_to_string, path::PathBuf, process::Command};
+use std::{path::PathBuf, process::Command};
 
 use lang_tester::LangTester;
 use tempfile::TempDir;
 
-static COMMENT_PREFIX: &str = "//";
-
 fn main() {
     // We use rustc to compile files into a binary: we store those binary files into `tempdir`.
     // This may not be necessary for other languages.
@@ -22,18 +20,7 @@ fn main() {
         // Treat lines beginning with "#" as comments.
         .comment_prefix("#")
         // Extract the first sequence of commented line(s) as the tests.
-        .test_extract(|p| {
-            read_to_string(p)
-                .unwrap()
-                .lines()
-                // Skip non-commented lines at the start of the file.
-                .skip_while(|l| !l.starts_with(COMMENT_PREFIX))
-                // Extract consecutive commented lines.
-                .take_while(|l| l.starts_with(COMMENT_PREFIX))
-                .map(|l| &l[COMMENT_PREFIX.len()..])
-                .collect::<Vec<_>>()
-                .join("\n")
-        })
+        .extract_comment_block("//")
         // We have two test commands:
         //   * `Compiler`: runs rustc.
         //   * `Run-time`: if rustc does not error, and the `Compiler` tests succeed, then the
@@ -44,7 +31,7 @@ fn main() {
             exe.push(&tempdir);
             exe.push(p.file_stem().unwrap());
             let mut compiler = Command::new("rustc");
-            compiler.args(&["-o", exe.to_str().unwrap(), p.to_str().unwrap()]);
+            compiler.args(["-o", exe.to_str().unwrap(), p.to_str().unwrap()]);
             // Test command 2: run `tempdir/x`.
             let runtime = Command::new(exe);
             vec![("Compiler", compiler), ("Run-time", runtime)]
@@ -0,0 +1,15 @@
+# VM:
+#   status: success
+#   rerun-if-signal: 9
+
+import os, signal, sys
+
+cookie = os.path.join(os.environ["CARGO_TARGET_TMPDIR"], "rerun_signal_cookie")
+i = 0
+if os.path.exists(cookie):
+    i = int(open(cookie, "r").read().strip()) + 1
+    if i == 5:
+        sys.exit(0)
+
+open(cookie, "w").write(str(i))
+os.kill(os.getpid(), signal.SIGKILL)
@@ -11,6 +11,7 @@ use std::{
 /// have to leave cookies behind in target/tmp, which we must clear before starting a test run.
 /// This is horrible, but without doing something stateful, we can't test anything.
 const COOKIES: &[&str] = &[
+    "rerun_signal_cookie",
     "rerun_status_cookie",
     "rerun_stderr_cookie",
     "rerun_stdout_cookie",
@@ -55,7 +56,7 @@ fn main() {
         })
         .test_cmds(move |p| {
             let mut vm = Command::new("python3");
-            vm.args(&[p.to_str().unwrap()]);
+            vm.args([p.to_str().unwrap()]);
             vec![("VM", vm)]
         })
         .run();
@@ -0,0 +1,4 @@
+# Run:
+#   stdout:
+
+cat > /dev/null
@@ -0,0 +1,5 @@
+# Run:
+#   stdin-remaining: any
+#   stdout: line
+
+head -n1
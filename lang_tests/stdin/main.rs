@@ -0,0 +1,21 @@
+//! Tests that commands which do not consume all of their stdin can be checked with
+//! `stdin-remaining`. Each test is given far more stdin than fits in an operating system pipe
+//! buffer, so a command which stops reading early leaves some of it unwritten.
+
+use std::process::Command;
+
+use lang_tester::LangTester;
+
+fn main() {
+    LangTester::new()
+        .test_dir("lang_tests/stdin/")
+        .test_path_filter(|p| p.extension().and_then(|x| x.to_str()) == Some("sh"))
+        .extract_comment_block("# ")
+        .default_stdin("run", "line\n".repeat(100_000))
+        .test_cmds(move |p| {
+            let mut run = Command::new("sh");
+            run.args([p.to_str().unwrap()]);
+            vec![("Run", run)]
+        })
+        .run();
+}
@@ -0,0 +1,4 @@
+# Run:
+#   stdin-remaining: any
+
+exit 0
@@ -0,0 +1,319 @@
+//! A simple line-based diff, used to show the user how a command's output differs from what was
+//! expected.
+
+use std::cmp::max;
+
+/// The number of unchanged lines shown either side of a change.
+const CONTEXT: usize = 3;
+/// If (number of expected lines * number of actual lines) exceeds this, we don't attempt to find a
+/// minimal diff, since doing so would take too much time and memory: instead, all of the expected
+/// lines are shown as removed and all of the actual lines as added.
+const MAX_DIFF_CELLS: usize = 10_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    /// Line `.0` of the expected text is the same as line `.1` of the actual text.
+    Equal(usize, usize),
+    /// Line `.0` of the expected text is not present in the actual text.
+    Delete(usize),
+    /// Line `.0` of the actual text is not present in the expected text.
+    Insert(usize),
+    /// Line `.0` of the expected text is a `...` wildcard.
+    Wildcard(usize),
+    /// Line `.0` of the actual text is matched by a preceding `...` wildcard. Such lines are not
+    /// shown in the diff.
+    Absorbed(usize),
+}
+
+/// Return a unified diff (with `expected` as the "old" text and `actual` as the "new" text) or the
+/// empty string if `expected == actual`. Lines are split solely on `\n`, so differences in
+/// trailing whitespace, carriage returns, and the presence of a final newline are all reported.
+pub(crate) fn unified_diff(expected: &str, actual: &str) -> String {
+    if expected == actual {
+        return String::new();
+    }
+    let exp_lines = expected.split('\n').collect::<Vec<_>>();
+    let act_lines = actual.split('\n').collect::<Vec<_>>();
+    let ops = diff_ops(&exp_lines, &act_lines, |e, a| e == a);
+    render(&exp_lines, &act_lines, &ops)
+}
+
+/// Return a unified diff between the fuzzy match pattern `pattern` (in the format used by
+/// [`fm`](https://crates.io/crates/fm)) and `actual`, or the empty string if no differences can be
+/// found. Lines consisting solely of `...` match zero or more lines of `actual`, lines starting
+/// and/or ending in `...` are not anchored to the start and/or end of a line, and leading and
+/// trailing whitespace (including a final newline) is ignored. This is an approximation of `fm`'s
+/// matching, intended only to show the user roughly where a failed match went wrong.
+pub(crate) fn fuzzy_diff(pattern: &str, actual: &str) -> String {
+    let exp_lines = pattern.lines().collect::<Vec<_>>();
+    let act_lines = actual.lines().collect::<Vec<_>>();
+    let is_wildcard = |op| matches!(op, Op::Delete(e) if exp_lines[e].trim() == WILDCARD);
+    let raw_ops = diff_ops(&exp_lines, &act_lines, fuzzy_line_eq);
+    let mut ops = Vec::with_capacity(raw_ops.len());
+    let mut i = 0;
+    while i < raw_ops.len() {
+        let op = raw_ops[i];
+        i += 1;
+        let Op::Delete(e) = op else {
+            ops.push(op);
+            continue;
+        };
+        if !is_wildcard(op) {
+            ops.push(op);
+            continue;
+        }
+        ops.push(Op::Wildcard(e));
+        // The wildcard can match any of the actual lines before the next matching line. Pair as
+        // many of those lines as possible with unmatched expected lines, so that near misses are
+        // shown, and treat the rest (the earliest) as matched by the wildcard.
+        let mut j = i;
+        while j < raw_ops.len() && is_change(raw_ops[j]) && !is_wildcard(raw_ops[j]) {
+            j += 1;
+        }
+        let region = &raw_ops[i..j];
+        let inserts = region.iter().filter(|x| matches!(x, Op::Insert(_))).count();
+        let mut absorb = inserts.saturating_sub(region.len() - inserts);
+        for &op in region {
+            match op {
+                Op::Insert(a) if absorb > 0 => {
+                    ops.push(Op::Absorbed(a));
+                    absorb -= 1;
+                }
+                _ => ops.push(op),
+            }
+        }
+        i = j;
+    }
+    if !ops.iter().any(|x| is_change(*x)) {
+        return String::new();
+    }
+    render(&exp_lines, &act_lines, &ops)
+}
+
+/// The wildcard operator in fuzzy match patterns.
+const WILDCARD: &str = "...";
+
+/// Does the fuzzy match pattern line `ptn` match the line `act`? Lines consisting solely of `...`
+/// never match, since they can match any number of lines: `fuzzy_diff` deals with them.
+fn fuzzy_line_eq(ptn: &str, act: &str) -> bool {
+    let (ptn, act) = (ptn.trim(), act.trim());
+    if ptn == WILDCARD {
+        return false;
+    }
+    match (ptn.strip_prefix(WILDCARD), ptn.strip_suffix(WILDCARD)) {
+        (Some(_), Some(_)) if ptn.len() >= 2 * WILDCARD.len() => {
+            act.contains(&ptn[WILDCARD.len()..ptn.len() - WILDCARD.len()])
+        }
+        (Some(x), _) => act.ends_with(x),
+        (_, Some(x)) => act.starts_with(x),
+        (None, None) => ptn == act,
+    }
+}
+
+/// Render `ops` (which turn `exp_lines` into `act_lines`) as a unified diff.
+fn render(exp_lines: &[&str], act_lines: &[&str], ops: &[Op]) -> String {
+    let mut out = String::from("--- expected\n+++ actual\n");
+    let mut i = 0;
+    while i < ops.len() {
+        if !is_change(ops[i]) {
+            i += 1;
+            continue;
+        }
+        // `i` is the first change in a new hunk: extend the hunk for as long as the next change
+        // is close enough that the two hunks' context would overlap.
+        // Context is measured in shown lines, so lines absorbed by a wildcard don't count.
+        let mut start = i;
+        let mut shown = 0;
+        while start > 0 && shown < CONTEXT {
+            start -= 1;
+            if !matches!(ops[start], Op::Absorbed(_)) {
+                shown += 1;
+            }
+        }
+        let mut end = i;
+        let mut gap = 0;
+        for (j, op) in ops.iter().enumerate().skip(i + 1) {
+            if is_change(*op) {
+                end = j;
+                gap = 0;
+            } else if !matches!(op, Op::Absorbed(_)) {
+                gap += 1;
+                if gap > 2 * CONTEXT {
+                    break;
+                }
+            }
+        }
+        let mut end = end + 1;
+        let mut shown = 0;
+        while end < ops.len() && shown < CONTEXT {
+            if !matches!(ops[end], Op::Absorbed(_)) {
+                shown += 1;
+            }
+            end += 1;
+        }
+
+        let (mut exp_start, mut act_start) = (None, None);
+        let (mut exp_count, mut act_count) = (0, 0);
+        let mut body = String::new();
+        for op in &ops[start..end] {
+            match *op {
+                Op::Equal(e, a) => {
+                    exp_start.get_or_insert(e);
+                    act_start.get_or_insert(a);
+                    exp_count += 1;
+                    act_count += 1;
+                    body.push_str(&format!(" {}\n", exp_lines[e]));
+                }
+                Op::Delete(e) => {
+                    exp_start.get_or_insert(e);
+                    exp_count += 1;
+                    body.push_str(&format!("-{}\n", exp_lines[e]));
+                }
+                Op::Insert(a) => {
+                    act_start.get_or_insert(a);
+                    act_count += 1;
+                    body.push_str(&format!("+{}\n", act_lines[a]));
+                }
+                Op::Wildcard(e) => {
+                    exp_start.get_or_insert(e);
+                    exp_count += 1;
+                    body.push_str(&format!(" {}\n", exp_lines[e]));
+                }
+                Op::Absorbed(a) => {
+                    act_start.get_or_insert(a);
+                    act_count += 1;
+                }
+            }
+        }
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n{}",
+            exp_start.map(|x| x + 1).unwrap_or(0),
+            exp_count,
+            act_start.map(|x| x + 1).unwrap_or(0),
+            act_count,
+            body
+        ));
+        i = end;
+    }
+    out
+}
+
+/// Is `op` a difference between the expected and actual text?
+fn is_change(op: Op) -> bool {
+    matches!(op, Op::Delete(_) | Op::Insert(_))
+}
+
+/// Compute the sequence of operations which turns `exp` into `act`, using a longest common
+/// subsequence, where `eq(e, a)` decides whether the expected line `e` matches the actual line `a`.
+fn diff_ops(exp: &[&str], act: &[&str], eq: impl Fn(&str, &str) -> bool) -> Vec<Op> {
+    // Common prefixes and suffixes are frequent and cheap to find, so remove them before doing
+    // more expensive work.
+    let mut pre = 0;
+    while pre < exp.len() && pre < act.len() && eq(exp[pre], act[pre]) {
+        pre += 1;
+    }
+    let mut suf = 0;
+    while suf < exp.len() - pre
+        && suf < act.len() - pre
+        && eq(exp[exp.len() - 1 - suf], act[act.len() - 1 - suf])
+    {
+        suf += 1;
+    }
+    let exp_mid = &exp[pre..exp.len() - suf];
+    let act_mid = &act[pre..act.len() - suf];
+
+    let mut ops = (0..pre).map(|i| Op::Equal(i, i)).collect::<Vec<_>>();
+    if exp_mid.len().saturating_mul(act_mid.len()) <= MAX_DIFF_CELLS {
+        // `lcs[i * w + j]` is the length of the longest common subsequence of `exp_mid[i..]` and
+        // `act_mid[j..]`.
+        let w = act_mid.len() + 1;
+        let mut lcs = vec![0usize; (exp_mid.len() + 1) * w];
+        for i in (0..exp_mid.len()).rev() {
+            for j in (0..act_mid.len()).rev() {
+                lcs[i * w + j] = if eq(exp_mid[i], act_mid[j]) {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    max(lcs[(i + 1) * w + j], lcs[i * w + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < exp_mid.len() && j < act_mid.len() {
+            if eq(exp_mid[i], act_mid[j]) {
+                ops.push(Op::Equal(pre + i, pre + j));
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * w + j] >= lcs[i * w + j + 1] {
+                ops.push(Op::Delete(pre + i));
+                i += 1;
+            } else {
+                ops.push(Op::Insert(pre + j));
+                j += 1;
+            }
+        }
+        ops.extend((i..exp_mid.len()).map(|i| Op::Delete(pre + i)));
+        ops.extend((j..act_mid.len()).map(|j| Op::Insert(pre + j)));
+    } else {
+        ops.extend((0..exp_mid.len()).map(|i| Op::Delete(pre + i)));
+        ops.extend((0..act_mid.len()).map(|j| Op::Insert(pre + j)));
+    }
+    ops.extend((0..suf).map(|k| Op::Equal(exp.len() - suf + k, act.len() - suf + k)));
+    ops
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n"), "");
+        assert_eq!(
+            unified_diff("a\nb\nc", "a\nx\nc"),
+            "--- expected\n+++ actual\n@@ -1,3 +1,3 @@\n a\n-b\n+x\n c\n"
+        );
+        assert_eq!(
+            unified_diff("a", "a\n"),
+            "--- expected\n+++ actual\n@@ -1,1 +1,2 @@\n a\n+\n"
+        );
+        assert_eq!(
+            unified_diff("a ", "a"),
+            "--- expected\n+++ actual\n@@ -1,1 +1,1 @@\n-a \n+a\n"
+        );
+        // Changes far apart produce separate hunks.
+        let exp = (0..20).map(|i| i.to_string()).collect::<Vec<_>>();
+        let mut act = exp.clone();
+        act[2] = "x".to_owned();
+        act[17] = "y".to_owned();
+        let (exp, act) = (exp.join("\n"), act.join("\n"));
+        assert_eq!(
+            unified_diff(&exp, &act),
+            "--- expected\n+++ actual\n\
+             @@ -1,6 +1,6 @@\n 0\n 1\n-2\n+x\n 3\n 4\n 5\n\
+             @@ -15,6 +15,6 @@\n 14\n 15\n 16\n-17\n+y\n 18\n 19\n"
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_diff() {
+        assert_eq!(fuzzy_diff("a\n...\nd", "a\nb\nc\nd\n"), "");
+        assert_eq!(fuzzy_diff("  a...\n...b\n...c...", "abc\nab\nxcx  "), "");
+        assert_eq!(
+            fuzzy_diff("a\nb\nc", "a\nx\nc"),
+            "--- expected\n+++ actual\n@@ -1,3 +1,3 @@\n a\n-b\n+x\n c\n"
+        );
+        // Lines matched by a wildcard are not shown as added, but near misses are shown.
+        assert_eq!(
+            fuzzy_diff("a\n...\nmiddle\nz", "a\nb\nc\nmidle\nz"),
+            "--- expected\n+++ actual\n@@ -1,4 +1,5 @@\n a\n ...\n-middle\n+midle\n z\n"
+        );
+        assert_eq!(
+            fuzzy_diff("a\n...\nd\ne", "a\nb\nc\nd\nx"),
+            "--- expected\n+++ actual\n@@ -1,4 +1,5 @@\n a\n ...\n d\n-e\n+x\n"
+        );
+        assert_eq!(
+            fuzzy_diff("a\n...\nd", "a\nb\nc"),
+            "--- expected\n+++ actual\n@@ -1,3 +1,3 @@\n a\n ...\n-d\n+c\n"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::{error::Error, fmt, io, path::PathBuf};
+
+/// An error which prevented tests from being run to completion. Note that a test failing is not
+/// an error in this sense.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LangTesterError {
+    /// The `LangTester` was not configured correctly.
+    Config(String),
+    /// Invalid command-line arguments were given, or `--help` was passed: the string is a message
+    /// (including a usage summary) suitable for showing to the user.
+    Usage(String),
+    /// A test file (or directory) could not be accessed.
+    Io { path: PathBuf, err: io::Error },
+    /// The test data in a test file could not be parsed.
+    Parse {
+        path: PathBuf,
+        /// The line number (starting from 1) in the test data (i.e. as returned by `test_extract`)
+        /// at which the error was found.
+        line: usize,
+        msg: String,
+    },
+    /// A test file references command names not returned by `test_cmds`.
+    UnknownCommandNames {
+        test_name: String,
+        names: Vec<String>,
+    },
+    /// Two or more distinct test files would be reported under the same test name.
+    DuplicateTestNames {
+        test_name: String,
+        paths: Vec<PathBuf>,
+    },
+    /// A command could not be spawned.
+    Spawn {
+        test_name: String,
+        cmd: String,
+        err: io::Error,
+    },
+    /// Communicating with a spawned command failed.
+    Command { test_name: String, msg: String },
+    /// The handler for `SIGINT` and `SIGTERM` could not be installed.
+    Signals(io::Error),
+}
+
+impl fmt::Display for LangTesterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LangTesterError::Config(msg) | LangTesterError::Usage(msg) => write!(f, "{}", msg),
+            LangTesterError::Io { path, err } => write!(f, "{}: {}", path.display(), err),
+            LangTesterError::Parse { path, line, msg } => {
+                write!(f, "{}: line {}: {}", path.display(), line, msg)
+            }
+            LangTesterError::UnknownCommandNames { test_name, names } => write!(
+                f,
+                "{}: command name(s) '{}' in tests are not found in the actual commands.",
+                test_name,
+                names.join(", ")
+            ),
+            LangTesterError::DuplicateTestNames { test_name, paths } => write!(
+                f,
+                "{}: test name is shared by multiple test files: {}",
+                test_name,
+                paths
+                    .iter()
+                    .map(|x| x.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            LangTesterError::Spawn {
+                test_name,
+                cmd,
+                err,
+            } => write!(f, "{}: couldn't run command {}: {}", test_name, cmd, err),
+            LangTesterError::Command { test_name, msg } => write!(f, "{}: {}", test_name, msg),
+            LangTesterError::Signals(err) => {
+                write!(f, "Couldn't block SIGINT and SIGTERM: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for LangTesterError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LangTesterError::Io { err, .. }
+            | LangTesterError::Spawn { err, .. }
+            | LangTesterError::Signals(err) => Some(err),
+            _ => None,
+        }
+    }
+}
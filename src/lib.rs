@@ -9,7 +9,7 @@
 //! [`compiletest_rs`](https://crates.io/crates/compiletest_rs), looks as follows:
 //!
 //! ```rust,ignore
-//! use std::{env, fs::read_to_string, path::PathBuf, process::Command};
+//! use std::{env, path::PathBuf, process::Command};
 //!
 //! use lang_tester::LangTester;
 //! use tempfile::TempDir;
@@ -25,18 +25,7 @@
 //!         // Treat lines beginning with "#" inside a test as comments.
 //!         .comment_prefix("#")
 //!         // Extract the first sequence of commented line(s) as the tests.
-//!         .test_extract(|p| {
-//!             read_to_string(p)
-//!                 .unwrap()
-//!                 .lines()
-//!                 // Skip non-commented lines at the start of the file.
-//!                 .skip_while(|l| !l.starts_with("//"))
-//!                 // Extract consecutive commented lines.
-//!                 .take_while(|l| l.starts_with("//"))
-//!                 .map(|l| &l[COMMENT_PREFIX.len()..])
-//!                 .collect::<Vec<_>>()
-//!                 .join("\n")
-//!         })
+//!         .extract_comment_block("//")
 //!         // We have two test commands:
 //!         //   * `Compiler`: runs rustc.
 //!         //   * `Run-time`: if rustc does not error, and the `Compiler` tests
@@ -118,8 +107,19 @@
 //!   * `status: <success|error|signal|<int>>`, where `success` and `error` map to platform
 //!     specific notions of a command completing successfully or unsuccessfully respectively.
 //!     `signal` checks for termination due to a signal on Unix platforms; on non-Unix platforms,
-//!     the test will be ignored. `<int>` is a signed integer checking for a specific exit code on
-//!     platforms that support it. If not specified, defaults to `success`.
+//!     the test will be ignored. `<int>` (which can also be written `code(<int>)`) is a signed
+//!     integer checking for a specific exit code on platforms that support it; `signal(<int>)`
+//!     checks for termination due to a specific signal number. Several statuses can be combined
+//!     with `or` (e.g. `status: code(1) or signal(11)`), in which case the command's status must
+//!     match at least one of them. Names defined with
+//!     [`status_aliases`](struct.LangTester.html#method.status_aliases) (e.g. `status:
+//!     type-error`) can also be used. If not specified, defaults to `success` (this can be
+//!     changed with [`default_status`](struct.LangTester.html#method.default_status)). If a
+//!     command is expected to, and does, exit with `error`, subsequent commands are not run (e.g.
+//!     if a compiler is expected to fail, the program it would have produced is not run): see
+//!     `skip-remaining-on-failure` below. If tests are specified for any of those subsequent
+//!     commands, the test fails (reporting them as "not reached"), since those tests can never
+//!     be checked.
 //!   * `stderr: [<string>]`, `stdout: [<string>]` match `<string>` against a command's `stderr` or
 //!     `stdout`. The special string `...` can be used as a simple wildcard: if a line consists
 //!     solely of `...`, it means "match zero or more lines"; if a line begins with `...`, it means
@@ -130,29 +130,136 @@
 //!     the empty string so e.g. `stderr:` on its own means that a command's `stderr` muct not
 //!     contain any output.
 //!
+//!     Before matching, the placeholders `$FILE`, `$DIR`, and `$NAME` in `<string>` are replaced
+//!     with, respectively, the full (canonicalised) path of the test file, the directory
+//!     containing it, and its file name. This is useful for commands whose output contains the
+//!     test file's path, which can vary from machine to machine.
+//!   * `stderr-regex: <regex>`, `stdout-regex: <regex>` match a command's `stderr` or `stdout`
+//!     against the regular expression `<regex>` (using the
+//!     [regex crate](https://crates.io/crates/regex)'s syntax). Multi-line values are joined
+//!     with newlines and compiled as a single regular expression in multi-line mode (i.e. `^`
+//!     and `$` match the start and end of lines). The regular expression need only match part of
+//!     the output, so use `\A` and `\z` to anchor it to the start and end of the output. These
+//!     can be used in addition to `stderr` and `stdout`.
+//!   * `stderr-ignore: <regex>`, `stdout-ignore: <regex>` remove every line of a command's
+//!     `stderr` or `stdout` which the regular expression `<regex>` matches before any of the
+//!     other checks are made (and before the output is reported or written as an artifact).
+//!     This is useful for output which varies from run to run but is not of interest, such as
+//!     progress messages: e.g. `stdout-ignore: ^\[\d+/\d+\]` removes lines such as
+//!     `[1/10] Compiling`.
+//!   * `stderr-exact: [<string>]`, `stdout-exact: [<string>]` check that a command's `stderr` or
+//!     `stdout` is exactly equal to `<string>`: no wildcards are supported and whitespace is
+//!     significant. Each line of `<string>` (including the last) is considered to be terminated
+//!     by a newline, so `stdout-exact: a` matches the output `a\n` (an empty `<string>` matches
+//!     only empty output). Before comparison, `\r\n` in the output is normalised to `\n`. On
+//!     failure, a unified diff between `<string>` and the output is shown. The same placeholders
+//!     as for `stderr` and `stdout` are supported. These can be used in addition to `stderr` and
+//!     `stdout`.
+//!   * `trailing-newline: <present|absent>` checks that a command's `stderr` and `stdout` do
+//!     (`present`) or do not (`absent`) end with a newline. Since fuzzy matching ignores trailing
+//!     newlines, this allows tests to catch output which unexpectedly gains or loses a final
+//!     newline. Empty output is not checked.
+//!   * `stderr-matches-file: <path>`, `stdout-matches-file: <path>` match a command's `stderr` or
+//!     `stdout` against the fuzzy match pattern contained in the file `<path>` (a single trailing
+//!     newline in the file is ignored). The file is read when the command's output is matched,
+//!     so it can be created while tests are running (e.g. by
+//!     [`before_each`](struct.LangTester.html#method.before_each)). Relative paths are relative
+//!     to `lang_tester`'s working directory, and the same placeholders as for `stderr` and
+//!     `stdout` are supported (e.g. `$DIR/expected.out`). These can be used in addition to
+//!     `stderr` and `stdout`.
+//!
 //! Test commands can alter the general command by specifying zero or more of the following:
 //!
 //!   * `env-var: <key>=<string>` will set (or override if it is already present) the environment
 //!     variable `<key>` to the value `<string>`. `env-var` can be specified multiple times, each
-//!     setting an additional (or overriding an existing) environment variable.
+//!     setting an additional (or overriding an existing) environment variable. `$VAR` and
+//!     `${VAR}` in `<string>` are replaced by the value of the environment variable `VAR` in
+//!     `lang_tester`'s own environment (or by the empty string if `VAR` is not set), and `$$` by
+//!     a literal `$` (e.g. `env-var: PATH=$PATH:/opt/bin`).
+//!   * `env-var: <key>` (i.e. without `=<string>`) removes the environment variable `<key>` (if
+//!     it is present) from the command's environment. Note that every command is run with the
+//!     environment variables `LANG_TESTER_TEST_NAME` and `LANG_TESTER_TEST_PATH` set to the
+//!     test's name (e.g. `a::b.x`) and the canonical path of its test file respectively: these
+//!     can be overridden or removed with `env-var`.
+//!   * `env-file: <path>` sets the environment variables listed in the file `<path>` (relative
+//!     to the directory containing the test file). Each line of the file is either blank, a
+//!     comment beginning with `#`, or of the form `<key>=<string>`, where `<string>` is treated
+//!     as for `env-var`. These are set before any `env-var`s, which can thus override them.
+//!     The file is read when the test is parsed, so a missing or malformed file is reported as
+//!     a parse error (including by `--check`).
 //!   * `exec-arg: <string>` specifies a string which will be passed as an additional command-line
 //!     argument to the command (in addition to those specified by the `test_cmds` function).
 //!     Multiple `exec-arg`s can be specified, each adding an additional command-line argument.
+//!   * `exec-args-split: <string>` is similar to `exec-arg` but splits `<string>` into multiple
+//!     command-line arguments in the manner of a Unix shell: arguments are separated by
+//!     whitespace, and quotes (`'...'` and `"..."`) and backslashes can be used to include
+//!     whitespace within an argument (e.g. `exec-args-split: -O2 -g "-DNAME=a b"` passes three
+//!     arguments). No other shell features (e.g. variable expansion) are supported.
+//!   * `max-rss: <int>` limits the memory the command can allocate to `<int>` bytes (on Unix
+//!     platforms this sets the command's `RLIMIT_AS` and `RLIMIT_DATA` resource limits). A
+//!     command which exceeds this limit will typically fail to allocate memory, and may be
+//!     terminated by a signal, which can be checked for with `status: signal`. Note that some
+//!     platforms (e.g. macOS) do not enforce these resource limits.
+//!   * `max-peak-rss: <int>` fails the test if the command's peak resident set size (i.e. the
+//!     most physical memory it used at any one point) exceeds `<int>` bytes, which is useful for
+//!     catching memory usage regressions. Unlike `max-rss`, this does not limit the command while
+//!     it runs. Passing `--verbose` prints each command's peak resident set size.
+//!   * `pty: <true|false>` specifies whether the command's stdin and stdout are connected to a
+//!     pseudo-terminal (defaults to `false`), which is useful for testing programs which behave
+//!     differently when run in a terminal (e.g. a REPL). The terminal does not echo input and
+//!     does not translate `\n` into `\r\n` in output. stderr is still captured separately. Note
+//!     that the terminal processes input line-by-line: a newline is added to the end of `stdin`
+//!     if it does not already end with one, and a very long line may not be fully received by
+//!     the command.
+//!   * `bench: <true|false>` specifies whether the wall-clock time the command takes to run is
+//!     measured (defaults to `false`). If the test passes, it is reported as `bench: <n> ns/iter`
+//!     (where `<n>` is the total, over all the test's commands with `bench: true`, of the average
+//!     time taken by each run of the command, so `repeat` can be used to reduce noise) and is
+//!     counted as "measured" rather than "passed".
+//!   * `skip-remaining-on-failure: <true|false>` specifies whether, if the command exits
+//!     unsuccessfully in the way the test expects, subsequent commands are skipped (`true`) or
+//!     run (`false`). If not specified, subsequent commands are skipped only if the command's
+//!     `status` is `error` (so, for example, if a command is expected to exit with a specific
+//!     exit code, subsequent commands are run unless `skip-remaining-on-failure: true` is
+//!     specified). Skipped commands are listed if `--verbose` is passed.
+//!   * `repeat: <int>` runs the command `<int>` times (which must be at least 1), with the test
+//!     failing if any run fails. This is useful for flushing out intermittent failures (e.g. due
+//!     to races). Note that this differs from the `rerun-if-*` keys described below, which rerun
+//!     a command only if it fails in an expected way.
 //!   * `stdin: <string>` specifies text to be passed to the command's `stdin`. If the command
 //!     exits without consuming all of `<string>`, an error will be raised. Note, though, that
 //!     operating system file buffers can mean that the command *appears* to have consumed all of
-//!     `<string>` without it actually having done so.
+//!     `<string>` without it actually having done so. A default `stdin` for a command can be
+//!     set with [`LangTester::default_stdin`](struct.LangTester.html#method.default_stdin).
+//!   * `stdin-remaining: <int|any>` specifies how many bytes of `stdin` the command is expected
+//!     not to consume (e.g. for a program which reads only the first line of its input), or
+//!     `any` if it does not matter. If not specified, defaults to `0` (i.e. the command must
+//!     consume all of `stdin`). Since, as noted for `stdin`, operating system file buffers mean
+//!     that `stdin` can be written in full before the command reads it, the number of bytes
+//!     counted as not consumed is only meaningful if `stdin` is larger than those buffers.
 //!
 //! Test commands can specify that a test should be rerun if one of the following (optional) is
 //! specified and it matches the test's output:
 //!
-//!   * `rerun-if-status` follows the same format as the `status`.
+//!   * `rerun-if-status` follows the same format as the `status`. Note that `rerun-if-status:
+//!     signal` matches any termination due to a signal, irrespective of the exit code (if any)
+//!     reported by the platform.
+//!   * `rerun-if-signal: <int>` reruns the command only if it was terminated by the signal
+//!     number `<int>` (on non-Unix platforms this never matches).
 //!   * `rerun-if-stderr` and `rerun-if-stdout` follow the same format as `stderr` and `stdout`.
 //!
+//! By default, only the command whose `rerun-if-*` matched is rerun. Specifying `rerun-scope:
+//! file` (the default being `rerun-scope: command`) instead reruns the whole sequence of the
+//! file's commands from the beginning, which is useful when a later command's intermittent
+//! failure depends on the output of an earlier command. The sequence of commands is rerun at
+//! most *n* times.
+//!
 //! These can be useful if tests are subject to intermittent errors (e.g. network failure) that
 //! should not be considered as a failure of the test itself. Test commands are rerun at most *n*
-//! times, which by default is specified as 3. If no `rerun-if-` is specified, then the first time
-//! a test fails, it will be reported to the user.
+//! times, which by default is specified as 3; a command can override this with
+//! `rerun-at-most: <int>` (e.g. for a single test which is particularly prone to such errors). If
+//! no `rerun-if-` is specified, then the first time a test fails, it will be reported to the
+//! user. Tests which pass only after being rerun are reported as such, since they may be flaky.
 //!
 //! The above file thus contains 4 meaningful tests, two specified by the user and two implied by
 //! defaults: the `Compiler` should succeed (e.g. return a `0` exit code when run on Unix), and
@@ -163,7 +270,47 @@
 //!
 //!   * `ignore-if: <cmd>` defines a shell command that will be run to determine whether to ignore
 //!     this test or not. If `<cmd>` returns 0 the test will be ignored, otherwise it will be run.
-//!     `<cmd>` will have its directory set to `CARGO_MANIFEST_DIR`.
+//!     `<cmd>` will have its directory set to `CARGO_MANIFEST_DIR`. The shell used to run `<cmd>`
+//!     can be set with [`LangTester::shell`](struct.LangTester.html#method.shell).
+//!   * `ignore-unless-stdout: <pattern>` changes the meaning of `ignore-if` (which must also be
+//!     specified): the exit status of `<cmd>` is disregarded, and the test is ignored unless
+//!     `<cmd>`'s stdout matches `<pattern>` (using the same fuzzy matching as `stdout`, and
+//!     which can span multiple lines). For example, a test can be run only if a tool's version
+//!     is 2.x with `ignore-if: tool --version` and `ignore-unless-stdout: tool 2...`.
+//!   * `ignore-if-arch: <arch> [... <arch>]` defines one or more whitespace separated
+//!     architectures (as reported by
+//!     [`std::env::consts::ARCH`](https://doc.rust-lang.org/std/env/consts/constant.ARCH.html)
+//!     e.g. `x86_64`, `aarch64`). If the current architecture is one of `<arch>`s, the test will
+//!     be ignored. Unlike `ignore-if`, no shell command is run.
+//!   * `tags: <tag>[, ... <tag>]` defines one or more comma separated tags. If tags are
+//!     specified with `--tag <tag>` on the command-line (or with
+//!     [`LangTester::only_tags`](struct.LangTester.html#method.only_tags)), only test files with
+//!     at least one of those tags are run.
+//!
+//! As with `cargo test`, passing `--ignored` runs only the tests that would otherwise be ignored,
+//! and passing `--include-ignored` runs all tests, whether they would be ignored or not.
+//!
+//! Test data can instead be written in YAML by calling
+//! [`yaml_tests(true)`](struct.LangTester.html#method.yaml_tests). Keys have the same meaning in
+//! both formats, and multi-line values (e.g. for `stderr`) can be written as YAML block scalars.
+//! Keys which can be repeated in the default format (`env-var`, `exec-arg`, and
+//! `exec-args-split`) take a sequence of values instead; `env-var` can also take a mapping, in
+//! which a null value (e.g. `~`) removes a variable; and `ignore-if-arch` can take a sequence of
+//! architectures. The test data from the example above can thus be written as:
+//!
+//! ```text
+//! Compiler:
+//!   stderr: |
+//!     warning: unused variable: `x`
+//!       ...unused_var.rs:12:9
+//!       ...
+//! Run-time:
+//!   stdout: Hello world
+//! ```
+//!
+//! Tools other than `lang_tester` (e.g. linters for test files) can parse test data in either
+//! format with [`ParsedTests`](struct.ParsedTests.html), which gives read-only access to each
+//! command's test.
 //!
 //! `lang_tester`'s output is deliberately similar to Rust's normal testing output. Running the
 //! example `rust_lang_tester` in this crate produces the following output:
@@ -199,6 +346,14 @@
 //! test result: ok. 2 passed; 0 failed; 0 ignored; 0 measured; 2 filtered out
 //! ```
 //!
+//! ## Platform support
+//!
+//! lang_tester currently only supports Unix platforms. Although test streams are polled with the
+//! portable [`polling`](https://crates.io/crates/polling) crate, pseudo-terminals, process groups,
+//! non-blocking file descriptors, resource limits and usage, and terminal size queries are all
+//! implemented with Unix-specific system calls. Building lang_tester on a non-Unix platform is a
+//! compile-time error.
+//!
 //! ## Integration with Cargo.
 //!
 //! Tests created with lang_tester can be used as part of an existing test suite and can be run
@@ -212,17 +367,40 @@
 //! harness = false
 //! ```
 
+#![allow(clippy::large_enum_variant)]
 #![allow(clippy::needless_doctest_main)]
 #![allow(clippy::new_without_default)]
 #![allow(clippy::redundant_closure)]
 #![allow(clippy::type_complexity)]
 
+#[cfg(not(unix))]
+compile_error!("lang_tester currently only supports Unix platforms");
+
+mod diff;
+mod error;
+mod parsed;
 mod parser;
+mod reporter;
 mod tester;
+mod yaml;
+
+pub use error::LangTesterError;
+pub use parsed::{CustomData, ParsedTestCmd, ParsedTests};
+pub use parser::ParseError;
+pub use reporter::{Reporter, Summary};
+pub use tester::{
+    custom_data, kill_child_process_groups, test_name, test_tmpdir, LangTester, LazyCommand,
+    Status, TestFailure, TestOutcome, TestStream,
+};
 
-pub use tester::LangTester;
+/// The exit code used by [`LangTester::run`] when one or more tests failed or timed out.
+pub const EXIT_TESTS_FAILED: i32 = 1;
+/// The exit code used when tests could not be run at all, either because of invalid command-line
+/// arguments or because a [`LangTesterError`] occurred.
+pub const EXIT_FATAL: i32 = 2;
 
 pub(crate) fn fatal(msg: &str) -> ! {
     eprintln!("\nFatal exception:\n  {}", msg);
-    std::process::exit(1);
+    tester::kill_child_process_groups();
+    std::process::exit(EXIT_FATAL);
 }
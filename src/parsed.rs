@@ -0,0 +1,332 @@
+//! A read-only public view of parsed test data, allowing tools other than `LangTester` (e.g.
+//! linters for test files) to reuse `lang_tester`'s parsers.
+
+use std::collections::HashMap;
+
+use crate::{
+    parser::{parse_tests, CustomKeys, ParseError, StatusAliases},
+    tester::{Status, TestCmd, Tests},
+    yaml::parse_yaml_tests,
+};
+
+/// The test data of a single test file.
+#[derive(Debug)]
+pub struct ParsedTests<'a> {
+    tests: Tests<'a>,
+}
+
+impl<'a> ParsedTests<'a> {
+    /// Parse `test_str` (i.e. test data as returned by
+    /// [`test_extract`](struct.LangTester.html#method.test_extract)) in the default format. Lines
+    /// starting with `comment_prefix` (if it is not `None`) are ignored, as with
+    /// [`comment_prefix`](struct.LangTester.html#method.comment_prefix). Since no
+    /// [custom keys](struct.LangTester.html#method.custom_key) are known, they are treated as
+    /// errors: use [`LangTester::parse_test_data`](struct.LangTester.html#method.parse_test_data)
+    /// to parse test data which uses them.
+    pub fn parse(comment_prefix: Option<&str>, test_str: &'a str) -> Result<Self, ParseError> {
+        let comment_prefixes = comment_prefix.into_iter().collect::<Vec<_>>();
+        parse_tests(
+            &comment_prefixes,
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            test_str,
+        )
+        .map(ParsedTests::new)
+    }
+
+    /// Parse `test_str` as YAML, as with
+    /// [`yaml_tests`](struct.LangTester.html#method.yaml_tests). As with
+    /// [`parse`](#method.parse), custom keys are treated as errors.
+    pub fn parse_yaml(test_str: &str) -> Result<ParsedTests<'static>, ParseError> {
+        parse_yaml_tests(&CustomKeys::new(), &StatusAliases::new(), test_str).map(ParsedTests::new)
+    }
+
+    pub(crate) fn new(tests: Tests<'a>) -> Self {
+        ParsedTests { tests }
+    }
+
+    /// The shell command given by `ignore-if`, if any.
+    pub fn ignore_if(&self) -> Option<&str> {
+        self.tests.ignore_if.as_deref()
+    }
+
+    /// The architectures given by `ignore-if-arch`, if any.
+    pub fn ignore_if_arch(&self) -> Option<&[String]> {
+        self.tests.ignore_if_arch.as_deref()
+    }
+
+    /// The fuzzy match pattern given by `ignore-unless-stdout`, if any.
+    pub fn ignore_unless_stdout(&self) -> Option<&str> {
+        self.tests.ignore_unless_stdout.as_deref()
+    }
+
+    /// The tags given by `tags`, in sorted order (empty if none were given).
+    pub fn tags(&self) -> Vec<&str> {
+        let mut tags = self
+            .tests
+            .tags
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+        tags.sort_unstable();
+        tags
+    }
+
+    /// The (lower-cased) names of the commands that tests are specified for, in sorted order.
+    pub fn command_names(&self) -> Vec<&str> {
+        let mut names = self
+            .tests
+            .tests
+            .keys()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    /// The test specified for the command `name` (which is matched case-insensitively), if any.
+    pub fn command(&self, name: &str) -> Option<ParsedTestCmd<'_>> {
+        self.tests
+            .tests
+            .get(&name.to_lowercase())
+            .map(|cmd| ParsedTestCmd { cmd })
+    }
+}
+
+/// The test specified for a single command. Where a key was not specified, its default value is
+/// returned (e.g. `status` defaults to `Status::Success`).
+#[derive(Debug)]
+pub struct ParsedTestCmd<'a> {
+    cmd: &'a TestCmd<'a>,
+}
+
+impl<'a> ParsedTestCmd<'a> {
+    /// The line number (starting from 1) in the test data at which the command's tests are
+    /// specified.
+    pub fn line(&self) -> usize {
+        self.cmd.line.unwrap()
+    }
+
+    /// The expected exit status given by `status` (or `Status::Success` if `status` is not given,
+    /// irrespective of any [`default_status`](struct.LangTester.html#method.default_status)).
+    pub fn status(&self) -> &Status {
+        self.cmd.status.as_ref().unwrap_or(&Status::Success)
+    }
+
+    /// The text given by `stdin`, if any.
+    pub fn stdin(&self) -> Option<&str> {
+        self.cmd.stdin.as_deref()
+    }
+
+    /// The number of bytes of `stdin` which the command must not consume, as given by
+    /// `stdin-remaining`, or `None` if any number is acceptable. Defaults to `Some(0)`.
+    pub fn stdin_remaining(&self) -> Option<usize> {
+        self.cmd.stdin_remaining
+    }
+
+    /// The lines of the fuzzy match pattern given by `stderr`.
+    pub fn stderr(&self) -> Vec<&str> {
+        self.cmd.stderr.iter().map(|x| x.as_ref()).collect()
+    }
+
+    /// The lines of the fuzzy match pattern given by `stdout`.
+    pub fn stdout(&self) -> Vec<&str> {
+        self.cmd.stdout.iter().map(|x| x.as_ref()).collect()
+    }
+
+    /// The regular expression given by `stderr-regex`, if any.
+    pub fn stderr_regex(&self) -> Option<&str> {
+        self.cmd.stderr_regex.as_ref().map(|x| x.as_str())
+    }
+
+    /// The regular expression given by `stdout-regex`, if any.
+    pub fn stdout_regex(&self) -> Option<&str> {
+        self.cmd.stdout_regex.as_ref().map(|x| x.as_str())
+    }
+
+    /// The regular expression given by `stderr-ignore`, if any.
+    pub fn stderr_ignore(&self) -> Option<&str> {
+        self.cmd.stderr_ignore.as_ref().map(|x| x.as_str())
+    }
+
+    /// The regular expression given by `stdout-ignore`, if any.
+    pub fn stdout_ignore(&self) -> Option<&str> {
+        self.cmd.stdout_ignore.as_ref().map(|x| x.as_str())
+    }
+
+    /// The text given by `stderr-exact`, if any.
+    pub fn stderr_exact(&self) -> Option<&str> {
+        self.cmd.stderr_exact.as_deref()
+    }
+
+    /// The text given by `stdout-exact`, if any.
+    pub fn stdout_exact(&self) -> Option<&str> {
+        self.cmd.stdout_exact.as_deref()
+    }
+
+    /// Whether non-empty output must (`Some(true)`) or must not (`Some(false)`) end with a
+    /// newline, as given by `trailing-newline`, if specified.
+    pub fn trailing_newline(&self) -> Option<bool> {
+        self.cmd.trailing_newline
+    }
+
+    /// The path given by `stderr-matches-file`, if any.
+    pub fn stderr_matches_file(&self) -> Option<&str> {
+        self.cmd.stderr_matches_file.as_deref()
+    }
+
+    /// The path given by `stdout-matches-file`, if any.
+    pub fn stdout_matches_file(&self) -> Option<&str> {
+        self.cmd.stdout_matches_file.as_deref()
+    }
+
+    /// The extra command-line arguments given by `exec-arg` and `exec-args-split`.
+    pub fn args(&self) -> &[String] {
+        &self.cmd.args
+    }
+
+    /// The environment variables given by `env-var`: a value of `None` means that the variable is
+    /// removed.
+    pub fn env(&self) -> &HashMap<String, Option<String>> {
+        &self.cmd.env
+    }
+
+    /// The path given by `env-file`, if any.
+    pub fn env_file(&self) -> Option<&str> {
+        self.cmd.env_file.as_ref().map(|(_, p)| p.as_str())
+    }
+
+    /// The memory limit (in bytes) given by `max-rss`, if any.
+    pub fn max_rss(&self) -> Option<u64> {
+        self.cmd.max_rss
+    }
+
+    /// The peak resident set size (in bytes) given by `max-peak-rss`, if any.
+    pub fn max_peak_rss(&self) -> Option<u64> {
+        self.cmd.max_peak_rss
+    }
+
+    /// The number of times the command is run, as given by `repeat`.
+    pub fn repeat(&self) -> u64 {
+        self.cmd.repeat
+    }
+
+    /// Whether the command's running time is measured, as given by `bench`.
+    pub fn bench(&self) -> bool {
+        self.cmd.bench
+    }
+
+    /// Whether the command is run in a pseudo-terminal, as given by `pty`.
+    pub fn pty(&self) -> bool {
+        self.cmd.pty
+    }
+
+    /// Whether subsequent commands are skipped if this command exits unsuccessfully, as given by
+    /// `skip-remaining-on-failure`, if specified.
+    pub fn skip_remaining_on_failure(&self) -> Option<bool> {
+        self.cmd.skip_remaining_on_failure
+    }
+
+    /// Whether a matching `rerun-if-*` reruns the whole sequence of commands, as given by
+    /// `rerun-scope: file`.
+    pub fn rerun_scope_file(&self) -> bool {
+        self.cmd.rerun_scope_file
+    }
+
+    /// The maximum number of reruns given by `rerun-at-most`, if any.
+    pub fn rerun_at_most(&self) -> Option<u64> {
+        self.cmd.rerun_at_most
+    }
+
+    /// The exit status given by `rerun-if-status`, if any.
+    pub fn rerun_if_status(&self) -> Option<&Status> {
+        self.cmd.rerun_if_status.as_ref()
+    }
+
+    /// The signal number given by `rerun-if-signal`, if any.
+    pub fn rerun_if_signal(&self) -> Option<i32> {
+        self.cmd.rerun_if_signal
+    }
+
+    /// The lines of the fuzzy match pattern given by `rerun-if-stderr`, if any.
+    pub fn rerun_if_stderr(&self) -> Option<Vec<&str>> {
+        self.cmd
+            .rerun_if_stderr
+            .as_ref()
+            .map(|x| x.iter().map(|x| x.as_ref()).collect())
+    }
+
+    /// The lines of the fuzzy match pattern given by `rerun-if-stdout`, if any.
+    pub fn rerun_if_stdout(&self) -> Option<Vec<&str>> {
+        self.cmd
+            .rerun_if_stdout
+            .as_ref()
+            .map(|x| x.iter().map(|x| x.as_ref()).collect())
+    }
+
+    /// The data collected from the command's custom keys (see
+    /// [`custom_key`](struct.LangTester.html#method.custom_key)).
+    pub fn custom_data(&self) -> &CustomData {
+        &self.cmd.custom
+    }
+}
+
+/// Data collected from a command's custom keys by the functions passed to
+/// [`LangTester::custom_key`](struct.LangTester.html#method.custom_key). This is a simple map
+/// from names to values: a custom key's function is free to choose how it stores its value.
+#[derive(Clone, Debug, Default)]
+pub struct CustomData {
+    values: HashMap<String, String>,
+}
+
+impl CustomData {
+    /// Set `name` to `value`, replacing any previous value.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_owned(), value.to_owned());
+    }
+
+    /// The value of `name`, if it has been set.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|x| x.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parsed_tests() {
+        let tests = ParsedTests::parse(
+            Some("#"),
+            "ignore-if: false
+# A comment.
+Compiler:
+  status: error
+  stderr:
+    a
+    ...
+Run-time:
+  stdout-regex: ^x$
+  stdout-ignore: ^\\[\\d+/\\d+\\]",
+        )
+        .unwrap();
+        assert_eq!(tests.ignore_if(), Some("false"));
+        assert_eq!(tests.ignore_if_arch(), None);
+        assert_eq!(tests.command_names(), vec!["compiler", "run-time"]);
+        let compiler = tests.command("Compiler").unwrap();
+        assert_eq!(compiler.status(), &Status::Error);
+        assert_eq!(compiler.stderr(), vec!["a", "..."]);
+        assert_eq!(compiler.stdout(), vec!["..."]);
+        assert_eq!(compiler.repeat(), 1);
+        let run_time = tests.command("run-time").unwrap();
+        assert_eq!(run_time.stdout_regex(), Some("^x$"));
+        assert_eq!(run_time.stdout_ignore(), Some(r"^\[\d+/\d+\]"));
+        assert_eq!(run_time.stderr_ignore(), None);
+        assert_eq!((compiler.line(), run_time.line()), (3, 8));
+        assert!(tests.command("linker").is_none());
+
+        let e = ParsedTests::parse(None, "x:\n  unknown: 1").unwrap_err();
+        assert_eq!((e.line(), e.msg()), (2, "Unknown key 'unknown'."));
+    }
+}
@@ -1,139 +1,626 @@
-use std::collections::hash_map::{Entry, HashMap};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fmt,
+    panic::RefUnwindSafe,
+};
+
+use regex::RegexBuilder;
 
 use crate::{
-    fatal,
+    parsed::CustomData,
     tester::{Status, TestCmd, Tests},
 };
 
-/// Parse test data into a set of `Test`s.
-pub(crate) fn parse_tests<'a>(comment_prefix: Option<&str>, test_str: &'a str) -> Tests<'a> {
+/// User-defined keys (see [`LangTester::custom_key`](struct.LangTester.html#method.custom_key)),
+/// each mapped to the function which handles its values.
+pub(crate) type CustomKeys = HashMap<
+    String,
+    Box<dyn Fn(&str, &mut CustomData) -> Result<(), String> + RefUnwindSafe + Send + Sync>,
+>;
+
+/// User-defined names for statuses (see
+/// [`LangTester::status_aliases`](struct.LangTester.html#method.status_aliases)), each mapped (in
+/// lower case) to the status it stands for.
+pub(crate) type StatusAliases = HashMap<String, Status>;
+
+/// An error in test data.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// The line number (starting from 1) in the test data at which the error was found.
+    pub(crate) line: usize,
+    pub(crate) msg: String,
+}
+
+impl ParseError {
+    pub(crate) fn new(line_off: usize, msg: String) -> Self {
+        ParseError {
+            line: line_off + 1,
+            msg,
+        }
+    }
+
+    /// The line number (starting from 1) in the test data at which the error was found.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// A description of the error.
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parse test data into a set of `Test`s, returning the first error found (if any).
+pub(crate) fn parse_tests<'a>(
+    comment_prefixes: &[&str],
+    custom_keys: &CustomKeys,
+    status_aliases: &StatusAliases,
+    test_str: &'a str,
+) -> Result<Tests<'a>, ParseError> {
+    parse_tests_all_errors(comment_prefixes, custom_keys, status_aliases, test_str)
+        .map_err(|mut errors| errors.remove(0))
+}
+
+/// Parse test data into a set of `Test`s. Rather than stopping at the first error, parsing
+/// continues as best it can, so that all the errors in the test data (of which there is at least
+/// one, in the order they were found) can be returned.
+pub(crate) fn parse_tests_all_errors<'a>(
+    comment_prefixes: &[&str],
+    custom_keys: &CustomKeys,
+    status_aliases: &StatusAliases,
+    test_str: &'a str,
+) -> Result<Tests<'a>, Vec<ParseError>> {
+    let mut errors = Vec::new();
     let lines = test_str.lines().collect::<Vec<_>>();
     let mut tests = HashMap::new();
     let mut line_off = 0;
     let mut ignore_if = None;
+    let mut ignore_if_arch = None;
+    let mut ignore_unless_stdout = None;
+    let mut ignore_unless_stdout_line_off = 0;
+    let mut tags = None;
     while line_off < lines.len() {
         let indent = indent_level(&lines, line_off);
         if indent == lines[line_off].len() {
             line_off += 1;
             continue;
         }
-        if let Some(cp) = comment_prefix {
-            if lines[line_off][indent..].starts_with(cp) {
+        if is_comment(comment_prefixes, &lines[line_off][indent..]) {
+            line_off += 1;
+            continue;
+        }
+        // If a line is invalid, we skip it, and hope that what follows is valid.
+        let (test_name, val) = match key_val(&lines, line_off, indent) {
+            Ok(x) => x,
+            Err(e) => {
+                errors.push(e);
                 line_off += 1;
                 continue;
             }
-        }
-        let (test_name, val) = key_val(&lines, line_off, indent);
+        };
         if test_name == "ignore-if" {
             if ignore_if.is_some() {
-                fatal(&format!(
-                    "'ignore-if' is specified more than once, line {}.",
-                    line_off
-                ))
+                errors.push(ParseError::new(
+                    line_off,
+                    "'ignore-if' is specified more than once.".to_owned(),
+                ));
+            } else {
+                ignore_if = Some(val.into());
+            }
+            line_off += 1;
+            continue;
+        }
+        if test_name == "ignore-unless-stdout" {
+            if ignore_unless_stdout.is_some() {
+                errors.push(ParseError::new(
+                    line_off,
+                    "'ignore-unless-stdout' is specified more than once.".to_owned(),
+                ));
+            } else {
+                ignore_unless_stdout_line_off = line_off;
+            }
+            // `key_val` succeeded on this line, so this cannot fail.
+            let (end_line_off, _, val) =
+                key_multiline_val(comment_prefixes, &lines, line_off, indent).unwrap();
+            ignore_unless_stdout.get_or_insert(val.join("\n"));
+            line_off = end_line_off;
+            continue;
+        }
+        if test_name == "ignore-if-arch" {
+            if ignore_if_arch.is_some() {
+                errors.push(ParseError::new(
+                    line_off,
+                    "'ignore-if-arch' is specified more than once.".to_owned(),
+                ));
+            } else {
+                match parse_arches(val, line_off) {
+                    Ok(x) => ignore_if_arch = Some(x),
+                    Err(e) => errors.push(e),
+                }
+            }
+            line_off += 1;
+            continue;
+        }
+        if test_name == "tags" {
+            if tags.is_some() {
+                errors.push(ParseError::new(
+                    line_off,
+                    "'tags' is specified more than once.".to_owned(),
+                ));
+            } else {
+                match parse_tags(val, line_off) {
+                    Ok(x) => tags = Some(x),
+                    Err(e) => errors.push(e),
+                }
             }
-            ignore_if = Some(val.into());
             line_off += 1;
             continue;
         }
+        // Even if the command is invalid, we still parse its keys so that any errors in them are
+        // reported.
         if !val.is_empty() {
-            fatal(&format!(
-                "Test name '{}' can't have a value on line {}.",
-                test_name, line_off
+            errors.push(ParseError::new(
+                line_off,
+                format!("Test name '{}' can't have a value.", test_name),
             ));
         }
-        match tests.entry(test_name.to_lowercase()) {
-            Entry::Occupied(_) => fatal(&format!(
-                "Command name '{}' is specified more than once, line {}.",
-                test_name, line_off
-            )),
-            Entry::Vacant(e) => {
+        let cmd_name = test_name.to_lowercase();
+        if tests.contains_key(&cmd_name) {
+            errors.push(ParseError::new(
+                line_off,
+                format!("Command name '{}' is specified more than once.", test_name),
+            ));
+        }
+        let mut testcmd = TestCmd {
+            line: Some(line_off + 1),
+            ..TestCmd::default()
+        };
+        line_off += 1;
+        while line_off < lines.len() {
+            let sub_indent = indent_level(&lines, line_off);
+            if sub_indent == lines[line_off].len() {
                 line_off += 1;
-                let mut testcmd = TestCmd::default();
-                while line_off < lines.len() {
-                    let sub_indent = indent_level(&lines, line_off);
-                    if sub_indent == lines[line_off].len() {
+                continue;
+            }
+            if sub_indent == indent {
+                break;
+            }
+            let key_line_off = line_off;
+            let (end_line_off, key, val) =
+                match key_multiline_val(comment_prefixes, &lines, line_off, sub_indent) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        errors.push(e);
                         line_off += 1;
                         continue;
                     }
-                    if sub_indent == indent {
-                        break;
+                };
+            line_off = end_line_off;
+            if let Err(e) = set_key(
+                &mut testcmd,
+                custom_keys,
+                status_aliases,
+                key,
+                val.into_iter().map(Cow::Borrowed).collect(),
+                key_line_off,
+            ) {
+                errors.push(e);
+            }
+        }
+        tests.entry(cmd_name).or_insert(testcmd);
+    }
+    if ignore_unless_stdout.is_some() && ignore_if.is_none() {
+        errors.push(ParseError::new(
+            ignore_unless_stdout_line_off,
+            "'ignore-unless-stdout' requires 'ignore-if' to be specified.".to_owned(),
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Tests {
+        ignore_if,
+        ignore_if_arch,
+        ignore_unless_stdout,
+        tags: tags.unwrap_or_default(),
+        tests,
+    })
+}
+
+/// Parse the value `val` of `tags` (found at line offset `line_off`): a comma-separated list of
+/// tags.
+pub(crate) fn parse_tags(val: &str, line_off: usize) -> Result<HashSet<String>, ParseError> {
+    let tags = val
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_owned())
+        .collect::<HashSet<_>>();
+    if tags.is_empty() {
+        return Err(ParseError::new(
+            line_off,
+            "'tags' must specify at least one tag.".to_owned(),
+        ));
+    }
+    Ok(tags)
+}
+
+/// Parse the value `val` of `ignore-if-arch` (found at line offset `line_off`): a
+/// whitespace-separated list of architectures.
+pub(crate) fn parse_arches(val: &str, line_off: usize) -> Result<Vec<String>, ParseError> {
+    let arches = val
+        .split_whitespace()
+        .map(|x| x.to_owned())
+        .collect::<Vec<_>>();
+    if arches.is_empty() {
+        return Err(ParseError::new(
+            line_off,
+            "'ignore-if-arch' must specify at least one architecture.".to_owned(),
+        ));
+    }
+    Ok(arches)
+}
+
+/// Parse a status: either a single status (`success`, `error`, `signal`, `<int>`, `code(<int>)`,
+/// `signal(<int>)`, or a name in `status_aliases`) or several separated by `or` (e.g. `code(1) or
+/// signal(11)`). Returns `None` if `s` is not a valid status.
+fn parse_status(status_aliases: &StatusAliases, s: &str) -> Option<Status> {
+    let mut statuses = s
+        .split(" or ")
+        .map(|x| {
+            let x = x.trim().to_lowercase();
+            if let Some(status) = status_aliases.get(&x) {
+                return Some(status.clone());
+            }
+            let arg = |prefix: &str| {
+                x.strip_prefix(prefix)?
+                    .strip_suffix(')')?
+                    .trim()
+                    .parse::<i32>()
+                    .ok()
+            };
+            match x.as_str() {
+                "success" => Some(Status::Success),
+                "error" => Some(Status::Error),
+                "signal" => Some(Status::Signal),
+                _ => {
+                    if let Ok(i) = x.parse::<i32>() {
+                        Some(Status::Int(i))
+                    } else if let Some(i) = arg("code(") {
+                        Some(Status::Int(i))
+                    } else {
+                        arg("signal(").map(Status::SignalInt)
                     }
-                    let (end_line_off, key, val) =
-                        key_multiline_val(comment_prefix, &lines, line_off, sub_indent);
-                    line_off = end_line_off;
-                    match key {
-                        "env-var" => {
-                            let val_str = val.join("\n");
-                            match val_str.find('=') {
-                                Some(i) => {
-                                    let key = val_str[..i].trim().to_owned();
-                                    let var = val_str[i + 1..].trim().to_owned();
-                                    testcmd.env.insert(key, var);
-                                }
-                                None => {
-                                    fatal(&format!(
-                                        "'{}' is not in the format '<key>=<string>' on line {}",
-                                        val_str, line_off
-                                    ));
-                                }
-                            }
-                        }
-                        "exec-arg" => {
-                            let val_str = val.join("\n");
-                            testcmd.args.push(val_str);
-                        }
-                        "status" | "rerun-if-status" => {
-                            let val_str = val.join("\n");
-                            let status = match val_str.to_lowercase().as_str() {
-                                "success" => Status::Success,
-                                "error" => Status::Error,
-                                "signal" => Status::Signal,
-                                x => {
-                                    if let Ok(i) = x.parse::<i32>() {
-                                        Status::Int(i)
-                                    } else {
-                                        fatal(&format!(
-                                            "Unknown status '{}' on line {}",
-                                            val_str, line_off
-                                        ));
-                                    }
-                                }
-                            };
-                            match key {
-                                "status" => {
-                                    testcmd.status = status;
-                                }
-                                "rerun-if-status" => {
-                                    testcmd.rerun_if_status = Some(status);
-                                }
-                                _ => {
-                                    unreachable!();
-                                }
-                            }
-                        }
-                        "stdin" => {
-                            testcmd.stdin = Some(val.join("\n"));
-                        }
-                        "stderr" => {
-                            testcmd.stderr = val;
-                        }
-                        "stdout" => {
-                            testcmd.stdout = val;
-                        }
-                        "rerun-if-stderr" => {
-                            testcmd.rerun_if_stderr = Some(val);
-                        }
-                        "rerun-if-stdout" => {
-                            testcmd.rerun_if_stdout = Some(val);
-                        }
-                        _ => fatal(&format!("Unknown key '{}' on line {}.", key, line_off)),
+                }
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if statuses.len() == 1 {
+        statuses.pop()
+    } else {
+        Some(Status::Any(statuses))
+    }
+}
+
+/// Set the key `key` (whose value, split into lines, is `val`) in `testcmd`. `key_line_off` is the
+/// line offset of the key, used if an error is reported.
+pub(crate) fn set_key<'a>(
+    testcmd: &mut TestCmd<'a>,
+    custom_keys: &CustomKeys,
+    status_aliases: &StatusAliases,
+    key: &str,
+    val: Vec<Cow<'a, str>>,
+    key_line_off: usize,
+) -> Result<(), ParseError> {
+    testcmd
+        .key_lines
+        .entry(key.to_owned())
+        .or_insert(key_line_off + 1);
+    match key {
+        "env-var" => {
+            let val_str = val.join("\n");
+            match val_str.find('=') {
+                Some(i) => {
+                    let key = val_str[..i].trim().to_owned();
+                    let var = expand_env_vars(val_str[i + 1..].trim());
+                    testcmd.env.insert(key, Some(var));
+                }
+                None => {
+                    let key = val_str.trim();
+                    if key.is_empty() || key.contains(char::is_whitespace) {
+                        return Err(ParseError::new(
+                            key_line_off,
+                            format!(
+                                "'{}' is not in the format '<key>=<string>' or '<key>'.",
+                                val_str
+                            ),
+                        ));
                     }
+                    testcmd.env.insert(key.to_owned(), None);
                 }
-                e.insert(testcmd);
             }
         }
+        "env-file" => {
+            let val_str = val.join("\n").trim().to_owned();
+            if val_str.is_empty() {
+                return Err(ParseError::new(
+                    key_line_off,
+                    "'env-file' requires a path.".to_owned(),
+                ));
+            }
+            testcmd.env_file = Some((key_line_off, val_str));
+        }
+        "exec-arg" => {
+            let val_str = val.join("\n");
+            testcmd.args.push(val_str);
+        }
+        "exec-args-split" => {
+            let val_str = val.join("\n");
+            match split_words(&val_str) {
+                Ok(words) => testcmd.args.extend(words),
+                Err(msg) => return Err(ParseError::new(key_line_off, msg)),
+            }
+        }
+        "max-rss" => {
+            let val_str = val.join("\n");
+            match val_str.parse::<u64>() {
+                Ok(i) => testcmd.max_rss = Some(i),
+                Err(_) => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("'{}' is not a valid number of bytes.", val_str),
+                    ))
+                }
+            }
+        }
+        "max-peak-rss" => {
+            let val_str = val.join("\n");
+            match val_str.parse::<u64>() {
+                Ok(i) => testcmd.max_peak_rss = Some(i),
+                Err(_) => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("'{}' is not a valid number of bytes.", val_str),
+                    ))
+                }
+            }
+        }
+        "rerun-scope" => {
+            let val_str = val.join("\n");
+            match val_str.as_str() {
+                "command" => testcmd.rerun_scope_file = false,
+                "file" => testcmd.rerun_scope_file = true,
+                _ => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("'{}' is not 'command' or 'file'.", val_str),
+                    ))
+                }
+            }
+        }
+        "skip-remaining-on-failure" => {
+            let val_str = val.join("\n");
+            match val_str.as_str() {
+                "true" => testcmd.skip_remaining_on_failure = Some(true),
+                "false" => testcmd.skip_remaining_on_failure = Some(false),
+                _ => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("'{}' is not 'true' or 'false'.", val_str),
+                    ))
+                }
+            }
+        }
+        "bench" => {
+            let val_str = val.join("\n");
+            match val_str.as_str() {
+                "true" => testcmd.bench = true,
+                "false" => testcmd.bench = false,
+                _ => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("'{}' is not 'true' or 'false'.", val_str),
+                    ))
+                }
+            }
+        }
+        "pty" => {
+            let val_str = val.join("\n");
+            match val_str.as_str() {
+                "true" => testcmd.pty = true,
+                "false" => testcmd.pty = false,
+                _ => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("'{}' is not 'true' or 'false'.", val_str),
+                    ))
+                }
+            }
+        }
+        "repeat" => {
+            let val_str = val.join("\n");
+            match val_str.parse::<u64>() {
+                Ok(i) if i > 0 => testcmd.repeat = i,
+                _ => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("'{}' is not a valid number of repetitions.", val_str),
+                    ))
+                }
+            }
+        }
+        "rerun-at-most" => {
+            let val_str = val.join("\n");
+            match val_str.parse::<u64>() {
+                Ok(i) => testcmd.rerun_at_most = Some(i),
+                Err(_) => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("'{}' is not a valid number of reruns.", val_str),
+                    ))
+                }
+            }
+        }
+        "status" | "rerun-if-status" => {
+            let val_str = val.join("\n");
+            let status = match parse_status(status_aliases, &val_str) {
+                Some(x) => x,
+                None => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("Unknown status '{}'.", val_str),
+                    ));
+                }
+            };
+            match key {
+                "status" => {
+                    testcmd.status = Some(status);
+                }
+                "rerun-if-status" => {
+                    testcmd.rerun_if_status = Some(status);
+                }
+                _ => {
+                    unreachable!();
+                }
+            }
+        }
+        "rerun-if-signal" => {
+            let val_str = val.join("\n");
+            match val_str.parse::<i32>() {
+                Ok(i) => testcmd.rerun_if_signal = Some(i),
+                Err(_) => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!("Unknown signal number '{}'.", val_str),
+                    ))
+                }
+            }
+        }
+        "stdin" => {
+            testcmd.stdin = Some(val.join("\n"));
+        }
+        "stdin-remaining" => {
+            let val_str = val.join("\n");
+            testcmd.stdin_remaining = match val_str.trim() {
+                "any" => None,
+                x => match x.parse::<usize>() {
+                    Ok(x) => Some(x),
+                    Err(_) => {
+                        return Err(ParseError::new(
+                            key_line_off,
+                            format!("'{}' is not a number of bytes or 'any'.", x),
+                        ))
+                    }
+                },
+            };
+        }
+        "stderr" => {
+            testcmd.stderr = val;
+        }
+        "stdout" => {
+            testcmd.stdout = val;
+        }
+        "stderr-regex" | "stdout-regex" => {
+            let val_str = val.join("\n");
+            let re = RegexBuilder::new(&val_str)
+                .multi_line(true)
+                .build()
+                .map_err(|e| {
+                    ParseError::new(
+                        key_line_off,
+                        format!("Invalid regular expression:\n  {}", e),
+                    )
+                })?;
+            if key == "stderr-regex" {
+                testcmd.stderr_regex = Some(re);
+            } else {
+                testcmd.stdout_regex = Some(re);
+            }
+        }
+        "stderr-ignore" | "stdout-ignore" => {
+            let val_str = val.join("\n");
+            let re = RegexBuilder::new(&val_str).build().map_err(|e| {
+                ParseError::new(
+                    key_line_off,
+                    format!("Invalid regular expression:\n  {}", e),
+                )
+            })?;
+            if key == "stderr-ignore" {
+                testcmd.stderr_ignore = Some(re);
+            } else {
+                testcmd.stdout_ignore = Some(re);
+            }
+        }
+        "stderr-exact" | "stdout-exact" => {
+            let mut val_str = val.join("\n");
+            if !val.is_empty() {
+                val_str.push('\n');
+            }
+            if key == "stderr-exact" {
+                testcmd.stderr_exact = Some(val_str);
+            } else {
+                testcmd.stdout_exact = Some(val_str);
+            }
+        }
+        "trailing-newline" => {
+            let val_str = val.join("\n");
+            testcmd.trailing_newline = match val_str.trim() {
+                "present" => Some(true),
+                "absent" => Some(false),
+                x => {
+                    return Err(ParseError::new(
+                        key_line_off,
+                        format!(
+                        "'{}' is not a valid trailing newline (expected 'present' or 'absent').",
+                        x
+                    ),
+                    ))
+                }
+            };
+        }
+        "stderr-matches-file" | "stdout-matches-file" => {
+            let val_str = val.join("\n").trim().to_owned();
+            if val_str.is_empty() {
+                return Err(ParseError::new(
+                    key_line_off,
+                    format!("'{}' requires a path.", key),
+                ));
+            }
+            if key == "stderr-matches-file" {
+                testcmd.stderr_matches_file = Some(val_str);
+            } else {
+                testcmd.stdout_matches_file = Some(val_str);
+            }
+        }
+        "rerun-if-stderr" => {
+            testcmd.rerun_if_stderr = Some(val);
+        }
+        "rerun-if-stdout" => {
+            testcmd.rerun_if_stdout = Some(val);
+        }
+        _ => match custom_keys.get(key) {
+            Some(handler) => handler(&val.join("\n"), &mut testcmd.custom)
+                .map_err(|msg| ParseError::new(key_line_off, msg))?,
+            None => {
+                return Err(ParseError::new(
+                    key_line_off,
+                    format!("Unknown key '{}'.", key),
+                ))
+            }
+        },
     }
-    Tests { ignore_if, tests }
+    Ok(())
 }
 
 fn indent_level(lines: &[&str], line_off: usize) -> usize {
@@ -143,8 +630,104 @@ fn indent_level(lines: &[&str], line_off: usize) -> usize {
         .count()
 }
 
+/// Expand references to host environment variables in `s`: `$VAR` and `${VAR}` are replaced by
+/// the value of `VAR` (or the empty string if `VAR` is not set) and `$$` by a literal `$`. A `$`
+/// not followed by one of these forms is left as-is.
+pub(crate) fn expand_env_vars(s: &str) -> String {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + '$'.len_utf8()..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+        } else if let Some((name, after)) = rest
+            .strip_prefix('{')
+            .and_then(|x| x.find('}').map(|j| (&x[..j], &x[j + '}'.len_utf8()..])))
+        {
+            expanded.push_str(&env::var(name).unwrap_or_default());
+            rest = after;
+        } else {
+            let name_len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if name_len == 0 {
+                expanded.push('$');
+            } else {
+                expanded.push_str(&env::var(&rest[..name_len]).unwrap_or_default());
+                rest = &rest[name_len..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Split `s` into words in the manner of a (simplified) Unix shell: words are separated by
+/// whitespace; text within single quotes is taken literally; text within double quotes is taken
+/// literally except that `\` escapes a following `"` or `\`; and, outside quotes, `\` escapes
+/// any following character.
+fn split_words(s: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    // The word currently being built, if any. This is distinct from an empty word, since `''` is a
+    // valid (empty) word.
+    let mut word = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(format!("Unterminated single quote in '{}'.", s)),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(format!("Unterminated double quote in '{}'.", s)),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(format!("Unterminated double quote in '{}'.", s)),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(format!("Trailing '\\' in '{}'.", s)),
+            },
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(word) = word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
 /// Turn a line such as `key: val` into its separate components.
-fn key_val<'a>(lines: &[&'a str], line_off: usize, indent: usize) -> (&'a str, &'a str) {
+fn key_val<'a>(
+    lines: &[&'a str],
+    line_off: usize,
+    indent: usize,
+) -> Result<(&'a str, &'a str), ParseError> {
     let line = lines[line_off];
     let key_len = line[indent..]
         .chars()
@@ -158,27 +741,34 @@ fn key_val<'a>(lines: &[&'a str], line_off: usize, indent: usize) -> (&'a str, &
         .count();
     match line[content_start..].chars().next() {
         Some(':') => content_start += ':'.len_utf8(),
-        _ => fatal(&format!(
-            "Invalid key terminator at line {}.\n  {}",
-            line_off, line
-        )),
+        _ => {
+            return Err(ParseError::new(
+                line_off,
+                format!("Invalid key terminator.\n  {}", line),
+            ))
+        }
     }
     content_start += line[content_start..]
         .chars()
         .take_while(|c| c.is_whitespace())
         .count();
-    (key, &line[content_start..])
+    Ok((key, &line[content_start..]))
+}
+
+/// Does `line` start with any of `comment_prefixes`?
+fn is_comment(comment_prefixes: &[&str], line: &str) -> bool {
+    comment_prefixes.iter().any(|cp| line.starts_with(cp))
 }
 
 /// Turn one more lines of the format `key: val` (where `val` may spread over many lines) into its
 /// separate components.
 fn key_multiline_val<'a>(
-    comment_prefix: Option<&str>,
+    comment_prefixes: &[&str],
     lines: &[&'a str],
     mut line_off: usize,
     indent: usize,
-) -> (usize, &'a str, Vec<&'a str>) {
-    let (key, first_line_val) = key_val(lines, line_off, indent);
+) -> Result<(usize, &'a str, Vec<&'a str>), ParseError> {
+    let (key, first_line_val) = key_val(lines, line_off, indent)?;
     line_off += 1;
     let mut val = vec![first_line_val];
     if line_off < lines.len() {
@@ -193,11 +783,9 @@ fn key_multiline_val<'a>(
             if cur_indent <= indent {
                 break;
             }
-            if let Some(cp) = comment_prefix {
-                if lines[line_off][sub_indent..].starts_with(cp) {
-                    line_off += 1;
-                    continue;
-                }
+            if is_comment(comment_prefixes, &lines[line_off][sub_indent..]) {
+                line_off += 1;
+                continue;
             }
             val.push(&lines[line_off][sub_indent..]);
             line_off += 1;
@@ -212,7 +800,7 @@ fn key_multiline_val<'a>(
         val.remove(0);
     }
 
-    (line_off, key, val)
+    Ok((line_off, key, val))
 }
 
 #[cfg(test)]
@@ -221,31 +809,510 @@ mod test {
 
     #[test]
     fn test_key_multiline() {
-        assert_eq!(key_multiline_val(None, &["x:", ""], 0, 0), (2, "x", vec![]));
         assert_eq!(
-            key_multiline_val(None, &["x: y", "  z", "a"], 0, 0),
-            (2, "x", vec!["y", "z"])
+            key_multiline_val(&[], &["x:", ""], 0, 0),
+            Ok((2, "x", vec![]))
+        );
+        assert_eq!(
+            key_multiline_val(&[], &["x: y", "  z", "a"], 0, 0),
+            Ok((2, "x", vec!["y", "z"]))
         );
         assert_eq!(
-            key_multiline_val(None, &["x:", "  z", "a"], 0, 0),
-            (2, "x", vec!["z"])
+            key_multiline_val(&[], &["x:", "  z", "a"], 0, 0),
+            Ok((2, "x", vec!["z"]))
         );
         assert_eq!(
-            key_multiline_val(None, &["x:", "  z  ", "  a  ", "  ", "b"], 0, 0),
-            (4, "x", vec!["z  ", "a  "])
+            key_multiline_val(&[], &["x:", "  z  ", "  a  ", "  ", "b"], 0, 0),
+            Ok((4, "x", vec!["z  ", "a  "]))
         );
         assert_eq!(
-            key_multiline_val(None, &["x:", "  z  ", "    a  ", "  ", "  b"], 0, 0),
-            (5, "x", vec!["z  ", "  a  ", "", "b"])
+            key_multiline_val(&[], &["x:", "  z  ", "    a  ", "  ", "  b"], 0, 0),
+            Ok((5, "x", vec!["z  ", "  a  ", "", "b"]))
         );
         assert_eq!(
             key_multiline_val(
-                Some("#"),
+                &["#"],
                 &["x:", "  z  ", "    a  ", "  # c2", "  ", "  b"],
                 0,
                 0
             ),
-            (6, "x", vec!["z  ", "  a  ", "", "b"])
+            Ok((6, "x", vec!["z  ", "  a  ", "", "b"]))
+        );
+        assert_eq!(
+            key_multiline_val(
+                &["#", "//"],
+                &["x:", "  z", "  // c1", "  # c2", "  b"],
+                0,
+                0
+            ),
+            Ok((5, "x", vec!["z", "b"]))
+        );
+    }
+
+    #[test]
+    fn test_comment_prefixes() {
+        let tests = parse_tests(
+            &["#", "//"],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "# c1\nx:\n  status: error\n// c2\ny:",
+        )
+        .unwrap();
+        assert_eq!(tests.tests["x"].status, Some(Status::Error));
+        assert!(tests.tests.contains_key("y"));
+    }
+
+    #[test]
+    fn test_ignore_if_arch() {
+        let tests = parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "ignore-if-arch: x86_64  aarch64\nx:\n  status: error",
+        )
+        .unwrap();
+        assert_eq!(
+            tests.ignore_if_arch,
+            Some(vec!["x86_64".to_owned(), "aarch64".to_owned()])
+        );
+        assert!(tests.ignore_if.is_none());
+        assert!(tests.ignore_unless_stdout.is_none());
+        assert!(parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "x:\n  status: error"
+        )
+        .unwrap()
+        .ignore_if_arch
+        .is_none());
+    }
+
+    #[test]
+    fn test_ignore_unless_stdout() {
+        let tests = parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "ignore-if: tool --version\nignore-unless-stdout:\n  tool 2...\n  ...\n\
+             x:\n  status: success",
+        )
+        .unwrap();
+        assert_eq!(tests.ignore_if.as_deref(), Some("tool --version"));
+        assert_eq!(
+            tests.ignore_unless_stdout.as_deref(),
+            Some("tool 2...\n...")
+        );
+        assert!(tests.tests.contains_key("x"));
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "ignore-unless-stdout: x"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 1,
+                msg: "'ignore-unless-stdout' requires 'ignore-if' to be specified.".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: success\n  unknown: 1"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 3,
+                msg: "Unknown key 'unknown'.".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: success\nx:"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 3,
+                msg: "Command name 'x' is specified more than once.".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_tests(&[], &CustomKeys::new(), &StatusAliases::new(), "\nx\n").unwrap_err(),
+            ParseError {
+                line: 2,
+                msg: "Invalid key terminator.\n  x".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: 1.5"
+            )
+            .unwrap_err()
+            .line,
+            2
+        );
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  env-var: A B"
+            )
+            .unwrap_err()
+            .line,
+            2
+        );
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  repeat: 0"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 2,
+                msg: "'0' is not a valid number of repetitions.".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_status() {
+        let status = |s: &str| {
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                &format!("x:\n  status: {}", s),
+            )
+            .map(|x| x.tests["x"].status.clone().unwrap())
+        };
+        assert_eq!(status("error").unwrap(), Status::Error);
+        assert_eq!(status("-1").unwrap(), Status::Int(-1));
+        assert_eq!(status("code(3)").unwrap(), Status::Int(3));
+        assert_eq!(status("signal(11)").unwrap(), Status::SignalInt(11));
+        assert_eq!(
+            status("code(1) or signal(11)").unwrap(),
+            Status::Any(vec![Status::Int(1), Status::SignalInt(11)])
+        );
+        assert_eq!(
+            status("code(1) or signal(11)").unwrap().to_string(),
+            "code(1) or signal(11)"
+        );
+        assert_eq!(
+            status("code(1) or").unwrap_err(),
+            ParseError {
+                line: 2,
+                msg: "Unknown status 'code(1) or'.".to_owned()
+            }
+        );
+        assert!(status("signal(x)").is_err());
+    }
+
+    #[test]
+    fn test_all_errors() {
+        let errors = parse_tests_all_errors(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "x:\n  status: a\n  stdout: b\n  repeat: 0\nignore-unless-stdout: c\ny\n\
+             X:\n  unknown: 1",
+        )
+        .unwrap_err()
+        .into_iter()
+        .map(|e| e.line)
+        .collect::<Vec<_>>();
+        assert_eq!(errors, vec![2, 4, 6, 7, 8, 5]);
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: a\n  repeat: 0"
+            )
+            .unwrap_err()
+            .line,
+            2
+        );
+        assert!(parse_tests_all_errors(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "x:\n  status: error"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_status_aliases() {
+        let mut status_aliases = StatusAliases::new();
+        status_aliases.insert("type-error".to_owned(), Status::Int(65));
+        let status = |s: &str| {
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &status_aliases,
+                &format!("x:\n  status: {}", s),
+            )
+            .map(|x| x.tests["x"].status.clone().unwrap())
+        };
+        assert_eq!(status("type-error").unwrap(), Status::Int(65));
+        assert_eq!(status("Type-Error").unwrap(), Status::Int(65));
+        assert_eq!(
+            status("type-error or signal").unwrap(),
+            Status::Any(vec![Status::Int(65), Status::Signal])
+        );
+        assert_eq!(status("success").unwrap(), Status::Success);
+        assert!(status("syntax-error").is_err());
+    }
+
+    #[test]
+    fn test_key_lines() {
+        let tests = parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "x:\n  status: success\n\n  stdout:\n    a\n  exec-arg: b\n  exec-arg: c",
+        )
+        .unwrap();
+        let key_lines = &tests.tests["x"].key_lines;
+        assert_eq!(key_lines["status"], 2);
+        assert_eq!(key_lines["stdout"], 4);
+        assert_eq!(key_lines["exec-arg"], 6);
+        assert_eq!(key_lines.get("stderr"), None);
+    }
+
+    #[test]
+    fn test_trailing_newline() {
+        let tests = parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "x:\n  trailing-newline: absent\ny:\n  trailing-newline: present\nz:",
+        )
+        .unwrap();
+        assert_eq!(tests.tests["x"].trailing_newline, Some(false));
+        assert_eq!(tests.tests["y"].trailing_newline, Some(true));
+        assert_eq!(tests.tests["z"].trailing_newline, None);
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  trailing-newline: yes"
+            )
+            .unwrap_err()
+            .line,
+            2
+        );
+    }
+
+    #[test]
+    fn test_tags() {
+        let tests = parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "tags: smoke, regression
+x:",
+        )
+        .unwrap();
+        let mut tags = tests.tags.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        tags.sort_unstable();
+        assert_eq!(tags, vec!["regression", "smoke"]);
+        assert!(
+            parse_tests(&[], &CustomKeys::new(), &StatusAliases::new(), "x:")
+                .unwrap()
+                .tags
+                .is_empty()
+        );
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "tags: ,
+x:"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 1,
+                msg: "'tags' must specify at least one tag.".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "tags: a
+tags: b
+x:"
+            )
+            .unwrap_err()
+            .line,
+            2
+        );
+    }
+
+    #[test]
+    fn test_rerun_scope() {
+        let tests = parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "x:\n  rerun-scope: file\ny:\n  rerun-scope: command\nz:",
+        )
+        .unwrap();
+        assert!(tests.tests["x"].rerun_scope_file);
+        assert!(!tests.tests["y"].rerun_scope_file);
+        assert!(!tests.tests["z"].rerun_scope_file);
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  rerun-scope: all"
+            )
+            .unwrap_err()
+            .line,
+            2
+        );
+    }
+
+    #[test]
+    fn test_matches_file() {
+        let tests = parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "x:\n  stdout-matches-file: $DIR/x.out\n  stderr-matches-file: x.err",
+        )
+        .unwrap();
+        let x = &tests.tests["x"];
+        assert_eq!(x.stdout_matches_file.as_deref(), Some("$DIR/x.out"));
+        assert_eq!(x.stderr_matches_file.as_deref(), Some("x.err"));
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  stdout-matches-file:"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 2,
+                msg: "'stdout-matches-file' requires a path.".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_env_var() {
+        let tests = parse_tests(
+            &[],
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "x:\n  env-var: A=b\n  env-var: C\n  env-file: vars.env",
+        )
+        .unwrap();
+        let env = &tests.tests["x"].env;
+        assert_eq!(env["A"], Some("b".to_owned()));
+        assert_eq!(env["C"], None);
+        assert_eq!(tests.tests["x"].env_file, Some((3, "vars.env".to_owned())));
+        assert_eq!(
+            parse_tests(
+                &[],
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  env-file:"
+            )
+            .unwrap_err()
+            .line,
+            2
+        );
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split_words("").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            split_words(" -O2  -g\n-x ").unwrap(),
+            vec!["-O2", "-g", "-x"]
+        );
+        assert_eq!(
+            split_words(r#"a'b c'd "e \"f\" \g" h\ i ''"#).unwrap(),
+            vec!["ab cd", "e \"f\" \\g", "h i", ""]
+        );
+        assert!(split_words("'a").is_err());
+        assert!(split_words("\"a").is_err());
+        assert!(split_words("a\\").is_err());
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        env::set_var("LANG_TESTER_TEST_VAR", "abc");
+        env::remove_var("LANG_TESTER_UNSET_VAR");
+        assert_eq!(expand_env_vars("x"), "x");
+        assert_eq!(expand_env_vars("$LANG_TESTER_TEST_VAR:y"), "abc:y");
+        assert_eq!(expand_env_vars("${LANG_TESTER_TEST_VAR}y"), "abcy");
+        assert_eq!(expand_env_vars("x$LANG_TESTER_UNSET_VAR"), "x");
+        assert_eq!(
+            expand_env_vars("$$LANG_TESTER_TEST_VAR"),
+            "$LANG_TESTER_TEST_VAR"
+        );
+        assert_eq!(expand_env_vars("$ $"), "$ $");
+        assert_eq!(expand_env_vars("${x"), "${x");
+    }
+
+    #[test]
+    fn test_custom_keys() {
+        let mut custom_keys = CustomKeys::new();
+        custom_keys.insert(
+            "category".to_owned(),
+            Box::new(|val, data| match val {
+                "fast" | "slow" => {
+                    data.insert("category", val);
+                    Ok(())
+                }
+                _ => Err(format!("Unknown category '{}'.", val)),
+            }),
+        );
+        let tests = parse_tests(
+            &[],
+            &custom_keys,
+            &StatusAliases::new(),
+            "x:\n  category: slow",
+        )
+        .unwrap();
+        assert_eq!(tests.tests["x"].custom.get("category"), Some("slow"));
+        assert_eq!(
+            parse_tests(
+                &[],
+                &custom_keys,
+                &StatusAliases::new(),
+                "x:\n  status: error\n  category: quick"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 3,
+                msg: "Unknown category 'quick'.".to_owned()
+            }
         );
     }
 }
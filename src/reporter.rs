@@ -0,0 +1,751 @@
+use std::{
+    borrow::Cow,
+    io::{self, IsTerminal, Write},
+    mem,
+    os::unix::io::AsRawFd,
+    str,
+    sync::Mutex,
+    time::Duration,
+};
+
+use libc::{ioctl, winsize, TIOCGWINSZ};
+
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::tester::{write_with_colour, TestFailure, TestOutcome};
+
+/// Receives notifications as tests are run, so that users can report progress and results in
+/// whatever format they want. Each method has a default implementation which does nothing, so
+/// implementations need only implement the methods they are interested in.
+///
+/// Since tests are run in parallel, a `Reporter`'s methods can be called from multiple threads
+/// (though `started` and `finished` are only ever called from the thread that called
+/// [`LangTester::run`](struct.LangTester.html#method.run)). Notifications for different tests may
+/// thus be interleaved.
+pub trait Reporter: Send + Sync {
+    /// Called once, before searching for the tests to run (which, for large test directories, can
+    /// take some time).
+    fn discovering(&self) {}
+
+    /// Called once, before any tests are run, with the number of tests that will be run.
+    fn started(&self, _num_tests: usize) {}
+
+    /// Called when the test `test_name` starts.
+    fn test_started(&self, _test_name: &str) {}
+
+    /// Called when the test `test_name` has passed, having taken `duration` to run. `reruns` is
+    /// the number of times commands were rerun before the test passed.
+    fn test_passed(&self, _test_name: &str, _duration: Duration, _reruns: u64) {}
+
+    /// Called when the test `test_name`, one or more of whose commands have `bench: true`, has
+    /// passed, those commands having taken `ns_per_iter` nanoseconds (in total) on average to run.
+    /// By default this calls [`test_passed`](#method.test_passed).
+    fn test_measured(&self, test_name: &str, ns_per_iter: u64) {
+        self.test_passed(test_name, Duration::from_nanos(ns_per_iter), 0);
+    }
+
+    /// Called when the test `test_name` has failed.
+    fn test_failed(&self, _test_name: &str, _failure: &TestFailure) {}
+
+    /// Called when the test `test_name` has been ignored. `message` gives the reason, if any (and
+    /// is otherwise the empty string).
+    fn test_ignored(&self, _test_name: &str, _message: &str) {}
+
+    /// Called when the test `test_name` was not run because it cannot be run on this platform.
+    /// `message` gives the reason. By default this calls
+    /// [`test_ignored`](#method.test_ignored).
+    fn test_unsupported(&self, test_name: &str, message: &str) {
+        self.test_ignored(test_name, message);
+    }
+
+    /// Called when the test `test_name` was stopped because the suite timeout expired. This is not
+    /// called for tests which were not started because the suite timeout had already expired.
+    fn test_timed_out(&self, _test_name: &str) {}
+
+    /// Called once, after all tests have been run.
+    fn finished(&self, _summary: &Summary) {}
+
+    /// Called with a problem which does not stop tests from being run (e.g. a test file which
+    /// could not be read, or a test for a command that `test_cmds` does not return). `test_name`
+    /// is the test the problem relates to, if any.
+    fn warning(&self, _test_name: Option<&str>, _msg: &str) {}
+
+    /// Called with information about the test `test_name` which is not itself a result (e.g. the
+    /// command lines that `--verbose` and `--dry-run` ask to be shown).
+    fn test_message(&self, _test_name: &str, _msg: &str) {}
+
+    /// Called periodically (see
+    /// [`progress_interval`](struct.LangTester.html#method.progress_interval)) while a command of
+    /// the test `test_name` is running, with how long the command has been running for.
+    fn test_running_long(&self, _test_name: &str, _running_for: Duration) {}
+
+    /// Called once, before test files are validated (instead of being run) with `--check`, with
+    /// the number of test files that will be validated.
+    fn validation_started(&self, _num_tests: usize) {}
+
+    /// Called when the test data of `test_name` is found to be invalid, with a description of each
+    /// problem found.
+    fn test_invalid(&self, _test_name: &str, _errors: &[String]) {}
+
+    /// Called once, after all test files have been validated.
+    fn validation_finished(&self, _num_valid: usize, _num_invalid: usize) {}
+}
+
+/// A summary of a test run.
+#[derive(Debug)]
+pub struct Summary {
+    pub(crate) passed: usize,
+    pub(crate) ignored: usize,
+    pub(crate) filtered: usize,
+    /// The outcome of every test which was run, sorted by test name.
+    pub(crate) outcomes: Vec<(String, TestOutcome)>,
+}
+
+impl Summary {
+    /// The number of tests which passed. Ignored tests are not counted as having passed.
+    pub fn passed(&self) -> usize {
+        self.passed
+    }
+
+    /// The number of tests which failed.
+    pub fn failed(&self) -> usize {
+        self.failures().count()
+    }
+
+    /// The number of tests which did not complete because the
+    /// [suite timeout](struct.LangTester.html#method.suite_timeout) expired.
+    pub fn timed_out(&self) -> usize {
+        self.timed_out_tests().count()
+    }
+
+    /// The names of tests which did not complete because the suite timeout expired, sorted by
+    /// test name.
+    pub fn timed_out_tests(&self) -> impl Iterator<Item = &str> {
+        self.outcomes
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, TestOutcome::TimedOut))
+            .map(|(test_name, _)| test_name.as_str())
+    }
+
+    /// The number of tests which were ignored. Tests which were not run because they cannot be run
+    /// on this platform are not included (see [`unsupported`](#method.unsupported)).
+    pub fn ignored(&self) -> usize {
+        self.ignored
+    }
+
+    /// The number of tests which were not run because they cannot be run on this platform.
+    pub fn unsupported(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, TestOutcome::Unsupported(_)))
+            .count()
+    }
+
+    /// The number of tests with `bench: true` commands which passed (and whose timings were thus
+    /// measured). These are not counted as having passed.
+    pub fn measured(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, TestOutcome::Measured { .. }))
+            .count()
+    }
+
+    /// The number of tests which were filtered out (e.g. because they didn't match a filter
+    /// given on the command-line).
+    pub fn filtered(&self) -> usize {
+        self.filtered
+    }
+
+    /// The outcome of every test which was run, sorted by test name.
+    pub fn outcomes(&self) -> &[(String, TestOutcome)] {
+        &self.outcomes
+    }
+
+    /// The tests which passed only after one or more commands were rerun (and may thus be
+    /// flaky), and the number of reruns, sorted by test name.
+    pub fn flaky(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.outcomes
+            .iter()
+            .filter_map(|(test_name, outcome)| match outcome {
+                TestOutcome::Passed { reruns } if *reruns > 0 => {
+                    Some((test_name.as_str(), *reruns))
+                }
+                _ => None,
+            })
+    }
+
+    /// The tests which failed, and why, sorted by test name.
+    pub fn failures(&self) -> impl Iterator<Item = (&str, &TestFailure)> {
+        self.outcomes
+            .iter()
+            .filter_map(|(test_name, outcome)| match outcome {
+                TestOutcome::Failed(failure) => Some((test_name.as_str(), failure)),
+                _ => None,
+            })
+    }
+}
+
+/// The width (in characters) of terse output if stderr is not a terminal.
+const DEFAULT_TERSE_WIDTH: usize = 80;
+
+/// How `ConsoleReporter` formats the result of each test.
+#[derive(Clone, Copy)]
+pub(crate) enum OutputFormat {
+    /// One line per test (e.g. `test lang_tests::a ... ok`).
+    Pretty,
+    /// One character per test (`.` for passed, `b` for measured, `F` for failed, `i` for ignored).
+    Terse,
+}
+
+/// The default `Reporter`, which prints results to `stderr` in the same format as `cargo test`.
+pub(crate) struct ConsoleReporter {
+    test_threads: usize,
+    colour_choice: ColorChoice,
+    format: OutputFormat,
+    /// If true, results are not printed as tests complete, but are printed sorted by test name
+    /// once all tests have completed.
+    ordered: bool,
+    /// If set, the maximum number of bytes of each stderr/stdout report shown for a failing test.
+    max_report_bytes: Option<usize>,
+    /// In terse mode, the number of characters to print before wrapping onto a new line.
+    terse_width: usize,
+    /// In terse mode, the number of results printed on the current line (`None` if no results have
+    /// been printed yet).
+    terse_printed: Mutex<Option<usize>>,
+    /// Is a "discovering tests..." message currently shown (which `started` then replaces)?
+    discovering: Mutex<bool>,
+}
+
+impl ConsoleReporter {
+    pub(crate) fn new(
+        test_threads: usize,
+        colour_choice: ColorChoice,
+        format: OutputFormat,
+        ordered: bool,
+        max_report_bytes: Option<usize>,
+    ) -> Self {
+        ConsoleReporter {
+            test_threads,
+            colour_choice,
+            format,
+            ordered,
+            max_report_bytes,
+            terse_width: terminal_width().unwrap_or(DEFAULT_TERSE_WIDTH),
+            terse_printed: Mutex::new(None),
+            discovering: Mutex::new(false),
+        }
+    }
+
+    /// Write the result of the test `test_name`.
+    /// Print `msg` on a line of its own. In terse mode, the next result then starts a new line.
+    fn write_line(&self, msg: &str) {
+        if let OutputFormat::Terse = self.format {
+            let mut printed = self.terse_printed.lock().unwrap();
+            eprint!("\n{}", msg);
+            *printed = Some(self.terse_width);
+        } else {
+            eprintln!("{}", msg);
+        }
+    }
+
+    fn write_outcome(&self, test_name: &str, outcome: &TestOutcome) {
+        match outcome {
+            TestOutcome::Passed { reruns: 0 } => {
+                self.write_result(test_name, "ok", ".", Color::Green, "")
+            }
+            TestOutcome::Passed { reruns } => self.write_result(
+                test_name,
+                "ok",
+                ".",
+                Color::Green,
+                &format!("passed after {}", plural_reruns(*reruns)),
+            ),
+            TestOutcome::Measured { ns_per_iter } => self.write_result(
+                test_name,
+                &format!("bench: {} ns/iter", fmt_thousands(*ns_per_iter)),
+                "b",
+                Color::Cyan,
+                "",
+            ),
+            TestOutcome::Failed(_) => self.write_result(test_name, "FAILED", "F", Color::Red, ""),
+            TestOutcome::Ignored(message) => {
+                self.write_result(test_name, "ignored", "i", Color::Yellow, message)
+            }
+            TestOutcome::Unsupported(message) => {
+                self.write_result(test_name, "unsupported", "u", Color::Yellow, message)
+            }
+            TestOutcome::TimedOut => self.write_result(test_name, "timed out", "T", Color::Red, ""),
+        }
+    }
+
+    /// Write `result` (e.g. "ok") in `colour` for the test `test_name`, followed by `message` if it
+    /// is not empty. In terse mode, only `terse_result` (e.g. ".") is written.
+    fn write_result(
+        &self,
+        test_name: &str,
+        result: &str,
+        terse_result: &str,
+        colour: Color,
+        message: &str,
+    ) {
+        // Grab a lock on stderr so that we can avoid the possibility of lines blurring
+        // together in confusing ways.
+        let stderr = StandardStream::stderr(self.colour_choice);
+        let mut handle = stderr.lock();
+        if let OutputFormat::Terse = self.format {
+            let mut printed = self.terse_printed.lock().unwrap();
+            match *printed {
+                Some(n) if n < self.terse_width => *printed = Some(n + 1),
+                _ => {
+                    handle.write_all(b"\n").ok();
+                    *printed = Some(1);
+                }
+            }
+            handle.set_color(ColorSpec::new().set_fg(Some(colour))).ok();
+            handle.write_all(terse_result.as_bytes()).ok();
+            handle.reset().ok();
+            return;
+        }
+        if self.test_threads > 1 || self.ordered {
+            handle
+                .write_all(format!("\ntest lang_tests::{} ... ", test_name).as_bytes())
+                .ok();
+        }
+        handle.set_color(ColorSpec::new().set_fg(Some(colour))).ok();
+        handle.write_all(result.as_bytes()).ok();
+        handle.reset().ok();
+        if !message.is_empty() {
+            handle.write_all(format!(" ({})", message).as_bytes()).ok();
+        }
+    }
+}
+
+impl Reporter for ConsoleReporter {
+    fn discovering(&self) {
+        // The message is replaced once the tests have been found, which is only possible on a
+        // terminal: otherwise we would clutter the output for little benefit.
+        if io::stderr().is_terminal() {
+            eprint!("\ndiscovering tests...");
+            *self.discovering.lock().unwrap() = true;
+        }
+    }
+
+    fn started(&self, num_tests: usize) {
+        // Overwrite the "discovering tests..." message, if it is shown.
+        let prefix = if *self.discovering.lock().unwrap() {
+            "\r\x1b[2K"
+        } else {
+            "\n"
+        };
+        eprint!(
+            "{}running {} test{}",
+            prefix,
+            num_tests,
+            if num_tests == 1 { "" } else { "s" }
+        );
+    }
+
+    fn test_started(&self, test_name: &str) {
+        if let (OutputFormat::Pretty, 1, false) = (self.format, self.test_threads, self.ordered) {
+            eprint!("\ntest lang_test::{} ... ", test_name);
+        }
+    }
+
+    fn test_passed(&self, test_name: &str, _duration: Duration, reruns: u64) {
+        if !self.ordered {
+            self.write_outcome(test_name, &TestOutcome::Passed { reruns });
+        }
+    }
+
+    fn test_measured(&self, test_name: &str, ns_per_iter: u64) {
+        if !self.ordered {
+            self.write_outcome(test_name, &TestOutcome::Measured { ns_per_iter });
+        }
+    }
+
+    fn test_failed(&self, test_name: &str, _failure: &TestFailure) {
+        if !self.ordered {
+            self.write_outcome(test_name, &TestOutcome::Failed(TestFailure::default()));
+        }
+    }
+
+    fn test_ignored(&self, test_name: &str, message: &str) {
+        if !self.ordered {
+            self.write_outcome(test_name, &TestOutcome::Ignored(message.to_owned()));
+        }
+    }
+
+    fn test_unsupported(&self, test_name: &str, message: &str) {
+        if !self.ordered {
+            self.write_outcome(test_name, &TestOutcome::Unsupported(message.to_owned()));
+        }
+    }
+
+    fn test_timed_out(&self, test_name: &str) {
+        if !self.ordered {
+            self.write_outcome(test_name, &TestOutcome::TimedOut);
+        }
+    }
+
+    fn warning(&self, test_name: Option<&str>, msg: &str) {
+        let stderr = StandardStream::stderr(self.colour_choice);
+        let mut handle = stderr.lock();
+        match test_name {
+            Some(_) if self.test_threads == 1 && matches!(self.format, OutputFormat::Pretty) => {
+                // The warning follows the "test lang_test::... " which has already been printed.
+                handle
+                    .set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))
+                    .ok();
+                write!(handle, "warning: {} ", msg).ok();
+                handle.reset().ok();
+            }
+            _ => {
+                if let OutputFormat::Terse = self.format {
+                    // Start a new line, and make the next result start one too.
+                    *self.terse_printed.lock().unwrap() = Some(self.terse_width);
+                    writeln!(handle).ok();
+                } else if test_name.is_some() {
+                    writeln!(handle).ok();
+                }
+                if let Some(test_name) = test_name {
+                    write!(handle, "lang_tests::{} ... ", test_name).ok();
+                }
+                handle
+                    .set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))
+                    .ok();
+                write!(handle, "warning").ok();
+                handle.reset().ok();
+                write!(handle, ": {}", msg).ok();
+                if matches!(self.format, OutputFormat::Pretty) {
+                    writeln!(handle).ok();
+                }
+            }
+        }
+    }
+
+    fn test_message(&self, test_name: &str, msg: &str) {
+        self.write_line(&format!("lang_tests::{} {}", test_name, msg));
+    }
+
+    fn test_running_long(&self, test_name: &str, running_for: Duration) {
+        let running_for = running_for.as_secs_f64();
+        if self.test_threads == 1 && matches!(self.format, OutputFormat::Pretty) {
+            eprint!("running for {:.1} seconds... ", running_for);
+        } else {
+            self.write_line(&format!(
+                "lang_tests::{} ... has been running for {:.1} seconds",
+                test_name, running_for
+            ));
+        }
+    }
+
+    fn validation_started(&self, num_tests: usize) {
+        eprintln!("\nvalidating {} tests", num_tests);
+    }
+
+    fn test_invalid(&self, _test_name: &str, errors: &[String]) {
+        for e in errors {
+            write_with_colour("error", Color::Red, self.colour_choice);
+            eprintln!(": {}", e);
+        }
+    }
+
+    fn validation_finished(&self, num_valid: usize, num_invalid: usize) {
+        eprint!("\nvalidation result: ");
+        if num_invalid == 0 {
+            write_with_colour("ok", Color::Green, self.colour_choice);
+        } else {
+            write_with_colour("FAILED", Color::Red, self.colour_choice);
+        }
+        eprintln!(". {} valid; {} invalid\n", num_valid, num_invalid);
+    }
+
+    fn finished(&self, summary: &Summary) {
+        if self.ordered {
+            // Sort by directory first, so that a directory's tests are grouped together.
+            let mut outcomes = summary.outcomes().iter().collect::<Vec<_>>();
+            outcomes.sort_by_cached_key(|(test_name, _)| {
+                let mut components = test_name.split("::").collect::<Vec<_>>();
+                let file_name = components.pop();
+                (components, file_name)
+            });
+            for (test_name, outcome) in outcomes {
+                self.write_outcome(test_name, outcome);
+            }
+        }
+        let failures = summary.failures().collect::<Vec<_>>();
+        if !failures.is_empty() {
+            eprintln!("\n\nfailures:");
+            for (test_fname, test) in &failures {
+                if let Some(ref name) = test.panicked {
+                    eprintln!(
+                        "\n---- lang_tests::{} panic ----\n{} panicked",
+                        test_fname, name
+                    );
+                }
+                if let Some(ref cmd_name) = test.cmd_name {
+                    match test.cmd_line {
+                        Some(line) => eprintln!(
+                            "\n---- lang_tests::{} command ----\n{} (defined at {})",
+                            test_fname,
+                            cmd_name,
+                            test.defined_at(line)
+                        ),
+                        None => eprintln!(
+                            "\n---- lang_tests::{} command ----\n{}",
+                            test_fname, cmd_name
+                        ),
+                    }
+                }
+                if !test.not_reached.is_empty() {
+                    eprintln!(
+                        "\n---- lang_tests::{} not reached ----\ncommand(s) '{}' have tests \
+                         specified but were not run because an earlier command failed as \
+                         expected",
+                        test_fname,
+                        test.not_reached.join(", ")
+                    );
+                }
+                if !test.missing_commands.is_empty() {
+                    eprintln!(
+                        "\n---- lang_tests::{} missing commands ----\ncommand(s) '{}' have no \
+                         tests specified",
+                        test_fname,
+                        test.missing_commands.join(", ")
+                    );
+                }
+                if let Some((run, repeat)) = test.failed_repeat {
+                    eprintln!(
+                        "\n---- lang_tests::{} repeat{} ----\nfailed on run {} of {}",
+                        test_fname,
+                        defined_at(test, &["repeat"]),
+                        run,
+                        repeat
+                    );
+                }
+                if let Some(ref status) = test.status {
+                    eprintln!(
+                        "\n---- lang_tests::{} status{} ----\n{}",
+                        test_fname,
+                        defined_at(test, &["status"]),
+                        status
+                    );
+                }
+                if let Some(max_output_bytes) = test.output_exceeded {
+                    eprintln!(
+                        "\n---- lang_tests::{} output ----\noutput exceeded {} bytes",
+                        test_fname, max_output_bytes
+                    );
+                }
+                if let Some((peak_rss, max_peak_rss)) = test.peak_rss_exceeded {
+                    eprintln!(
+                        "\n---- lang_tests::{} peak rss{} ----\npeak RSS of {} bytes exceeded \
+                         max-peak-rss of {} bytes",
+                        test_fname,
+                        defined_at(test, &["max-peak-rss"]),
+                        peak_rss,
+                        max_peak_rss
+                    );
+                }
+                match test.expected_stdin_remaining {
+                    Some(0) | None if test.stdin_remaining != 0 => eprintln!(
+                        "\n---- lang_tests::{} stdin{} ----\n{} bytes of stdin were not consumed",
+                        test_fname,
+                        defined_at(test, &["stdin", "stdin-remaining"]),
+                        test.stdin_remaining
+                    ),
+                    Some(expected) if expected != test.stdin_remaining => eprintln!(
+                        "\n---- lang_tests::{} stdin{} ----\n{} bytes of stdin were not consumed \
+                         (expected {})",
+                        test_fname,
+                        defined_at(test, &["stdin", "stdin-remaining"]),
+                        test.stdin_remaining,
+                        expected
+                    ),
+                    _ => (),
+                }
+                if let Some(ref stderr) = test.stderr {
+                    eprintln!(
+                        "\n---- lang_tests::{} stderr{} ----\n",
+                        test_fname,
+                        defined_at(test, &STDERR_KEYS)
+                    );
+                    let report = test.stderr_match.as_ref().unwrap_or(stderr);
+                    eprint_match_msg(&truncate_report(report, self.max_report_bytes));
+                }
+                if let Some(ref stdout) = test.stdout {
+                    eprintln!(
+                        "\n---- lang_tests::{} stdout{} ----\n",
+                        test_fname,
+                        defined_at(test, &STDOUT_KEYS)
+                    );
+                    let report = test.stdout_match.as_ref().unwrap_or(stdout);
+                    eprint_match_msg(&truncate_report(report, self.max_report_bytes));
+                }
+            }
+            eprint!("failures:");
+            for (test_fname, _) in &failures {
+                eprint!("\n    lang_tests::{}", test_fname);
+            }
+        }
+
+        let timed_out = summary.timed_out_tests().collect::<Vec<_>>();
+        if !timed_out.is_empty() {
+            eprint!("\n\ntimed out (suite timeout expired):");
+            for test_fname in &timed_out {
+                eprint!("\n    lang_tests::{}", test_fname);
+            }
+        }
+
+        let flaky = summary.flaky().collect::<Vec<_>>();
+        if !flaky.is_empty() {
+            eprint!("\n\npassed after reruns:");
+            for (test_fname, reruns) in flaky {
+                eprint!(
+                    "\n    lang_tests::{} ({})",
+                    test_fname,
+                    plural_reruns(reruns)
+                );
+            }
+        }
+
+        eprint!("\n\ntest result: ");
+        if failures.is_empty() && timed_out.is_empty() {
+            write_with_colour("ok", Color::Green, self.colour_choice);
+        } else {
+            write_with_colour("FAILED", Color::Red, self.colour_choice);
+        }
+        eprint!(". {} passed; {} failed; ", summary.passed, failures.len());
+        if !timed_out.is_empty() {
+            eprint!("{} timed out; ", timed_out.len());
+        }
+        eprint!("{} ignored; ", summary.ignored);
+        let unsupported = summary.unsupported();
+        if unsupported > 0 {
+            eprint!("{} unsupported; ", unsupported);
+        }
+        eprintln!(
+            "{} measured; {} filtered out\n",
+            summary.measured(),
+            summary.filtered
+        );
+    }
+}
+
+/// The keys which specify what a command's stderr must match.
+const STDERR_KEYS: [&str; 4] = [
+    "stderr",
+    "stderr-exact",
+    "stderr-matches-file",
+    "stderr-regex",
+];
+/// The keys which specify what a command's stdout must match.
+const STDOUT_KEYS: [&str; 4] = [
+    "stdout",
+    "stdout-exact",
+    "stdout-matches-file",
+    "stdout-regex",
+];
+
+/// If `test`'s failing command specifies any of `keys`, return " (defined at <location>)" for the
+/// first of them in the test data, or the empty string otherwise.
+fn defined_at(test: &TestFailure, keys: &[&str]) -> String {
+    keys.iter()
+        .filter_map(|k| test.key_line(k))
+        .min()
+        .map(|line| format!(" (defined at {})", test.defined_at(line)))
+        .unwrap_or_default()
+}
+
+/// Format `n` with commas separating each group of thousands (e.g. "1,234,567"), as `cargo bench`
+/// does.
+fn fmt_thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut groups = digits
+        .as_bytes()
+        .rchunks(3)
+        .map(|g| str::from_utf8(g).unwrap())
+        .collect::<Vec<_>>();
+    groups.reverse();
+    groups.join(",")
+}
+
+/// Return a description of `reruns` (e.g. "1 rerun" or "2 reruns").
+fn plural_reruns(reruns: u64) -> String {
+    if reruns == 1 {
+        "1 rerun".to_owned()
+    } else {
+        format!("{} reruns", reruns)
+    }
+}
+
+/// The width of the terminal attached to stderr, or `None` if stderr is not a terminal.
+fn terminal_width() -> Option<usize> {
+    if !io::stderr().is_terminal() {
+        return None;
+    }
+    let mut ws = unsafe { mem::zeroed::<winsize>() };
+    if unsafe { ioctl(io::stderr().as_raw_fd(), TIOCGWINSZ, &mut ws) } == -1 || ws.ws_col == 0 {
+        return None;
+    }
+    Some(usize::from(ws.ws_col))
+}
+
+/// If `report` is longer than `max_bytes`, return its first and last (roughly) `max_bytes / 2`
+/// bytes, separated by a line noting how many bytes were elided.
+fn truncate_report(report: &str, max_bytes: Option<usize>) -> Cow<'_, str> {
+    let max_bytes = match max_bytes {
+        Some(x) if report.len() > x => x,
+        _ => return Cow::Borrowed(report),
+    };
+    let mut head_end = max_bytes / 2;
+    while !report.is_char_boundary(head_end) {
+        head_end -= 1;
+    }
+    let mut tail_start = report.len() - max_bytes / 2;
+    while !report.is_char_boundary(tail_start) {
+        tail_start += 1;
+    }
+    Cow::Owned(format!(
+        "{}\n... {} bytes elided ...\n{}",
+        &report[..head_end],
+        tail_start - head_end,
+        &report[tail_start..]
+    ))
+}
+
+fn eprint_match_msg(msg: &str) {
+    if msg.ends_with('\n') {
+        eprint!("{}", msg);
+    } else {
+        eprintln!("{}", msg);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fmt_thousands() {
+        assert_eq!(fmt_thousands(0), "0");
+        assert_eq!(fmt_thousands(999), "999");
+        assert_eq!(fmt_thousands(1000), "1,000");
+        assert_eq!(fmt_thousands(1234567), "1,234,567");
+    }
+
+    #[test]
+    fn test_truncate_report() {
+        assert_eq!(truncate_report("abcdef", None), "abcdef");
+        assert_eq!(truncate_report("abcdef", Some(6)), "abcdef");
+        assert_eq!(
+            truncate_report("abcdefgh", Some(4)),
+            "ab\n... 4 bytes elided ...\ngh"
+        );
+        // Truncation never splits a multi-byte character.
+        assert_eq!(
+            truncate_report("a\u{e9}bc\u{e9}d", Some(4)),
+            "a\n... 6 bytes elided ...\nd"
+        );
+    }
+}
@@ -1,56 +1,155 @@
 use std::{
-    cmp::max,
+    borrow::Cow,
+    cell::RefCell,
+    cmp::min,
     collections::{hash_map::HashMap, HashSet},
     convert::TryFrom,
-    env,
-    fs::canonicalize,
-    io::{self, Read, Write},
+    env, fmt,
+    fs::{self, canonicalize, File},
+    io::{self, IsTerminal, Read, Write},
+    mem,
     os::{
         raw::c_int,
-        unix::{io::AsRawFd, process::ExitStatusExt},
+        unix::{
+            io::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+            process::{CommandExt, ExitStatusExt},
+        },
     },
-    panic::{catch_unwind, RefUnwindSafe},
+    panic::{catch_unwind, AssertUnwindSafe, RefUnwindSafe},
     path::{Path, PathBuf, MAIN_SEPARATOR},
     process::{self, Command, ExitStatus},
-    str,
+    ptr, str,
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex, Once,
     },
-    thread::sleep,
-    time::{Duration, Instant},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use fm::{FMBuilder, FMatchError};
+use fm::FMBuilder;
 use getopts::Options;
+use globset::{GlobBuilder, GlobMatcher};
 use libc::{
-    close, fcntl, poll, pollfd, F_GETFL, F_SETFL, O_NONBLOCK, POLLERR, POLLHUP, POLLIN, POLLNVAL,
-    POLLOUT,
+    cfmakeraw, fcntl, getrlimit, kill, openpty, pid_t, pthread_sigmask, rlim_t, rlimit,
+    setpriority, setrlimit, sigaddset, sigemptyset, sigset_t, sigwait, tcgetattr, tcsetattr,
+    termios, wait4, winsize, ECHO, EIO, FD_CLOEXEC, F_GETFD, F_GETFL, F_SETFD, F_SETFL, ICANON,
+    ISIG, O_NONBLOCK, PRIO_PROCESS, RLIMIT_AS, RLIMIT_DATA, SIGINT, SIGKILL, SIGTERM, SIG_BLOCK,
+    TCSANOW, VEOF,
 };
+use polling::{Event, Events, PollMode, Poller};
+use regex::Regex;
+use tempfile::{NamedTempFile, TempDir};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use threadpool::ThreadPool;
 use walkdir::WalkDir;
 
-use crate::{fatal, parser::parse_tests};
+use crate::{
+    diff::{fuzzy_diff, unified_diff},
+    error::LangTesterError,
+    fatal,
+    parsed::{CustomData, ParsedTests},
+    parser::{expand_env_vars, parse_tests_all_errors, CustomKeys, ParseError, StatusAliases},
+    reporter::{ConsoleReporter, OutputFormat, Reporter, Summary},
+    yaml::parse_yaml_tests,
+    EXIT_FATAL, EXIT_TESTS_FAILED,
+};
 
 /// The size of the (stack allocated) buffer use to read stderr/stdout from a child process.
 const READBUF: usize = 1024 * 4; // bytes
-/// Print a warning to the user every multiple of `TIMEOUT` seconds that a child process has run
-/// without completing.
-const TIMEOUT: u64 = 60; // seconds
-/// The time that we should initially wait() for a child process to exit. This should be a very
-/// small value, as most child processes will exit almost immediately.
-const INITIAL_WAIT_TIMEOUT: u64 = 10000; // nanoseconds
-/// The maximum time we should wait() between checking if a child process has exited.
-const MAX_WAIT_TIMEOUT: u64 = 250_000_000; // nanoseconds
-                                           //
+/// By default, print a warning to the user every multiple of `DEFAULT_PROGRESS_INTERVAL` seconds
+/// that a child process has run without completing.
+const DEFAULT_PROGRESS_INTERVAL: u64 = 60; // seconds
+/// By default, the number of bytes initially reserved for capturing each of a command's stderr and
+/// stdout.
+const DEFAULT_OUTPUT_CAPACITY: usize = 16 * 1024; // bytes
 /// The default maximum number of times to rerun a command if it fails and a rerun-if-* matches.
 const DEFAULT_RERUN_AT_MOST: u64 = 3;
+/// The character which signals EOF when written to a pseudo-terminal (i.e. Ctrl-D).
+const PTY_EOF: u8 = 4;
+
+/// The process group IDs of all currently running child processes. Each child is placed in its
+/// own process group so that, if we need to terminate it, any processes it has itself spawned are
+/// terminated too.
+static CHILD_PGIDS: Mutex<Vec<pid_t>> = Mutex::new(Vec::new());
+/// Ensures that we only install the signal handling thread once.
+static SIGNAL_HANDLER: Once = Once::new();
+/// The name of the environment variable through which commands are told the path of their test's
+/// temporary directory.
+const TMPDIR_ENV_VAR: &str = "LANG_TESTER_TMPDIR";
+/// The name of the environment variable through which commands are told their test's name.
+const TEST_NAME_ENV_VAR: &str = "LANG_TESTER_TEST_NAME";
+/// The name of the environment variable through which commands are told their test file's path.
+const TEST_PATH_ENV_VAR: &str = "LANG_TESTER_TEST_PATH";
+
+/// A closure which constructs a [`Command`] when called. See
+/// [`LangTester::test_cmds_lazy`](struct.LangTester.html#method.test_cmds_lazy).
+pub type LazyCommand = Box<dyn FnOnce() -> Command>;
+
+thread_local! {
+    /// The temporary directory of the test currently being run on this thread, if any.
+    static TEST_TMPDIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    /// The custom data of each command (keyed by its lower-cased name) of the test currently being
+    /// run on this thread.
+    static TEST_CUSTOM_DATA: RefCell<HashMap<String, CustomData>> = RefCell::new(HashMap::new());
+}
+
+/// If [`LangTester::test_tmpdirs`](struct.LangTester.html#method.test_tmpdirs) is enabled, return
+/// the path of the temporary directory of the test currently being run. This is only meaningful
+/// when called from within one of the user functions that `lang_tester` calls for each test (e.g.
+/// [`test_cmds`](struct.LangTester.html#method.test_cmds) or
+/// [`before_each`](struct.LangTester.html#method.before_each)): at any other point it returns
+/// `None`.
+pub fn test_tmpdir() -> Option<PathBuf> {
+    TEST_TMPDIR.with(|x| x.borrow().clone())
+}
+
+/// Return the name (e.g. `lang_tests::a::b.x`) that `lang_tester` uses, when reporting results
+/// and matching filters, for the test file `file` found within `test_dir`. Both paths are
+/// canonicalized (if possible) in the same way as [`LangTester::test_dir`] canonicalizes its
+/// argument, so this can be used by external tools to derive names identical to those
+/// `lang_tester` reports.
+pub fn test_name(test_dir: &Path, file: &Path) -> String {
+    let test_dir = canonicalize(test_dir).unwrap_or_else(|_| test_dir.to_owned());
+    let file = canonicalize(file).unwrap_or_else(|_| file.to_owned());
+    format!("lang_tests::{}", test_fname(Some(&test_dir), &file))
+}
+
+/// Return the data collected from the [custom keys](struct.LangTester.html#method.custom_key) of
+/// the command `cmd_name` (which is matched case-insensitively) in the test currently being run.
+/// As with [`test_tmpdir`](fn.test_tmpdir.html), this is only meaningful when called from within
+/// one of the user functions that `lang_tester` calls for each test once its test data has been
+/// parsed (e.g. [`test_cmds`](struct.LangTester.html#method.test_cmds) or
+/// [`matcher`](struct.LangTester.html#method.matcher)): at any other point, or if the test does
+/// not specify `cmd_name`, it returns `None`.
+pub fn custom_data(cmd_name: &str) -> Option<CustomData> {
+    TEST_CUSTOM_DATA.with(|x| x.borrow().get(&cmd_name.to_lowercase()).cloned())
+}
 
 pub struct LangTester {
     use_cmdline_args: bool,
     test_path_filter: Option<Box<dyn Fn(&Path) -> bool + RefUnwindSafe>>,
     cmdline_filters: Option<Vec<String>>,
+    /// Tests whose names contain any of these strings are not run.
+    cmdline_skips: Vec<String>,
+    /// If non-empty, only test files with at least one of these tags are run.
+    only_tags: Vec<String>,
+    /// If set, only tests whose names match this regular expression are run.
+    filter_regex: Option<String>,
+    /// If set, only files whose paths match this glob pattern are considered tests.
+    test_glob: Option<String>,
+    /// If set, the test files to use instead of searching `test_dir`.
+    test_paths: Option<Vec<PathBuf>>,
+    /// If `test_dir` could not be canonicalized, a description of why.
+    test_dir_error: Option<String>,
+    /// If true, test files are parsed and checked, but no tests are run.
+    validate_only: bool,
+    /// If true, only run the tests which failed the last time they were run (if any did).
+    failed_only: bool,
+    /// If true, paths matching the patterns in [`IGNORE_FILE`]s are not considered tests.
+    ignore_files: bool,
+    before_all: Option<Box<dyn FnOnce()>>,
+    after_all: Option<Box<dyn FnOnce()>>,
     inner: Arc<LangTesterPooler>,
 }
 
@@ -60,9 +159,29 @@ struct LangTesterPooler {
     test_dir: Option<PathBuf>,
     test_threads: usize,
     ignored: bool,
+    /// If true, tests are run irrespective of whether they are ignored or not.
+    include_ignored: bool,
     nocapture: bool,
-    comment_prefix: Option<String>,
-    test_extract: Option<Box<dyn Fn(&Path) -> String + RefUnwindSafe + Send + Sync>>,
+    /// If true, the commands for each test are printed rather than run.
+    dry_run: bool,
+    /// If true, each command is printed before it is run.
+    verbose: bool,
+    /// Lines which, ignoring indentation, begin with any of these are treated as comments.
+    comment_prefixes: Vec<String>,
+    /// If true, test data is parsed as YAML rather than in the default format.
+    yaml_tests: bool,
+    /// Keys, other than the built-in keys, which tests can specify for a command.
+    custom_keys: CustomKeys,
+    /// User-defined names which tests can use in `status` and `rerun-if-status`.
+    status_aliases: StatusAliases,
+    /// Extracts the test data from a test file, returning `None` if the file is not a test.
+    test_extract: Option<Box<dyn Fn(&Path) -> Option<String> + RefUnwindSafe + Send + Sync>>,
+    /// If set, the results of `test_extract` keyed by test file path.
+    extract_cache: Option<Mutex<HashMap<PathBuf, CachedExtract>>>,
+    /// If set, overrides `fm`'s default for ignoring leading whitespace.
+    ignore_leading_whitespace: Option<bool>,
+    /// If set, overrides `fm`'s default for ignoring trailing whitespace.
+    ignore_trailing_whitespace: Option<bool>,
     fm_options: Option<
         Box<
             dyn for<'a> Fn(&'a Path, TestStream, FMBuilder<'a>) -> FMBuilder<'a>
@@ -71,8 +190,91 @@ struct LangTesterPooler {
                 + Sync,
         >,
     >,
-    test_cmds: Option<Box<dyn Fn(&Path) -> Vec<(&str, Command)> + RefUnwindSafe + Send + Sync>>,
+    /// If set, used instead of `fm_options` for the `rerun-if-stderr` and `rerun-if-stdout`
+    /// fuzzy matchers.
+    rerun_fm_options: Option<
+        Box<
+            dyn for<'a> Fn(&'a Path, TestStream, FMBuilder<'a>) -> FMBuilder<'a>
+                + RefUnwindSafe
+                + Send
+                + Sync,
+        >,
+    >,
+    test_cmds: Option<Box<dyn Fn(&Path) -> Vec<(&str, LazyCommand)> + RefUnwindSafe + Send + Sync>>,
+    matcher: Option<
+        Box<
+            dyn Fn(&Path, TestStream, &str, &str) -> Result<(), String>
+                + RefUnwindSafe
+                + Send
+                + Sync,
+        >,
+    >,
     rerun_at_most: u64,
+    /// If set, limits the number of child processes which can be running at any one time.
+    max_processes: Option<Semaphore>,
+    /// If set, the maximum number of bytes of stderr or stdout captured from a command.
+    max_output_bytes: Option<usize>,
+    /// The number of bytes initially reserved for capturing each of a command's stderr and stdout.
+    output_capacity: usize,
+    /// If set, the scheduling priority ("niceness") at which commands are run.
+    child_nice: Option<i32>,
+    /// If set, the directory to which commands' output and exit statuses are written.
+    artifact_dir: Option<PathBuf>,
+    /// If true, artifacts are written for every command run, not just those that fail.
+    all_artifacts: bool,
+    before_each: Option<Box<dyn Fn(&Path) + RefUnwindSafe + Send + Sync>>,
+    after_each: Option<Box<dyn Fn(&Path) + RefUnwindSafe + Send + Sync>>,
+    on_complete: Option<Box<dyn Fn(&str, &TestOutcome) + RefUnwindSafe + Send + Sync>>,
+    between_cmds: Option<
+        Box<
+            dyn Fn(&Path, &str, &mut Command, &ExitStatus, &str, &str)
+                + RefUnwindSafe
+                + Send
+                + Sync,
+        >,
+    >,
+    /// The `stdin` given to a command (keyed by its lower-cased name) when a test does not
+    /// specify one.
+    default_stdin: HashMap<String, String>,
+    /// The status a command is expected to exit with if a test does not specify one.
+    default_status: Status,
+    /// How often to warn the user that a command is still running. If `None`, no warnings are
+    /// printed.
+    progress_interval: Option<Duration>,
+    /// If set, the maximum time the whole test suite is allowed to run for.
+    suite_timeout: Option<Duration>,
+    /// If set, the time at which the suite timeout expires: this is set when tests start to run.
+    suite_deadline: Option<Instant>,
+    /// If true, a test referencing a command name not returned by `test_cmds` is a fatal error;
+    /// otherwise a warning is printed and that part of the test is ignored.
+    strict_command_names: bool,
+    /// If true, a test which has no tests specified for one of the commands returned by
+    /// `test_cmds` fails.
+    require_all_commands: bool,
+    /// If true, each test is given its own temporary directory.
+    test_tmpdirs: bool,
+    /// Whether output to the terminal is coloured.
+    colour_choice: ColorChoice,
+    /// How the default reporter formats each test's result.
+    output_format: OutputFormat,
+    /// If true, the default reporter prints results sorted by test name once all tests have run.
+    ordered_output: bool,
+    /// If set, the maximum number of bytes of a failing command's stderr/stdout (or the
+    /// description of why they did not match) that the default reporter prints.
+    max_report_bytes: Option<usize>,
+    /// If true, a unified diff is added to the description of why stderr/stdout did not match
+    /// a fuzzy match pattern.
+    fuzzy_diffs: bool,
+    /// If true, every check of a command's stderr/stdout is run, and all that fail are reported,
+    /// rather than only the first that fails.
+    report_all_failures: bool,
+    /// If set, a program (and its arguments) which every command is run under.
+    command_wrapper: Option<Vec<String>>,
+    /// If set, the shell (and its arguments) used to run `ignore-if` commands.
+    shell: Option<Vec<String>>,
+    /// Where progress and results are reported to. If `None` when tests are run, a
+    /// `ConsoleReporter` is used.
+    reporter: Option<Box<dyn Reporter>>,
 }
 
 /// Specify a given test stream.
@@ -84,62 +286,430 @@ pub enum TestStream {
 
 impl LangTester {
     /// Create a new `LangTester` with default options. Note that, at a minimum, you need to call
-    /// [`test_dir`](#method.test_dir), [`test_extract`](#method.test_extract), and
-    /// [`test_cmds`](#method.test_cmds).
+    /// [`test_dir`](#method.test_dir) (or [`test_paths`](#method.test_paths)),
+    /// [`test_extract`](#method.test_extract), and [`test_cmds`](#method.test_cmds).
     pub fn new() -> Self {
         LangTester {
             test_path_filter: None,
             use_cmdline_args: true,
             cmdline_filters: None,
+            cmdline_skips: Vec::new(),
+            only_tags: Vec::new(),
+            filter_regex: None,
+            test_glob: None,
+            test_paths: None,
+            test_dir_error: None,
+            validate_only: false,
+            failed_only: false,
+            ignore_files: false,
+            before_all: None,
+            after_all: None,
             inner: Arc::new(LangTesterPooler {
                 test_dir: None,
                 ignored: false,
+                include_ignored: false,
                 nocapture: false,
-                comment_prefix: None,
+                dry_run: false,
+                verbose: false,
+                comment_prefixes: Vec::new(),
+                yaml_tests: false,
+                custom_keys: CustomKeys::new(),
+                status_aliases: StatusAliases::new(),
                 test_threads: num_cpus::get(),
+                ignore_leading_whitespace: None,
+                ignore_trailing_whitespace: None,
                 fm_options: None,
+                rerun_fm_options: None,
                 test_extract: None,
+                extract_cache: None,
                 test_cmds: None,
+                matcher: None,
                 rerun_at_most: DEFAULT_RERUN_AT_MOST,
+                max_processes: None,
+                max_output_bytes: None,
+                child_nice: None,
+                artifact_dir: None,
+                all_artifacts: false,
+                output_capacity: DEFAULT_OUTPUT_CAPACITY,
+                before_each: None,
+                after_each: None,
+                on_complete: None,
+                between_cmds: None,
+                default_stdin: HashMap::new(),
+                default_status: Status::Success,
+                progress_interval: Some(Duration::from_secs(DEFAULT_PROGRESS_INTERVAL)),
+                suite_timeout: None,
+                suite_deadline: None,
+                strict_command_names: true,
+                require_all_commands: false,
+                test_tmpdirs: false,
+                colour_choice: default_colour_choice(),
+                output_format: OutputFormat::Pretty,
+                ordered_output: false,
+                max_report_bytes: None,
+                fuzzy_diffs: false,
+                report_all_failures: false,
+                command_wrapper: None,
+                shell: None,
+                reporter: None,
             }),
         }
     }
 
     /// Specify the directory where test files are contained. Note that this directory will be
     /// searched recursively (i.e. subdirectories and their contents will also be considered as
-    /// potential test files).
+    /// potential test files). Relative paths are relative to the current working directory, and a
+    /// leading `~` is expanded to the user's home directory. If the directory cannot be accessed,
+    /// [`try_run`](#method.try_run) returns a [`LangTesterError::Config`] naming it.
     pub fn test_dir(&mut self, test_dir: &str) -> &mut Self {
+        let path = expand_tilde(test_dir);
         let inner = Arc::get_mut(&mut self.inner).unwrap();
-        inner.test_dir = Some(canonicalize(test_dir).unwrap());
+        match canonicalize(&path) {
+            Ok(x) => {
+                inner.test_dir = Some(x);
+                self.test_dir_error = None;
+            }
+            Err(e) => {
+                inner.test_dir = None;
+                self.test_dir_error = Some(if e.kind() == io::ErrorKind::NotFound {
+                    format!("test_dir '{}' not found: {}", test_dir, e)
+                } else {
+                    format!("test_dir '{}' could not be accessed: {}", test_dir, e)
+                });
+            }
+        }
+        self
+    }
+
+    /// Specify the test files explicitly, rather than searching [`test_dir`](#method.test_dir) for
+    /// them. This is useful if, for example, test files are generated, or do not live in a single
+    /// directory tree. [`test_path_filter`](#method.test_path_filter) and
+    /// [`test_extract`](#method.test_extract) are still applied to each path. If `test_dir` is
+    /// also specified, test names are relative to it where possible; otherwise a test's name is
+    /// its file name.
+    pub fn test_paths(&mut self, test_paths: Vec<PathBuf>) -> &mut Self {
+        self.test_paths = Some(test_paths);
         self
     }
 
     /// Specify the number of simultaneous running test cases. Defaults to using
-    /// all available CPUs.
+    /// all available CPUs, as does specifying 0. If [`use_cmdline_args`](#method.use_cmdline_args)
+    /// is `true`, this can be overridden by `--test-threads` or the `RUST_TEST_THREADS`
+    /// environment variable.
     pub fn test_threads(&mut self, test_threads: usize) -> &mut Self {
         let inner = Arc::get_mut(&mut self.inner).unwrap();
-        inner.test_threads = test_threads;
+        inner.test_threads = if test_threads == 0 {
+            num_cpus::get()
+        } else {
+            test_threads
+        };
         self
     }
 
     /// Specify the maximum number of times to rerun a test if it fails and a rerun-if-* matches.
-    /// Defaults to 3.
+    /// Defaults to 3. Individual commands can override this with the `rerun-at-most` key.
     pub fn rerun_at_most(&mut self, rerun_at_most: u64) -> &mut Self {
         let inner = Arc::get_mut(&mut self.inner).unwrap();
         inner.rerun_at_most = rerun_at_most;
         self
     }
 
+    /// Specify the maximum number of commands which can be running simultaneously. By default,
+    /// this is limited only by the number of test threads (see
+    /// [`test_threads`](#method.test_threads)). Setting this to a lower value than the number of
+    /// test threads can be useful if commands are resource hungry (e.g. use a lot of memory), as
+    /// it allows lightweight parts of testing to run in parallel while limiting the number of
+    /// heavyweight commands running at any one time.
+    pub fn max_processes(&mut self, max_processes: usize) -> &mut Self {
+        let inner = Arc::get_mut(&mut self.inner).unwrap();
+        inner.max_processes = Some(Semaphore::new(max_processes));
+        self
+    }
+
+    /// Run each command at the scheduling priority ("niceness") `nice` (as set by `setpriority`,
+    /// where higher values mean lower priority), so that heavyweight commands do not starve other
+    /// processes (including `lang_tester` itself) of CPU time. Note that unprivileged users can
+    /// generally only set a niceness at or above that of `lang_tester`: if the niceness cannot be
+    /// set, running the command fails.
+    ///
+    /// By default, commands run at the same niceness as `lang_tester`.
+    pub fn child_nice(&mut self, nice: i32) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().child_nice = Some(nice);
+        self
+    }
+
+    /// Specify a directory to which the captured stderr, stdout, and exit status of each failing
+    /// command are written, which is useful for inspecting failures after the fact (e.g. on CI).
+    /// For a test `a::b.x` whose command `Run` fails, the files `a/b.x.run.stderr`,
+    /// `a/b.x.run.stdout`, and `a/b.x.run.status` are written within the directory (creating
+    /// directories as necessary and overwriting any existing files); if the command later passes,
+    /// those files are removed. See also [`all_artifacts`](#method.all_artifacts).
+    pub fn artifact_dir(&mut self, artifact_dir: PathBuf) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().artifact_dir = Some(artifact_dir);
+        self
+    }
+
+    /// If set to `true`, and an [`artifact_dir`](#method.artifact_dir) is specified, artifacts are
+    /// written for every command run, not just those which fail. If a command is rerun, the
+    /// artifacts of its last run are kept. Defaults to `false`.
+    pub fn all_artifacts(&mut self, all_artifacts: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().all_artifacts = all_artifacts;
+        self
+    }
+
+    /// Specify the maximum number of bytes that will be captured from each of a command's stderr
+    /// and stdout. If a command produces more output than this on either stream, the command is
+    /// killed and the test fails. This is a safety valve for commands which (e.g. due to a bug)
+    /// produce unbounded output that would otherwise consume ever more memory.
+    ///
+    /// This option defaults to `None` (i.e. no limit).
+    pub fn max_output_bytes(&mut self, max_output_bytes: usize) -> &mut Self {
+        let inner = Arc::get_mut(&mut self.inner).unwrap();
+        inner.max_output_bytes = Some(max_output_bytes);
+        self
+    }
+
+    /// Specify the number of bytes initially reserved for capturing each of a command's stderr and
+    /// stdout (capped at [`max_output_bytes`](#method.max_output_bytes), if that is set). Captured
+    /// output can grow beyond this, but if commands routinely produce large amounts of output,
+    /// increasing this avoids the cost of repeatedly reallocating it.
+    ///
+    /// This option defaults to 16KiB.
+    pub fn output_capacity(&mut self, output_capacity: usize) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().output_capacity = output_capacity;
+        self
+    }
+
+    /// If `true` (the default), a test which references a command name not returned by
+    /// [`test_cmds`](#method.test_cmds) causes the whole test run to abort. If `false`, a warning
+    /// is printed instead and the sub-tests for such command names are ignored. This can be useful
+    /// when temporarily adding tests for commands that `test_cmds` does not yet produce.
+    pub fn strict_command_names(&mut self, strict_command_names: bool) -> &mut Self {
+        let inner = Arc::get_mut(&mut self.inner).unwrap();
+        inner.strict_command_names = strict_command_names;
+        self
+    }
+
+    /// If `true`, a test fails (without any of its commands being run) if it does not specify
+    /// tests for every command returned by [`test_cmds`](#method.test_cmds). Otherwise, a command
+    /// with no tests specified is run with the default expectations (i.e. it must exit
+    /// successfully, but may produce any output), which can hide the fact that a test file has
+    /// accidentally omitted a command's tests.
+    ///
+    /// This option defaults to `false`.
+    pub fn require_all_commands(&mut self, require_all_commands: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().require_all_commands = require_all_commands;
+        self
+    }
+
+    /// If set to `true`, a fresh temporary directory is created for each test before
+    /// [`before_each`](#method.before_each) is called. Its path can be obtained with
+    /// [`test_tmpdir`](fn.test_tmpdir.html) from within the functions `lang_tester` calls for
+    /// that test (e.g. [`test_cmds`](#method.test_cmds)), and is passed to each of the test's
+    /// commands in the `LANG_TESTER_TMPDIR` environment variable. The directory (and its
+    /// contents) is deleted once the test has completed unless `--nocapture` was passed, in which
+    /// case its path is printed so that its contents can be inspected.
+    ///
+    /// This option defaults to `false`.
+    pub fn test_tmpdirs(&mut self, test_tmpdirs: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().test_tmpdirs = test_tmpdirs;
+        self
+    }
+
+    /// Specify a program, and any arguments to be passed to it, under which every command returned
+    /// by [`test_cmds`](#method.test_cmds) is run. For example,
+    /// `command_wrapper(vec!["valgrind".to_owned(), "--error-exitcode=1".to_owned()])` causes a
+    /// command `prog arg1 arg2` to be run as `valgrind --error-exitcode=1 prog arg1 arg2`. A
+    /// test's `status` (etc.) is then checked against the wrapper program's exit status. The
+    /// command's environment variable changes and working directory are applied to the wrapper
+    /// program, but other settings (e.g. those made with `env_clear` or `pre_exec`) are not.
+    /// `command_wrapper` must contain at least a program name.
+    pub fn command_wrapper(&mut self, command_wrapper: Vec<String>) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().command_wrapper = Some(command_wrapper);
+        self
+    }
+
+    /// Specify the shell, and any arguments to be passed to it, used to run `ignore-if` commands.
+    /// The command is passed as the final argument. For example, `shell(vec!["bash".to_owned(),
+    /// "-c".to_owned()])` allows `ignore-if` commands to use `bash`-specific syntax. `shell` must
+    /// contain at least a program name.
+    ///
+    /// By default, `$SHELL -c` (or, if `SHELL` is not set, `/bin/sh -c`) is used, except on
+    /// Windows, where `cmd /C` is used.
+    pub fn shell(&mut self, shell: Vec<String>) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().shell = Some(shell);
+        self
+    }
+
+    /// Specify the maximum number of bytes of a failing command's stderr and stdout that are
+    /// printed when reporting the failure. If the stderr or stdout report (which, if the output
+    /// did not match what was expected, includes an explanation of why) is longer than this, only
+    /// its beginning and end are printed, separated by a note of how many bytes were elided.
+    /// This only affects what is printed: matching always uses the full output. This has no
+    /// effect if a custom [`reporter`](#method.reporter) is used.
+    ///
+    /// This option defaults to `None` (i.e. no limit).
+    pub fn max_report_bytes(&mut self, max_report_bytes: usize) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().max_report_bytes = Some(max_report_bytes);
+        self
+    }
+
+    /// If set to `true`, when a command's stderr or stdout does not match the test's fuzzy match
+    /// pattern, a unified diff between the pattern and the actual output is added to the
+    /// description of why the match failed. Lines matched by a `...` wildcard are elided, so the
+    /// diff concentrates on the lines that could not be matched. The diff approximates `fm`'s
+    /// matching (e.g. it ignores [`fm_options`](#method.fm_options)) and is intended only to
+    /// make failures easier to understand. This has no effect if a custom
+    /// [`matcher`](#method.matcher) is used.
+    ///
+    /// This option defaults to `false`.
+    pub fn fuzzy_diffs(&mut self, fuzzy_diffs: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().fuzzy_diffs = fuzzy_diffs;
+        self
+    }
+
+    /// If set to `true`, when a command's stderr or stdout is checked in more than one way (e.g.
+    /// with both `stdout` and `stdout-regex`), every check is run and the reasons for all those
+    /// which fail are reported. Otherwise, only the first check which fails for each of stderr and
+    /// stdout is reported, which means that fixing one problem can reveal another on the next
+    /// run. Note that a command's exit status, stderr, stdout, and unconsumed stdin are always
+    /// reported if they do not match what was expected.
+    ///
+    /// This option defaults to `false`.
+    pub fn report_all_failures(&mut self, report_all_failures: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().report_all_failures = report_all_failures;
+        self
+    }
+
+    /// If set to `true`, the result of each test is not printed as soon as that test completes:
+    /// instead, once all tests have completed, results are printed sorted by test name (with the
+    /// tests in a directory grouped together). This makes output reproducible across runs at
+    /// the cost of not seeing results as they happen. This has no effect if a custom
+    /// [`reporter`](#method.reporter) is used.
+    ///
+    /// This option defaults to `false`.
+    pub fn ordered_output(&mut self, ordered_output: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().ordered_output = ordered_output;
+        self
+    }
+
+    /// Specify the maximum time the whole test suite is allowed to run for. Once this time has
+    /// passed, no further tests are started, the commands of running tests are killed, and those
+    /// tests, and any which were not started, are reported as having timed out. The test suite is
+    /// considered to have failed if any test timed out. The time taken by
+    /// [`before_all`](#method.before_all) counts towards the timeout, but that taken by
+    /// [`after_all`](#method.after_all) does not.
+    ///
+    /// This option defaults to `None` (i.e. no limit).
+    pub fn suite_timeout(&mut self, suite_timeout: Duration) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().suite_timeout = Some(suite_timeout);
+        self
+    }
+
+    /// Specify how often the user is warned that a command is still running (e.g. "running for
+    /// 60.0 seconds", where the time shown is how long the command has actually been running). A
+    /// zero `Duration` disables such warnings entirely.
+    ///
+    /// This option defaults to 60 seconds.
+    pub fn progress_interval(&mut self, progress_interval: Duration) -> &mut Self {
+        let inner = Arc::get_mut(&mut self.inner).unwrap();
+        inner.progress_interval = if progress_interval.is_zero() {
+            None
+        } else {
+            Some(progress_interval)
+        };
+        self
+    }
+
     /// If set, defines what lines will be treated as comments if, ignoring the current level of
-    /// indentation, they begin with `comment_prefix`.
+    /// indentation, they begin with `comment_prefix`. This replaces any prefixes previously set
+    /// with [`comment_prefixes`](#method.comment_prefixes).
     ///
     /// This option defaults to `None`.
     pub fn comment_prefix<S: AsRef<str>>(&mut self, comment_prefix: S) -> &mut Self {
+        self.comment_prefixes(&[comment_prefix])
+    }
+
+    /// As [`comment_prefix`](#method.comment_prefix), but lines are treated as comments if they
+    /// begin with any of `comment_prefixes` (e.g. `&["#", "//"]`), which is useful if test files
+    /// embed test data in comments of different languages. This replaces any prefixes previously
+    /// set.
+    pub fn comment_prefixes<S: AsRef<str>>(&mut self, comment_prefixes: &[S]) -> &mut Self {
         let inner = Arc::get_mut(&mut self.inner).unwrap();
-        inner.comment_prefix = Some(comment_prefix.as_ref().to_owned());
+        inner.comment_prefixes = comment_prefixes
+            .iter()
+            .map(|x| x.as_ref().to_owned())
+            .collect();
+        self
+    }
+
+    /// If set to `true`, the test data returned by [`test_extract`](#method.test_extract) is parsed
+    /// as YAML rather than in the default format (see the [crate documentation](index.html)).
+    /// [`comment_prefix`](#method.comment_prefix) is ignored, since YAML has its own comments.
+    ///
+    /// This option defaults to `false`.
+    pub fn yaml_tests(&mut self, yaml_tests: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().yaml_tests = yaml_tests;
+        self
+    }
+
+    /// Allow tests to specify the key `name` for a command, in addition to the built-in keys (e.g.
+    /// `status`). Whenever a test specifies `name`, `handler` is called with its value (with
+    /// multi-line values joined by newlines) and the command's
+    /// [`CustomData`](struct.CustomData.html), in which it can record whatever it needs. If
+    /// `handler` returns `Err(msg)`, the test data is considered invalid, and `msg` is reported
+    /// (with the key's line number) as the reason. For example, to allow tests to specify a
+    /// `timeout-category`:
+    ///
+    /// ```rust,ignore
+    /// LangTester::new()
+    ///     ...
+    ///     .custom_key("timeout-category", |val, data| match val {
+    ///         "fast" | "slow" => Ok(data.insert("timeout-category", val)),
+    ///         _ => Err(format!("Unknown timeout category '{}'.", val)),
+    ///     })
+    ///     ...
+    /// ```
+    ///
+    /// The data collected for a test's commands can be obtained with
+    /// [`custom_data`](fn.custom_data.html) from within the functions `lang_tester` calls for that
+    /// test (e.g. [`test_cmds`](#method.test_cmds)). Built-in keys take precedence, so `name`
+    /// should not be the name of a built-in key.
+    pub fn custom_key<F>(&mut self, name: &str, handler: F) -> &mut Self
+    where
+        F: 'static + Fn(&str, &mut CustomData) -> Result<(), String> + RefUnwindSafe + Send + Sync,
+    {
+        Arc::get_mut(&mut self.inner)
+            .unwrap()
+            .custom_keys
+            .insert(name.to_owned(), Box::new(handler));
         self
     }
 
+    /// Allow tests to use the names in `status_aliases` as statuses in `status` and
+    /// `rerun-if-status`, each standing for the status it maps to. This allows tests to refer to
+    /// the exit codes a program documents by name: for example, if `status_aliases` maps
+    /// `type-error` to `Status::Int(65)`, a test can specify `status: type-error`. Names are
+    /// matched case insensitively, and take precedence over the built-in statuses (e.g.
+    /// `success`). Calling this again replaces any previously specified aliases.
+    pub fn status_aliases(&mut self, status_aliases: HashMap<String, Status>) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().status_aliases = status_aliases
+            .into_iter()
+            .map(|(name, status)| (name.trim().to_lowercase(), status))
+            .collect();
+        self
+    }
+
+    /// Parse `test_str` (i.e. test data as returned by [`test_extract`](#method.test_extract)) in
+    /// the same way that this `LangTester` parses the test data of each test file, taking into
+    /// account options such as [`comment_prefix`](#method.comment_prefix),
+    /// [`yaml_tests`](#method.yaml_tests), [`custom_key`](#method.custom_key), and
+    /// [`status_aliases`](#method.status_aliases). This allows
+    /// other tools (e.g. linters for test files) to check test data without running it.
+    pub fn parse_test_data<'a>(&self, test_str: &'a str) -> Result<ParsedTests<'a>, ParseError> {
+        self.inner.parse_tests(test_str).map(ParsedTests::new)
+    }
+
     /// If `test_file_filter` is specified, only files for which it returns `true` will be
     /// considered tests. A common use of this is to filter files based on filename extensions
     /// e.g.:
@@ -184,13 +754,74 @@ impl LangTester {
         self
     }
 
+    /// Only run test files whose `tags` include at least one of `tags`: the rest are counted as
+    /// filtered out. This can be called multiple times, and is in addition to any tags specified
+    /// with `--tag` on the command-line. Note that, to find their tags, every test file has to
+    /// have its test data extracted and parsed before any tests are run. Test files whose test
+    /// data cannot be parsed are run, so that the error is reported.
+    pub fn only_tags(&mut self, tags: &[&str]) -> &mut Self {
+        self.only_tags.extend(tags.iter().map(|x| (*x).to_owned()));
+        self
+    }
+
+    /// Only consider files whose paths match the glob pattern `test_glob` to be tests. A
+    /// convenient alternative to [`test_path_filter`](#method.test_path_filter) for the common
+    /// case of matching file extensions, e.g.:
+    ///
+    /// ```rust,ignore
+    /// LangTester::new()
+    ///     ...
+    ///     .test_glob("**/*.rs")
+    ///     ...
+    /// ```
+    ///
+    /// The pattern is matched against paths relative to [`test_dir`](#method.test_dir) (or, if
+    /// `test_dir` is not set or the path is not within it, against the full path). `*` and `?` do
+    /// not match `/`, while `**` matches any number of directories. If `test_path_filter` is also
+    /// specified, a file must satisfy both. An invalid pattern causes
+    /// [`try_run`](#method.try_run) to return a [`LangTesterError::Config`].
+    pub fn test_glob(&mut self, test_glob: &str) -> &mut Self {
+        self.test_glob = Some(test_glob.to_owned());
+        self
+    }
+
+    /// If set to `true`, each directory searched for tests may contain a `.lang_tester_ignore`
+    /// file listing glob patterns (one per line; blank lines and lines starting with `#` are
+    /// ignored) of paths which are not to be considered tests. A pattern containing a `/` is
+    /// matched against paths relative to the directory containing the `.lang_tester_ignore` file;
+    /// other patterns are matched against the file names of all paths in or below that directory.
+    /// Matching directories are not searched. For example:
+    ///
+    /// ```text
+    /// # Scratch files.
+    /// *.tmp
+    /// fixtures/*.rs
+    /// ```
+    ///
+    /// This is applied before [`test_path_filter`](#method.test_path_filter) and has no effect on
+    /// paths specified with [`test_paths`](#method.test_paths). Defaults to `false`.
+    pub fn ignore_files(&mut self, ignore_files: bool) -> &mut Self {
+        self.ignore_files = ignore_files;
+        self
+    }
+
+    /// If specified, only tests whose names (e.g. `lang_tests::a::b.x`) match the regular
+    /// expression `filter_regex` are run: the rest are counted as filtered out. This is applied in
+    /// addition to any substring filters specified on the command-line. If the regular expression
+    /// is invalid, running tests will fail with a [`LangTesterError`].
+    pub fn filter_regex(&mut self, filter_regex: &str) -> &mut Self {
+        self.filter_regex = Some(filter_regex.to_owned());
+        self
+    }
+
     /// Specify a function which can extract the test data for `lang_tester` from a test file path,
     /// returning it as a `String`. Note that the test data does not have to be extracted from the
     /// `Path` passed to the function -- it can come from any source.
     ///
     /// How the test data is extracted from the test file is entirely up to the user, though a
-    /// common convention is to store the test data in a comment at the beginning of the test file.
-    /// For example, for Rust code one could use a function along the lines of the following:
+    /// common convention is to store the test data in a comment at the beginning of the test file
+    /// (for which [`extract_comment_block`](#method.extract_comment_block) can be used). For
+    /// example, for Rust code one could use a function along the lines of the following:
     ///
     /// ```rust,ignore
     /// LangTester::new()
@@ -212,11 +843,74 @@ impl LangTester {
     pub fn test_extract<F>(&mut self, test_extract: F) -> &mut Self
     where
         F: 'static + Fn(&Path) -> String + RefUnwindSafe + Send + Sync,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().test_extract =
+            Some(Box::new(move |p| Some(test_extract(p))));
+        self
+    }
+
+    /// Extract the test data from the first sequence of consecutive lines in each test file which
+    /// begin with `prefix` (i.e. the first block of line comments in the file), with `prefix`
+    /// removed from each line. Lines before that sequence are skipped. This is a convenient
+    /// alternative to [`test_extract`](#method.test_extract) for the common case of test data
+    /// stored in a comment at the beginning of a test file, e.g. for Rust code:
+    ///
+    /// ```rust,ignore
+    /// LangTester::new()
+    ///     ...
+    ///     .extract_comment_block("//")
+    ///     ...
+    /// ```
+    pub fn extract_comment_block(&mut self, prefix: &str) -> &mut Self {
+        let prefix = prefix.to_owned();
+        self.test_extract(move |p| {
+            fs::read_to_string(p)
+                .unwrap_or_else(|e| panic!("{}: {}", p.display(), e))
+                .lines()
+                // Skip non-commented lines at the start of the file.
+                .skip_while(|l| !l.starts_with(&prefix))
+                // Extract consecutive commented lines.
+                .take_while(|l| l.starts_with(&prefix))
+                .map(|l| &l[prefix.len()..])
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+    }
+
+    /// Specify a function which can extract the test data for `lang_tester` from a test file path,
+    /// as with [`test_extract`](#method.test_extract), except that the function can return `None`
+    /// to indicate that the file is not a test file at all. Such files are silently skipped: unlike
+    /// files whose test data is empty, they are not counted as ignored. Note that such files are
+    /// still counted in the "running <n> tests" message, since they can only be identified once
+    /// testing has started. Only one of `test_extract` and `test_extract_opt` can be used: the
+    /// one specified last takes effect.
+    pub fn test_extract_opt<F>(&mut self, test_extract: F) -> &mut Self
+    where
+        F: 'static + Fn(&Path) -> Option<String> + RefUnwindSafe + Send + Sync,
     {
         Arc::get_mut(&mut self.inner).unwrap().test_extract = Some(Box::new(test_extract));
         self
     }
 
+    /// If set to `true`, the test data returned by [`test_extract`](#method.test_extract) for each
+    /// test file is cached in `<target dir>/lang_tester_extract-<test binary>` (see
+    /// [`use_cmdline_args`](#method.use_cmdline_args) for the meaning of `<target dir>`), and
+    /// `test_extract` is not called again for a file until its modification time or size changes.
+    /// This can noticeably reduce the time taken to start large test suites. Each test binary has
+    /// its own cache, since different binaries may extract test data from the same files in
+    /// different ways. Note that the cache is only invalidated by changes to test files: if
+    /// `test_extract` itself is changed, the cache file should be deleted.
+    ///
+    /// This option defaults to `false`.
+    pub fn cache_test_extract(&mut self, cache_test_extract: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().extract_cache = if cache_test_extract {
+            Some(Mutex::new(HashMap::new()))
+        } else {
+            None
+        };
+        self
+    }
+
     /// Specify a function which sets options for the [`fm`](https://crates.io/crates/fm) library.
     /// `fm` is used for the fuzzy matching in `lang_tester`. This function can be used to override
     /// `fm`'s defaults for a given test file (passed as `Path`) and a given testing stream (stderr
@@ -247,6 +941,190 @@ impl LangTester {
         self
     }
 
+    /// Specify a function which sets options for the [`fm`](https://crates.io/crates/fm) matchers
+    /// used by the `rerun-if-stderr` and `rerun-if-stdout` keys, in the same manner as
+    /// [`fm_options`](#method.fm_options). If this is not specified, `fm_options` (if any) is used
+    /// for these matchers. This allows the condition under which a test is rerun to be made
+    /// stricter (or looser) than the condition under which it passes.
+    pub fn rerun_fm_options<F>(&mut self, rerun_fm_options: F) -> &mut Self
+    where
+        F: 'static
+            + for<'a> Fn(&'a Path, TestStream, FMBuilder<'a>) -> FMBuilder<'a>
+            + RefUnwindSafe
+            + Send
+            + Sync,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().rerun_fm_options = Some(Box::new(rerun_fm_options));
+        self
+    }
+
+    /// If `yes`, [`fm`](https://crates.io/crates/fm) ignores each line's leading whitespace when
+    /// matching `stderr` and `stdout`; otherwise leading whitespace must match. Defaults to
+    /// `fm`'s default (`true`). This is applied before [`fm_options`](#method.fm_options), which
+    /// can override it for individual tests.
+    pub fn ignore_leading_whitespace(&mut self, yes: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner)
+            .unwrap()
+            .ignore_leading_whitespace = Some(yes);
+        self
+    }
+
+    /// If `yes`, [`fm`](https://crates.io/crates/fm) ignores each line's trailing whitespace when
+    /// matching `stderr` and `stdout`; otherwise trailing whitespace must match. Defaults to
+    /// `fm`'s default (`true`). This is applied before [`fm_options`](#method.fm_options), which
+    /// can override it for individual tests.
+    pub fn ignore_trailing_whitespace(&mut self, yes: bool) -> &mut Self {
+        Arc::get_mut(&mut self.inner)
+            .unwrap()
+            .ignore_trailing_whitespace = Some(yes);
+        self
+    }
+
+    /// Specify a function which replaces [`fm`](https://crates.io/crates/fm) when matching a
+    /// command's stderr or stdout against the expected output. The function is passed the test
+    /// file (as `Path`), the testing stream (stderr or stdout, as `TestStream`), the expected
+    /// output (i.e. the `stderr` or `stdout` value from the test data), and the actual output of
+    /// the command. It must return `Ok(())` if the two match, or `Err(msg)` otherwise, where `msg`
+    /// will be shown to the user as the reason for the failure. For example, to compare stdout
+    /// as JSON (ignoring key order) while keeping textual equality for stderr, one could use a
+    /// function along the lines of the following:
+    ///
+    /// ```rust,ignore
+    /// LangTester::new()
+    ///    ...
+    ///    .matcher(|_, stream, expected, actual| match stream {
+    ///        TestStream::Stdout => {
+    ///            let expected = serde_json::from_str::<Value>(expected).unwrap();
+    ///            match serde_json::from_str::<Value>(actual) {
+    ///                Ok(actual) if actual == expected => Ok(()),
+    ///                _ => Err(format!("Expected JSON:\n{}\nActual:\n{}", expected, actual)),
+    ///            }
+    ///        }
+    ///        TestStream::Stderr if expected == actual => Ok(()),
+    ///        TestStream::Stderr => Err(format!("Expected:\n{}\nActual:\n{}", expected, actual)),
+    ///    })
+    /// ```
+    ///
+    /// The function is only called for the streams whose output a test specifies: if a test does
+    /// not specify `stderr` (or `stdout`), or specifies only `...`, that stream matches anything.
+    /// If the function panics, the test fails, with "matcher panicked" given as the reason. Note
+    /// that the function is not used for `rerun-if-stderr` or `rerun-if-stdout`, which always use
+    /// `fm`.
+    pub fn matcher<F>(&mut self, matcher: F) -> &mut Self
+    where
+        F: 'static
+            + Fn(&Path, TestStream, &str, &str) -> Result<(), String>
+            + RefUnwindSafe
+            + Send
+            + Sync,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().matcher = Some(Box::new(matcher));
+        self
+    }
+
+    /// Specify a function which is called once, before any tests are run. This can be used to set
+    /// up fixtures shared by all tests (e.g. starting a server). Note that if no tests are run
+    /// (e.g. because `test_path_filter` panicked), this function is not called.
+    pub fn before_all<F>(&mut self, before_all: F) -> &mut Self
+    where
+        F: 'static + FnOnce(),
+    {
+        self.before_all = Some(Box::new(before_all));
+        self
+    }
+
+    /// Specify a function which is called once, after all tests have been run (whether they
+    /// succeeded or failed). This can be used to tear down fixtures set up by
+    /// [`before_all`](#method.before_all).
+    pub fn after_all<F>(&mut self, after_all: F) -> &mut Self
+    where
+        F: 'static + FnOnce(),
+    {
+        self.after_all = Some(Box::new(after_all));
+        self
+    }
+
+    /// Specify a function which is called with a test file's `Path` before that test's commands
+    /// are run. This can be used to set up per-test fixtures (e.g. a scratch directory). Note
+    /// that this function is called from multiple threads simultaneously. If it panics, the test
+    /// is considered to have failed and its commands are not run.
+    pub fn before_each<F>(&mut self, before_each: F) -> &mut Self
+    where
+        F: 'static + Fn(&Path) + RefUnwindSafe + Send + Sync,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().before_each = Some(Box::new(before_each));
+        self
+    }
+
+    /// Specify a function which is called with a test file's `Path` after that test's commands
+    /// have been run, whether the test succeeded or failed. This can be used to tear down
+    /// fixtures set up by [`before_each`](#method.before_each). Note that this function is called
+    /// from multiple threads simultaneously. If it panics, the test is considered to have failed.
+    pub fn after_each<F>(&mut self, after_each: F) -> &mut Self
+    where
+        F: 'static + Fn(&Path) + RefUnwindSafe + Send + Sync,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().after_each = Some(Box::new(after_each));
+        self
+    }
+
+    /// Specify a function which is called with a test's name and its
+    /// [`TestOutcome`](enum.TestOutcome.html) as soon as that test has completed (whether it
+    /// passed, failed, or was ignored). This can be used to, for example, drive a progress bar
+    /// without replacing the whole [`Reporter`](trait.Reporter.html). Note that this function is
+    /// called from multiple threads simultaneously.
+    pub fn on_complete<F>(&mut self, on_complete: F) -> &mut Self
+    where
+        F: 'static + Fn(&str, &TestOutcome) + RefUnwindSafe + Send + Sync,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().on_complete = Some(Box::new(on_complete));
+        self
+    }
+
+    /// Specify a function which is called before each of a test's commands other than the first,
+    /// allowing the command to be adjusted based on what the previous command did. The function is
+    /// passed the test file's path, the (lower-cased) name of the command about to be run, the
+    /// [`Command`] itself (to which the test's `args` and `env-var`s have already been applied),
+    /// and the exit status, `stderr`, and `stdout` of the previous command. For example, a
+    /// run-time command can be given an argument that a compiler printed on `stdout`. Note that
+    /// this function is called from multiple threads simultaneously. If it panics, the test is
+    /// considered to have failed.
+    pub fn between_cmds<F>(&mut self, between_cmds: F) -> &mut Self
+    where
+        F: 'static
+            + Fn(&Path, &str, &mut Command, &ExitStatus, &str, &str)
+            + RefUnwindSafe
+            + Send
+            + Sync,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().between_cmds = Some(Box::new(between_cmds));
+        self
+    }
+
+    /// Specify the status which a command is expected to exit with when a test does not specify a
+    /// `status` for it (including when a test does not mention that command at all). For
+    /// example, if every test in a directory is expected to fail, setting this to
+    /// [`Status::Error`](enum.Status.html#variant.Error) avoids each test having to specify
+    /// `status: error`.
+    ///
+    /// This option defaults to [`Status::Success`](enum.Status.html#variant.Success).
+    pub fn default_status(&mut self, default_status: Status) -> &mut Self {
+        Arc::get_mut(&mut self.inner).unwrap().default_status = default_status;
+        self
+    }
+
+    /// Specify the `stdin` passed to the command `cmd_name` (as returned by
+    /// [`test_cmds`](#method.test_cmds)) when a test does not specify a `stdin` for that command
+    /// (including when a test does not mention that command at all). This can be called multiple
+    /// times for different commands.
+    pub fn default_stdin(&mut self, cmd_name: &str, stdin: String) -> &mut Self {
+        Arc::get_mut(&mut self.inner)
+            .unwrap()
+            .default_stdin
+            .insert(cmd_name.to_lowercase(), stdin);
+        self
+    }
+
     /// Specify a function which takes a `Path` to a test file and returns a vector containing 1 or
     /// more (`name`, <[`Command`](https://doc.rust-lang.org/std/process/struct.Command.html)>)
     /// pairs. The commands will be executed in order on the test file: for each executed command,
@@ -285,23 +1163,83 @@ impl LangTester {
     ///     Error at line 10
     ///     ...
     /// ```
+    ///
+    /// Each command is run in its own process group. If `lang_tester` needs to terminate a
+    /// command (e.g. because `lang_tester` itself was interrupted with Ctrl-C), the whole process
+    /// group is killed, so that any processes the command has itself spawned are killed too.
     pub fn test_cmds<F>(&mut self, test_cmds: F) -> &mut Self
     where
         F: 'static + Fn(&Path) -> Vec<(&str, Command)> + RefUnwindSafe + Send + Sync,
     {
-        Arc::get_mut(&mut self.inner).unwrap().test_cmds = Some(Box::new(test_cmds));
+        Arc::get_mut(&mut self.inner).unwrap().test_cmds = Some(Box::new(move |p| {
+            test_cmds(p)
+                .into_iter()
+                .map(|(name, cmd)| (name, Box::new(move || cmd) as LazyCommand))
+                .collect()
+        }));
         self
     }
 
-    /// If set to `true`, this reads arguments from `std::env::args()` and interprets them in the
-    /// same way as normal cargo test files. For example if you have tests "ab" and "cd" but only
-    /// want to run the latter:
+    /// As [`test_cmds`](#method.test_cmds), except that each command is returned as a closure
+    /// which constructs the [`Command`] only when that command is about to be run. A command
+    /// which is never reached (e.g. because an earlier command failed) is thus never constructed.
+    /// This is useful if constructing a command is expensive, or if it depends on the results of
+    /// earlier commands (e.g. files they created). If a closure panics, the test fails.
+    pub fn test_cmds_lazy<F>(&mut self, test_cmds: F) -> &mut Self
+    where
+        F: 'static + Fn(&Path) -> Vec<(&str, LazyCommand)> + RefUnwindSafe + Send + Sync,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().test_cmds = Some(Box::new(test_cmds));
+        self
+    }
+
+    /// If set to `true`, this reads arguments from `std::env::args()` and interprets them in the
+    /// same way as normal cargo test files. For example if you have tests "ab" and "cd" but only
+    /// want to run the latter:
     ///
     /// ```sh
     /// $ <test bin> c
     /// ```
     ///
     /// As this suggests, a simple substring search is used to decide which tests to run.
+    /// Conversely, `--skip <filter>` (which can be specified multiple times) excludes any test
+    /// whose name contains `<filter>`. `--filter-regex <regex>` runs only tests whose names match
+    /// `<regex>` (see [`filter_regex`](#method.filter_regex)). `--tag <tag>` (which can be
+    /// specified multiple times) runs only test files tagged with `<tag>` (see
+    /// [`only_tags`](#method.only_tags)).
+    ///
+    /// Passing `--nocapture` causes the stderr/stdout of commands to be passed through to the
+    /// terminal. Since the output of tests running in parallel would interleave unreadably,
+    /// `--nocapture` implies `--test-threads=1` unless the number of test threads is explicitly
+    /// specified.
+    ///
+    /// As with `cargo test`, the number of test threads can be specified with either
+    /// `--test-threads=<n>` or the environment variable `RUST_TEST_THREADS` (the former takes
+    /// precedence). Either overrides the value set with [`test_threads`](#method.test_threads).
+    /// `--test-threads=0` uses all available CPUs.
+    ///
+    /// Passing `--failed` runs only the tests which failed the last time they were run (or, if none
+    /// did, all tests). The paths of failing tests are recorded in
+    /// `<target dir>/lang_tester_failed`, where `<target dir>` is `CARGO_TARGET_DIR` if it is set
+    /// or `<CARGO_MANIFEST_DIR>/target` otherwise.
+    ///
+    /// Passing `--check` is equivalent to calling [`validate_only(true)`](#method.validate_only).
+    ///
+    /// Passing `--dry-run` prints the commands that each test would run (including their working
+    /// directory and environment variable overrides) to stderr rather than running them. Each such
+    /// test is then reported as ignored. Passing `--verbose` prints each command (in the same
+    /// format) just before it is run, which, with `--nocapture`, makes clear which command produced
+    /// which output.
+    ///
+    /// Passing `--format terse` prints a single character for each test (`.` if it passed, `b` if
+    /// it passed and was measured, `F` if it failed, `T` if it timed out, `i` if it was ignored,
+    /// and `u` if it is unsupported on this platform) rather than a line per test, which is
+    /// useful for large test suites. Details of failures are still printed at the end.
+    ///
+    /// Passing `--color <auto|always|never>` controls whether output is coloured. `auto` uses
+    /// colour only if stderr is a terminal. By default, output is coloured unless the `NO_COLOR`
+    /// environment variable is set to a non-empty value (though setting `CLICOLOR_FORCE` to a
+    /// value other than `0` forces colour).
     ///
     /// You can get help on `lang_tester`'s options:
     ///
@@ -315,17 +1253,70 @@ impl LangTester {
         self
     }
 
+    /// Specify a [`Reporter`](trait.Reporter.html) to which progress and results are reported.
+    /// By default, results are printed to `stderr` in the same format as `cargo test`.
+    pub fn reporter<R>(&mut self, reporter: R) -> &mut Self
+    where
+        R: 'static + Reporter,
+    {
+        Arc::get_mut(&mut self.inner).unwrap().reporter = Some(Box::new(reporter));
+        self
+    }
+
+    /// If set to `true`, the test data of every test file is extracted and parsed, and the command
+    /// names it references checked against those returned by [`test_cmds`](#method.test_cmds),
+    /// but no commands are run. Every problem found (e.g. a syntax error in a test file, with its
+    /// line number) is reported, rather than just the first: even within a single test file,
+    /// parsing continues past errors so that all of them can be fixed in one go (though only the
+    /// first error in YAML test data is reported). This gives fast feedback when writing
+    /// many tests.
+    ///
+    /// This option defaults to `false`.
+    pub fn validate_only(&mut self, validate_only: bool) -> &mut Self {
+        self.validate_only = validate_only;
+        self
+    }
+
     /// Make sure the user has specified the minimum set of things we need from them.
-    fn validate(&self) {
-        if self.inner.test_dir.is_none() {
-            fatal("test_dir must be specified.");
+    fn validate(&self) -> Result<(), LangTesterError> {
+        if let Some(ref e) = self.test_dir_error {
+            return Err(LangTesterError::Config(e.clone()));
+        }
+        if self.inner.test_dir.is_none() && self.test_paths.is_none() {
+            return Err(LangTesterError::Config(
+                "test_dir or test_paths must be specified.".to_owned(),
+            ));
         }
         if self.inner.test_extract.is_none() {
-            fatal("test_extract must be specified.");
+            return Err(LangTesterError::Config(
+                "test_extract must be specified.".to_owned(),
+            ));
         }
         if self.inner.test_cmds.is_none() {
-            fatal("test_cmds must be specified.");
+            return Err(LangTesterError::Config(
+                "test_cmds must be specified.".to_owned(),
+            ));
+        }
+        // Since no tests have yet run, no permits have been acquired, so the number available is
+        // the number the user specified.
+        if let Some(ref max_processes) = self.inner.max_processes {
+            if *max_processes.available.lock().unwrap() == 0 {
+                return Err(LangTesterError::Config(
+                    "max_processes must be more than 0.".to_owned(),
+                ));
+            }
+        }
+        if self.inner.command_wrapper.as_ref().map(|x| x.is_empty()) == Some(true) {
+            return Err(LangTesterError::Config(
+                "command_wrapper must contain at least a program name.".to_owned(),
+            ));
+        }
+        if self.inner.shell.as_ref().map(|x| x.is_empty()) == Some(true) {
+            return Err(LangTesterError::Config(
+                "shell must contain at least a program name.".to_owned(),
+            ));
         }
+        Ok(())
     }
 
     /// Enumerate all the test files we need to check, along with the number of files filtered out
@@ -334,42 +1325,121 @@ impl LangTester {
     /// canonicalised.
     fn test_files(
         &self,
-        failures: Arc<Mutex<Vec<(String, TestFailure)>>>,
-    ) -> (Vec<PathBuf>, usize) {
+        outcomes: Arc<Mutex<Vec<(String, TestOutcome)>>>,
+    ) -> Result<(Vec<PathBuf>, usize), LangTesterError> {
         let mut num_filtered = 0;
-        let paths = WalkDir::new(self.inner.test_dir.as_ref().unwrap())
+        let filter_regex = self
+            .filter_regex
+            .as_ref()
+            .map(|x| {
+                Regex::new(x).map_err(|e| {
+                    LangTesterError::Config(format!("Invalid filter regular expression:\n  {}", e))
+                })
+            })
+            .transpose()?;
+        let test_glob = self
+            .test_glob
+            .as_ref()
+            .map(|x| {
+                compile_glob(x).map_err(|e| {
+                    LangTesterError::Config(format!("Invalid test glob pattern:\n  {}", e))
+                })
+            })
+            .transpose()?;
+        // `test_dir` is canonicalized when it is set and, since `WalkDir` does not follow symbolic
+        // links, the paths it produces by joining onto `test_dir` are already canonical unless
+        // they are themselves symbolic links. Canonicalizing every path would require several
+        // system calls per file, which is noticeable for large test suites. Entries which can't
+        // be read, or symbolic links which can't be resolved (e.g. because they are dangling),
+        // are skipped with a warning, whereas explicitly specified test paths must exist.
+        let warn = |msg: String| self.inner.reporter().warning(None, &msg);
+        let mut ignore_files = IgnoreFiles::default();
+        let candidates: Box<dyn Iterator<Item = Result<PathBuf, LangTesterError>>> =
+            match self.test_paths {
+                Some(ref test_paths) => Box::new(test_paths.iter().map(|x| {
+                    canonicalize(x).map_err(|err| LangTesterError::Io {
+                        path: x.clone(),
+                        err,
+                    })
+                })),
+                None => Box::new(
+                    WalkDir::new(self.inner.test_dir.as_ref().unwrap())
+                        .into_iter()
+                        .filter_entry(|x| {
+                            !self.ignore_files
+                                || x.depth() == 0
+                                || !ignore_files
+                                    .is_ignored(self.inner.test_dir.as_ref().unwrap(), x.path())
+                        })
+                        .filter_map(|x| match x {
+                            Ok(x) => Some(x),
+                            Err(e) => {
+                                warn(format!("skipping unreadable entry: {}", e));
+                                None
+                            }
+                        })
+                        .filter_map(|x| {
+                            if !x.path_is_symlink() {
+                                return Some(Ok(x.into_path()));
+                            }
+                            match canonicalize(x.path()) {
+                                Ok(p) => Some(Ok(p)),
+                                Err(e) => {
+                                    warn(format!("skipping {}: {}", x.path().display(), e));
+                                    None
+                                }
+                            }
+                        }),
+                ),
+            };
+        let candidates = candidates.collect::<Result<Vec<_>, _>>()?;
+        if let Some(e) = ignore_files.error {
+            return Err(e);
+        }
+        let paths = candidates
             .into_iter()
-            .filter_map(|x| x.ok())
-            .map(|x| canonicalize(x.into_path()).unwrap())
             // Filter out non-test files
+            .filter(|x| match test_glob {
+                Some(ref m) => {
+                    let rel = self
+                        .inner
+                        .test_dir
+                        .as_ref()
+                        .and_then(|d| x.strip_prefix(d).ok())
+                        .unwrap_or(x);
+                    x.is_file() && m.is_match(rel)
+                }
+                None => true,
+            })
             .filter(|x| match self.test_path_filter.as_ref() {
                 Some(f) => match catch_unwind(|| f(x)) {
                     Ok(b) => b,
                     Err(_) => {
-                        let failure = TestFailure {
-                            status: None,
-                            stdin_remaining: 0,
-                            stderr: None,
-                            stderr_match: None,
-                            stdout: None,
-                            stdout_match: None,
-                        };
-                        failures
+                        let outcome =
+                            TestOutcome::Failed(TestFailure::from_panic("test_path_filter"));
+                        outcomes
                             .lock()
                             .unwrap()
-                            .push((x.to_str().unwrap().to_owned(), failure));
+                            .push((x.to_str().unwrap().to_owned(), outcome));
                         false
                     }
                 },
                 None => true,
             })
             // If the user has named one or more tests on the command-line, run only those,
-            // filtering out the rest (counting them as ignored).
+            // filtering out the rest (counting them as ignored). Tests the user has asked to skip
+            // are filtered out in the same way.
             .filter(|x| {
                 let test_fname = format!(
                     "lang_tests::{}",
-                    test_fname(self.inner.test_dir.as_deref().unwrap(), x.as_path(),)
+                    test_fname(self.inner.test_dir.as_deref(), x.as_path())
                 );
+                if self.cmdline_skips.iter().any(|x| test_fname.contains(x))
+                    || filter_regex.as_ref().map(|x| !x.is_match(&test_fname)) == Some(true)
+                {
+                    num_filtered += 1;
+                    return false;
+                }
                 match self.cmdline_filters.as_ref() {
                     Some(fs) => {
                         debug_assert!(self.use_cmdline_args);
@@ -384,137 +1454,588 @@ impl LangTester {
                     None => true,
                 }
             })
-            .collect();
-        (paths, num_filtered)
+            .collect::<Vec<_>>();
+        let paths = if self.only_tags.is_empty() {
+            paths
+        } else {
+            let (paths, filtered) = paths.into_iter().partition::<Vec<_>, _>(|x| {
+                match self.inner.extract_test_str(x) {
+                    Ok(Some(test_str)) => match self.inner.parse_tests(&test_str) {
+                        Ok(tests) => self.only_tags.iter().any(|t| tests.tags.contains(t)),
+                        Err(_) => true,
+                    },
+                    // Files which aren't tests are skipped later; panics are reported later.
+                    Ok(None) | Err(_) => true,
+                }
+            });
+            num_filtered += filtered.len();
+            paths
+        };
+        let paths = if self.failed_only {
+            // If none of the tests we would otherwise have run failed last time, we run them all,
+            // since the user is presumably happy that previous failures have been fixed.
+            let failed = failed_cache_path()
+                .map(|x| read_failed_cache(&x))
+                .unwrap_or_default();
+            if paths.iter().any(|x| failed.contains(x)) {
+                let (paths, filtered) = paths
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|x| failed.contains(x));
+                num_filtered += filtered.len();
+                paths
+            } else {
+                paths
+            }
+        } else {
+            paths
+        };
+        check_duplicate_names(self.inner.test_dir.as_deref(), &paths)?;
+        Ok((paths, num_filtered))
     }
 
-    /// Run all the lang tests.
+    /// Run all the lang tests, printing the results to `stderr`, and exiting the process if any
+    /// test fails or a [`LangTesterError`] occurs. The process exits with
+    /// [`EXIT_TESTS_FAILED`](crate::EXIT_TESTS_FAILED) if tests failed or timed out, and with
+    /// [`EXIT_FATAL`](crate::EXIT_FATAL) if invalid command-line arguments were given (in which
+    /// case a usage message is printed) or a
+    /// [`LangTesterError`] occurred.
+    ///
+    /// Since this takes over the whole process, it also installs a handler which, if `SIGINT`
+    /// (e.g. Ctrl-C) or `SIGTERM` is received, kills any running commands (which do not otherwise
+    /// receive these signals) before exiting. [`run_with_summary`](#method.run_with_summary) and
+    /// [`try_run`](#method.try_run) do not install such a handler, leaving the signal handling of
+    /// programs which embed `lang_tester` unchanged: such programs should call
+    /// [`kill_child_process_groups`](fn.kill_child_process_groups.html) when they are interrupted.
     pub fn run(&mut self) {
-        self.validate();
+        if let Err(e) = install_signal_handler() {
+            fatal(&e.to_string());
+        }
+        let summary = self.run_with_summary();
+        if summary.failed() > 0 || summary.timed_out() > 0 {
+            process::exit(EXIT_TESTS_FAILED);
+        }
+    }
+
+    /// Run all the lang tests, printing the results to `stderr`, and returning a
+    /// [`Summary`](struct.Summary.html) of them. Unlike [`run`](#method.run), this does not exit
+    /// the process if a test fails (though it does if a [`LangTesterError`] occurs). This is
+    /// useful if, for example, several `LangTester`s are run in turn and their results combined.
+    ///
+    /// Each command is run in its own process group, so it does not receive signals (e.g. from
+    /// Ctrl-C) sent to the caller's process group. Unlike [`run`](#method.run), this does not
+    /// install a signal handler: if the caller can be interrupted, it should call
+    /// [`kill_child_process_groups`](fn.kill_child_process_groups.html) before exiting, or running
+    /// commands (and any processes they have spawned) will be left behind.
+    pub fn run_with_summary(&mut self) -> Summary {
+        match self.try_run() {
+            Ok(summary) => summary,
+            Err(LangTesterError::Usage(msg)) => {
+                eprintln!("{}", msg);
+                process::exit(EXIT_FATAL);
+            }
+            Err(e) => fatal(&e.to_string()),
+        }
+    }
+
+    /// Run all the lang tests, reporting the results (by default, to `stderr`) and returning a
+    /// [`Summary`](struct.Summary.html) of them, including the [`TestOutcome`] of each test which
+    /// was run. If an error prevents tests
+    /// from being run (e.g. a test file's test data cannot be parsed, or a command cannot be
+    /// spawned), no further tests are started, those already running are allowed to complete, and
+    /// the error is returned.
+    ///
+    /// As with [`run_with_summary`](#method.run_with_summary), no signal handler is installed, so
+    /// callers which can be interrupted should call
+    /// [`kill_child_process_groups`](fn.kill_child_process_groups.html) before exiting.
+    ///
+    /// If command-line arguments are used (see [`use_cmdline_args`](#method.use_cmdline_args)) and
+    /// are invalid, or `--help` is passed, [`LangTesterError::Usage`] is returned without any tests
+    /// being run.
+    pub fn try_run(&mut self) -> Result<Summary, LangTesterError> {
+        self.validate()?;
         if self.use_cmdline_args {
             let args: Vec<String> = env::args().collect();
             let matches = Options::new()
                 .optflag("h", "help", "")
                 .optflag("", "ignored", "Run only ignored tests")
+                .optflag("", "include-ignored", "Run ignored and not ignored tests")
+                .optflag(
+                    "",
+                    "failed",
+                    "Run only the tests which failed last time (or all tests if none did)",
+                )
+                .optflag(
+                    "",
+                    "check",
+                    "Check that test files are valid without running any tests",
+                )
+                .optflag(
+                    "",
+                    "dry-run",
+                    "Print the commands each test would run without running them",
+                )
+                .optflag("", "verbose", "Print each command before running it")
                 .optflag(
                     "",
                     "nocapture",
-                    "Pass command stderr/stdout through to the terminal",
+                    "Pass command stderr/stdout through to the terminal (implies \
+                     --test-threads=1 unless --test-threads or RUST_TEST_THREADS is \
+                     specified)",
+                )
+                .optmulti(
+                    "",
+                    "skip",
+                    "Skip tests whose names contain FILTER (this flag can be used multiple times)",
+                    "FILTER",
+                )
+                .optmulti(
+                    "",
+                    "tag",
+                    "Run only test files tagged with TAG (this flag can be used multiple times)",
+                    "TAG",
+                )
+                .optopt(
+                    "",
+                    "color",
+                    "Configure coloured output: auto (colour if stderr is a terminal), always, or \
+                     never",
+                    "auto|always|never",
+                )
+                .optopt(
+                    "",
+                    "format",
+                    "Configure the output format: pretty (one line per test) or terse (one \
+                     character per test)",
+                    "pretty|terse",
+                )
+                .optopt(
+                    "",
+                    "filter-regex",
+                    "Run only tests whose names match the regular expression REGEX",
+                    "REGEX",
                 )
                 .optopt(
                     "",
                     "test-threads",
-                    "Number of threads used for running tests in parallel",
+                    "Number of threads used for running tests in parallel (0 uses all CPUs)",
                     "n_threads",
                 )
                 .parse(&args[1..])
-                .unwrap_or_else(|_| usage());
+                .map_err(|e| usage_error(Some(&e.to_string())))?;
             if matches.opt_present("h") {
-                usage();
+                return Err(usage_error(None));
             }
             if matches.opt_present("ignored") {
                 Arc::get_mut(&mut self.inner).unwrap().ignored = true;
             }
+            if matches.opt_present("include-ignored") {
+                Arc::get_mut(&mut self.inner).unwrap().include_ignored = true;
+            }
+            if matches.opt_present("check") {
+                self.validate_only = true;
+            }
+            if matches.opt_present("failed") {
+                self.failed_only = true;
+            }
+            if matches.opt_present("dry-run") {
+                Arc::get_mut(&mut self.inner).unwrap().dry_run = true;
+            }
+            if matches.opt_present("verbose") {
+                Arc::get_mut(&mut self.inner).unwrap().verbose = true;
+            }
+            // As with `cargo test`, `RUST_TEST_THREADS` sets the number of test threads, though
+            // `--test-threads` takes precedence.
+            let env_test_threads = match env::var("RUST_TEST_THREADS") {
+                Ok(s) => match s.parse::<usize>() {
+                    Ok(x) if x > 0 => Some(x),
+                    _ => {
+                        return Err(LangTesterError::Config(format!(
+                            "RUST_TEST_THREADS is '{}', which is not a positive integer.",
+                            s
+                        )))
+                    }
+                },
+                Err(_) => None,
+            };
+            if let Some(test_threads) = env_test_threads {
+                Arc::get_mut(&mut self.inner).unwrap().test_threads = test_threads;
+            }
             if matches.opt_present("nocapture") {
-                Arc::get_mut(&mut self.inner).unwrap().nocapture = true;
+                let inner = Arc::get_mut(&mut self.inner).unwrap();
+                inner.nocapture = true;
+                // If multiple tests run in parallel, their stderr/stdout interleave in ways that
+                // make the output unreadable, so unless the user explicitly asks otherwise, we
+                // run one test at a time.
+                if !matches.opt_present("test-threads") && env_test_threads.is_none() {
+                    inner.test_threads = 1;
+                }
             }
             if let Some(s) = matches.opt_str("test-threads") {
-                let test_threads = s.parse::<usize>().unwrap_or_else(|_| usage());
-                if test_threads == 0 {
-                    fatal("Must specify more than 0 threads.");
-                }
-                Arc::get_mut(&mut self.inner).unwrap().test_threads = test_threads;
+                // As with `test_threads`, 0 means "use all available CPUs".
+                let test_threads = s.parse::<usize>().map_err(|_| {
+                    usage_error(Some(&format!("Invalid --test-threads value '{}'.", s)))
+                })?;
+                self.test_threads(test_threads);
+            }
+            if let Some(s) = matches.opt_str("color") {
+                Arc::get_mut(&mut self.inner).unwrap().colour_choice = match s.as_str() {
+                    "auto" if io::stderr().is_terminal() => ColorChoice::Auto,
+                    "auto" | "never" => ColorChoice::Never,
+                    "always" => ColorChoice::Always,
+                    _ => {
+                        return Err(usage_error(Some(&format!(
+                            "Invalid --color value '{}'.",
+                            s
+                        ))))
+                    }
+                };
+            }
+            if let Some(s) = matches.opt_str("format") {
+                Arc::get_mut(&mut self.inner).unwrap().output_format = match s.as_str() {
+                    "pretty" => OutputFormat::Pretty,
+                    "terse" => OutputFormat::Terse,
+                    _ => {
+                        return Err(usage_error(Some(&format!(
+                            "Invalid --format value '{}'.",
+                            s
+                        ))))
+                    }
+                };
+            }
+            self.cmdline_skips = matches.opt_strs("skip");
+            self.only_tags.extend(matches.opt_strs("tag"));
+            if let Some(s) = matches.opt_str("filter-regex") {
+                self.filter_regex = Some(s);
             }
             if !matches.free.is_empty() {
                 self.cmdline_filters = Some(matches.free);
             }
         }
-        let failures = Arc::new(Mutex::new(Vec::new()));
-        let (test_files, num_filtered) = self.test_files(Arc::clone(&failures));
-        let test_files_len = test_files.len();
-        let num_ignored = if failures.lock().unwrap().is_empty() {
-            eprint!("\nrunning {} tests", test_files.len());
-            test_file(test_files, Arc::clone(&self.inner), Arc::clone(&failures))
-        } else {
-            0
-        };
+        let extract_cache_path = extract_cache_path();
+        if let (Some(cache), Some(cache_path)) = (
+            Arc::get_mut(&mut self.inner)
+                .unwrap()
+                .extract_cache
+                .as_mut(),
+            extract_cache_path.as_ref(),
+        ) {
+            *cache.get_mut().unwrap() = read_extract_cache(cache_path);
+        }
+        let inner = Arc::get_mut(&mut self.inner).unwrap();
+        if inner.reporter.is_none() {
+            inner.reporter = Some(Box::new(ConsoleReporter::new(
+                inner.test_threads,
+                inner.colour_choice,
+                inner.output_format,
+                inner.ordered_output,
+                inner.max_report_bytes,
+            )));
+        }
+        if !self.validate_only {
+            // Searching for tests in a large directory tree can take a noticeable amount of time,
+            // so give the user some indication that we haven't hung.
+            self.inner.reporter().discovering();
+        }
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let (test_files, num_filtered) = self.test_files(Arc::clone(&outcomes))?;
+        if self.validate_only {
+            let mut outcomes = Mutex::into_inner(Arc::try_unwrap(outcomes).unwrap()).unwrap();
+            outcomes.extend(self.validate_test_files(&test_files));
+            self.inner
+                .write_extract_cache(extract_cache_path.as_deref());
+            outcomes.sort_by_key(|x| x.0.to_lowercase());
+            let passed = outcomes
+                .iter()
+                .filter(|(_, x)| matches!(x, TestOutcome::Passed { .. }))
+                .count();
+            return Ok(Summary {
+                passed,
+                ignored: 0,
+                filtered: num_filtered,
+                outcomes,
+            });
+        }
+        let inner = Arc::get_mut(&mut self.inner).unwrap();
+        inner.suite_deadline = inner.suite_timeout.map(|x| Instant::now() + x);
+        if outcomes.lock().unwrap().is_empty() {
+            if let Some(before_all) = self.before_all.take() {
+                before_all();
+            }
+            self.inner.reporter().started(test_files.len());
+            let res = test_file(
+                test_files.clone(),
+                Arc::clone(&self.inner),
+                Arc::clone(&outcomes),
+            );
+            if let Some(after_all) = self.after_all.take() {
+                after_all();
+            }
+            res?;
+            self.inner
+                .write_extract_cache(extract_cache_path.as_deref());
+            if let Some(cache_path) = failed_cache_path() {
+                let outcomes = outcomes.lock().unwrap();
+                let failed = outcomes
+                    .iter()
+                    .filter(|(_, x)| matches!(x, TestOutcome::Failed(_) | TestOutcome::TimedOut))
+                    .map(|(test_fname, _)| test_fname)
+                    .collect::<HashSet<_>>();
+                let failed = test_files
+                    .iter()
+                    .filter(|x| failed.contains(&test_fname(self.inner.test_dir.as_deref(), x)))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                write_failed_cache(&cache_path, &test_files, &failed);
+            }
+        }
 
-        let mut failures = Mutex::into_inner(Arc::try_unwrap(failures).unwrap()).unwrap();
-        failures.sort_by_key(|x| x.0.to_lowercase());
-        self.pp_failures(
-            &failures,
-            max(test_files_len, failures.len()),
-            num_ignored,
-            num_filtered,
+        let mut outcomes = Mutex::into_inner(Arc::try_unwrap(outcomes).unwrap()).unwrap();
+        outcomes.sort_by_key(|x| x.0.to_lowercase());
+        // Every test which was run has an outcome, so counting outcomes (rather than, say,
+        // subtracting failures from the number of test files) means that the passed, failed, timed
+        // out, and ignored tests (plus those filtered out) always add up to the number of tests
+        // found, even if some tests were never run.
+        let num_passed = outcomes
+            .iter()
+            .filter(|(_, x)| matches!(x, TestOutcome::Passed { .. }))
+            .count();
+        let num_failed = outcomes
+            .iter()
+            .filter(|(_, x)| matches!(x, TestOutcome::Failed(_) | TestOutcome::TimedOut))
+            .count();
+        let num_ignored = outcomes
+            .iter()
+            .filter(|(_, x)| matches!(x, TestOutcome::Ignored(_)))
+            .count();
+        let num_unsupported = outcomes
+            .iter()
+            .filter(|(_, x)| matches!(x, TestOutcome::Unsupported(_)))
+            .count();
+        let num_measured = outcomes
+            .iter()
+            .filter(|(_, x)| matches!(x, TestOutcome::Measured { .. }))
+            .count();
+        debug_assert_eq!(
+            num_passed + num_failed + num_ignored + num_unsupported + num_measured,
+            outcomes.len()
         );
+        let summary = Summary {
+            passed: num_passed,
+            ignored: num_ignored,
+            filtered: num_filtered,
+            outcomes,
+        };
+        self.inner.reporter().finished(&summary);
+
+        Ok(summary)
+    }
 
-        if !failures.is_empty() {
-            process::exit(1);
+    /// Check that each file in `test_files` contains valid test data, reporting every problem found.
+    /// Each valid file is recorded as having passed, and each invalid file as having failed.
+    fn validate_test_files(&self, test_files: &[PathBuf]) -> Vec<(String, TestOutcome)> {
+        let reporter = self.inner.reporter();
+        reporter.validation_started(test_files.len());
+        // Test files are validated in parallel, but their results are reported in order, so that
+        // the output is the same from one run to the next.
+        let results = Arc::new(Mutex::new(
+            (0..test_files.len()).map(|_| None).collect::<Vec<_>>(),
+        ));
+        let pool = ThreadPool::new(self.inner.test_threads);
+        for (i, p) in test_files.iter().enumerate() {
+            let inner = Arc::clone(&self.inner);
+            let results = Arc::clone(&results);
+            let p = p.clone();
+            pool.execute(move || {
+                let res = inner.validate_test_file(&p);
+                results.lock().unwrap()[i] = Some(res);
+            });
+        }
+        pool.join();
+        let results = std::mem::take(&mut *results.lock().unwrap());
+
+        let mut outcomes = Vec::with_capacity(test_files.len());
+        let mut num_valid = 0;
+        let mut num_invalid = 0;
+        for (p, res) in test_files.iter().zip(results) {
+            let (errors, warnings) = match res.unwrap() {
+                Some(x) => x,
+                None => continue,
+            };
+            let test_fname = test_fname(self.inner.test_dir.as_deref(), p);
+            for w in warnings {
+                reporter.warning(None, &w);
+            }
+            if errors.is_empty() {
+                num_valid += 1;
+                outcomes.push((test_fname, TestOutcome::Passed { reruns: 0 }));
+            } else {
+                num_invalid += 1;
+                reporter.test_invalid(&test_fname, &errors);
+                outcomes.push((test_fname, TestOutcome::Failed(TestFailure::default())));
+            }
         }
+        reporter.validation_finished(num_valid, num_invalid);
+        outcomes
     }
+}
 
-    /// Pretty print any failures to `stderr`.
-    fn pp_failures(
-        &self,
-        failures: &[(String, TestFailure)],
-        test_files_len: usize,
-        num_ignored: usize,
-        num_filtered: usize,
-    ) {
-        if !failures.is_empty() {
-            eprintln!("\n\nfailures:");
-            for (test_fname, test) in failures {
-                if let Some(ref status) = test.status {
-                    eprintln!("\n---- lang_tests::{} status ----\n{}", test_fname, status);
-                }
-                if test.stdin_remaining != 0 {
-                    eprintln!(
-                        "\n---- lang_tests::{} stdin ----\n{} bytes of stdin were not consumed",
-                        test_fname, test.stdin_remaining
-                    );
-                }
-                if let Some(ref stderr) = test.stderr {
-                    eprintln!("\n---- lang_tests::{} stderr ----\n", test_fname);
-                    if let Some(ref stderr_match) = test.stderr_match {
-                        eprint!("{}", stderr_match);
-                    } else {
-                        eprintln!("{}", stderr);
+impl LangTesterPooler {
+    /// Check that the test file `p` contains valid test data, returning the errors and warnings
+    /// found (which are empty if it is valid), or `None` if `p` is not a test file.
+    fn validate_test_file(&self, p: &Path) -> Option<(Vec<String>, Vec<String>)> {
+        let test_fname = test_fname(self.test_dir.as_deref(), p);
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        match self.extract_test_str(p) {
+            Ok(None) => return None,
+            Ok(Some(test_str)) => match self
+                .parse_tests_all_errors(&test_str)
+                .and_then(|mut tests| load_env_files(p, &mut tests).map(|_| tests))
+            {
+                Ok(tests) => {
+                    let test_cmds = self.test_cmds.as_ref().unwrap();
+                    match catch_unwind(|| test_cmds(p)) {
+                        Ok(cmds) => {
+                            let cmd_pairs = cmds
+                                .into_iter()
+                                .map(|(test_name, cmd)| (test_name.to_lowercase(), cmd))
+                                .collect::<Vec<_>>();
+                            let names = unknown_command_names(&cmd_pairs, &tests.tests);
+                            if !names.is_empty() {
+                                let e = LangTesterError::UnknownCommandNames {
+                                    test_name: test_fname.clone(),
+                                    names,
+                                };
+                                if self.strict_command_names {
+                                    errors.push(e.to_string());
+                                } else {
+                                    warnings.push(e.to_string());
+                                }
+                            }
+                            let missing = missing_command_names(&cmd_pairs, &tests.tests);
+                            if self.require_all_commands && !missing.is_empty() {
+                                errors.push(format!(
+                                    "{}: command(s) '{}' have no tests specified",
+                                    test_fname,
+                                    missing.join(", ")
+                                ));
+                            }
+                        }
+                        Err(_) => errors.push(format!("{}: test_cmds panicked", test_fname)),
                     }
                 }
-                if let Some(ref stdout) = test.stdout {
-                    eprintln!("\n---- lang_tests::{} stdout ----\n", test_fname);
-                    if let Some(ref stdout_match) = test.stdout_match {
-                        eprint!("{}", stdout_match);
-                    } else {
-                        eprintln!("{}", stdout);
+                Err(es) => errors.extend(es.into_iter().map(|e| {
+                    LangTesterError::Parse {
+                        path: p.to_owned(),
+                        line: e.line,
+                        msg: e.msg,
                     }
+                    .to_string()
+                })),
+            },
+            Err(_) => errors.push(format!("{}: test_extract panicked", test_fname)),
+        }
+        Some((errors, warnings))
+    }
+
+    /// Create a `FMBuilder` for `ptn` with the user's default `fm` options (but not `fm_options`)
+    /// applied.
+    fn fm_builder<'a>(&self, ptn: &'a str) -> FMBuilder<'a> {
+        let mut fmb = FMBuilder::new(ptn).unwrap();
+        if let Some(yes) = self.ignore_leading_whitespace {
+            fmb = fmb.ignore_leading_whitespace(yes);
+        }
+        if let Some(yes) = self.ignore_trailing_whitespace {
+            fmb = fmb.ignore_trailing_whitespace(yes);
+        }
+        fmb
+    }
+
+    /// Extract the test data from the file `p`, returning `Err` if `test_extract` panicked and
+    /// `Ok(None)` if `p` is not a test file. If the extract cache is enabled and `p` has not
+    /// changed since its test data was cached, the cached test data is returned without calling
+    /// `test_extract`.
+    fn extract_test_str(&self, p: &Path) -> thread::Result<Option<String>> {
+        let test_extract = self.test_extract.as_ref().unwrap();
+        let cache = match self.extract_cache {
+            Some(ref x) => x,
+            None => return catch_unwind(|| test_extract(p)),
+        };
+        let md = fs::metadata(p)
+            .ok()
+            .and_then(|md| Some((md.modified().ok()?, md.len())));
+        if let Some((modified, len)) = md {
+            if let Some(x) = cache.lock().unwrap().get(p) {
+                if x.modified == modified && x.len == len {
+                    return Ok(x.test_str.clone());
                 }
             }
-            eprint!("failures:");
-            for (test_fname, _) in failures {
-                eprint!("\n    lang_tests::{}", test_fname);
-            }
         }
+        let test_str = catch_unwind(|| test_extract(p))?;
+        if let Some((modified, len)) = md {
+            cache.lock().unwrap().insert(
+                p.to_owned(),
+                CachedExtract {
+                    modified,
+                    len,
+                    test_str: test_str.clone(),
+                },
+            );
+        }
+        Ok(test_str)
+    }
+
+    /// Parse the test data `test_str`, in whichever format the user has chosen, returning the
+    /// first error found (if any).
+    fn parse_tests<'a>(&self, test_str: &'a str) -> Result<Tests<'a>, ParseError> {
+        self.parse_tests_all_errors(test_str)
+            .map_err(|mut errors| errors.remove(0))
+    }
 
-        eprint!("\n\ntest result: ");
-        if failures.is_empty() {
-            write_with_colour("ok", Color::Green);
+    /// Parse the test data `test_str`, in whichever format the user has chosen, returning every
+    /// error found (if any). YAML test data is parsed until the first error.
+    fn parse_tests_all_errors<'a>(&self, test_str: &'a str) -> Result<Tests<'a>, Vec<ParseError>> {
+        if self.yaml_tests {
+            parse_yaml_tests(&self.custom_keys, &self.status_aliases, test_str).map_err(|e| vec![e])
         } else {
-            write_with_colour("FAILED", Color::Red);
-        }
-        eprintln!(
-            ". {} passed; {} failed; {} ignored; 0 measured; {} filtered out\n",
-            test_files_len - failures.len(),
-            failures.len(),
-            num_ignored,
-            num_filtered
-        );
+            let comment_prefixes = self
+                .comment_prefixes
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<_>>();
+            parse_tests_all_errors(
+                &comment_prefixes,
+                &self.custom_keys,
+                &self.status_aliases,
+                test_str,
+            )
+        }
+    }
+
+    /// The reporter to be used. This can only be called once `try_run` has set a default reporter
+    /// (if the user did not specify one).
+    fn reporter(&self) -> &dyn Reporter {
+        self.reporter.as_deref().unwrap()
+    }
+
+    /// If the extract cache is enabled, write it to `cache_path` (if it is not `None`).
+    fn write_extract_cache(&self, cache_path: Option<&Path>) {
+        if let (Some(cache), Some(cache_path)) = (self.extract_cache.as_ref(), cache_path) {
+            write_extract_cache(cache_path, &cache.lock().unwrap());
+        }
+    }
+
+    /// Has the suite timeout (if any) expired?
+    fn suite_deadline_passed(&self) -> bool {
+        self.suite_deadline
+            .map(|x| Instant::now() >= x)
+            .unwrap_or(false)
     }
 }
 
-/// The status of an executed command.
+/// The expected status of an executed command, as specified by a test's `status` key.
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub(crate) enum Status {
+pub enum Status {
     /// The command exited successfully (by whatever definition of "successful" the running
     /// platform uses).
     Success,
@@ -527,776 +2048,2606 @@ pub(crate) enum Status {
     /// The command exited with a precise exit code. This option may not be available on all
     /// platforms.
     Int(i32),
+    /// The command terminated due to a precise signal number. This option may not be available
+    /// on all platforms.
+    SignalInt(i32),
+    /// The command's exit status matched at least one of these statuses (e.g. `code(1) or
+    /// signal(11)`).
+    Any(Vec<Status>),
+}
+
+impl Status {
+    /// Does the `ExitStatus` `status` match this `Status`? Note that `Signal` is checked solely
+    /// against whether the command was terminated by a signal, irrespective of the exit code (if
+    /// any) the platform reports.
+    fn matches(&self, status: &ExitStatus) -> bool {
+        match self {
+            Status::Success => status.success(),
+            Status::Error => !status.success(),
+            Status::Signal => status.signal().is_some(),
+            Status::Int(i) => status.code() == Some(*i),
+            Status::SignalInt(i) => status.signal() == Some(*i),
+            Status::Any(statuses) => statuses.iter().any(|x| x.matches(status)),
+        }
+    }
+
+    /// Does checking this `Status` require the platform to report termination due to signals?
+    fn uses_signals(&self) -> bool {
+        match self {
+            Status::Signal | Status::SignalInt(_) => true,
+            Status::Any(statuses) => statuses.iter().any(|x| x.uses_signals()),
+            Status::Success | Status::Error | Status::Int(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Success => write!(f, "success"),
+            Status::Error => write!(f, "error"),
+            Status::Signal => write!(f, "signal"),
+            Status::Int(i) => write!(f, "code({})", i),
+            Status::SignalInt(i) => write!(f, "signal({})", i),
+            Status::Any(statuses) => write!(
+                f,
+                "{}",
+                statuses
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ),
+        }
+    }
+}
+
+/// Describe why the `ExitStatus` `status` did not match the expected `Status` `expected`.
+fn status_failure(expected: &Status, status: &ExitStatus) -> String {
+    match expected {
+        Status::Success | Status::Error => {
+            if status.success() {
+                "Success".to_owned()
+            } else if status.code().is_none() {
+                format!("Exited due to signal: {}", status.signal().unwrap())
+            } else {
+                "Error".to_owned()
+            }
+        }
+        Status::Signal => "Exit was not due to signal".to_owned(),
+        Status::Int(i) => match status.code() {
+            Some(code) => code.to_string(),
+            None => format!(
+                "Expected exit code {} but process was terminated by signal {}",
+                i,
+                status.signal().unwrap()
+            ),
+        },
+        Status::SignalInt(_) | Status::Any(_) => {
+            let actual = match (status.code(), status.signal()) {
+                (_, Some(sig)) => format!("was terminated by signal {}", sig),
+                (Some(code), None) => format!("exited with code {}", code),
+                (None, None) => "exited with an unknown status".to_owned(),
+            };
+            format!("Expected {} but process {}", expected, actual)
+        }
+    }
 }
 
 /// A user `TestCmd`.
 #[derive(Clone, Debug)]
 pub(crate) struct TestCmd<'a> {
-    pub status: Status,
+    /// The line number (starting from 1) in the test data at which this command's tests are
+    /// specified, or `None` if they are not specified in the test data.
+    pub line: Option<usize>,
+    /// The expected status, or `None` if the test does not specify one (in which case the
+    /// `default_status` is expected).
+    pub status: Option<Status>,
     pub stdin: Option<String>,
-    pub stderr: Vec<&'a str>,
-    pub stdout: Vec<&'a str>,
+    /// The number of bytes of `stdin` which the command must not consume, or `None` if any number
+    /// is acceptable.
+    pub stdin_remaining: Option<usize>,
+    pub stderr: Vec<Cow<'a, str>>,
+    pub stdout: Vec<Cow<'a, str>>,
+    /// A regular expression which must match stderr.
+    pub stderr_regex: Option<Regex>,
+    /// A regular expression which must match stdout.
+    pub stdout_regex: Option<Regex>,
+    /// Lines of stderr matching this regular expression are removed before any checks are made.
+    pub stderr_ignore: Option<Regex>,
+    /// Lines of stdout matching this regular expression are removed before any checks are made.
+    pub stdout_ignore: Option<Regex>,
+    /// Text which stderr must be exactly equal to.
+    pub stderr_exact: Option<String>,
+    /// Text which stdout must be exactly equal to.
+    pub stdout_exact: Option<String>,
+    /// If `Some(true)`, non-empty stderr and stdout must end with a newline; if `Some(false)`,
+    /// they must not.
+    pub trailing_newline: Option<bool>,
+    /// The path of a file containing a fuzzy match pattern which stderr must match.
+    pub stderr_matches_file: Option<String>,
+    /// The path of a file containing a fuzzy match pattern which stdout must match.
+    pub stdout_matches_file: Option<String>,
     /// A list of custom command line arguments which should be passed when
     /// executing the test command.
     pub args: Vec<String>,
-    pub env: HashMap<String, String>,
+    /// Environment variables to set (`Some`) or remove (`None`) before executing the command.
+    pub env: HashMap<String, Option<String>>,
+    /// The line offset of the `env-file` key and the path (relative to the test file) of a file of
+    /// environment variables to set before those in `env`.
+    pub env_file: Option<(usize, String)>,
+    /// The environment variables read from `env_file` (see `load_env_files`).
+    pub env_file_vars: Vec<(String, String)>,
+    /// If set, the maximum amount of memory (in bytes) the command is allowed to allocate.
+    pub max_rss: Option<u64>,
+    /// If set, the test fails if the command's peak resident set size (in bytes) exceeds this.
+    pub max_peak_rss: Option<u64>,
+    /// The number of times the command is run: the test fails if any run fails.
+    pub repeat: u64,
+    /// If true, the command's stdin and stdout are connected to a pseudo-terminal.
+    pub pty: bool,
+    /// If true, a matching `rerun-if-*` reruns the whole sequence of commands rather than just
+    /// this command.
+    pub rerun_scope_file: bool,
+    /// If true, the wall-clock time the command takes to run is measured.
+    pub bench: bool,
+    /// If `Some(true)`, subsequent commands are not run if this command exits unsuccessfully (as
+    /// expected); if `Some(false)`, they are always run. If `None`, subsequent commands are not
+    /// run only if this command's `status` is `error`.
+    pub skip_remaining_on_failure: Option<bool>,
+    /// If set, overrides `rerun_at_most` for this command.
+    pub rerun_at_most: Option<u64>,
     pub rerun_if_status: Option<Status>,
-    pub rerun_if_stderr: Option<Vec<&'a str>>,
-    pub rerun_if_stdout: Option<Vec<&'a str>>,
+    /// Rerun the command if it was terminated by this specific signal number.
+    pub rerun_if_signal: Option<i32>,
+    pub rerun_if_stderr: Option<Vec<Cow<'a, str>>>,
+    pub rerun_if_stdout: Option<Vec<Cow<'a, str>>>,
+    /// The data collected from custom keys.
+    pub custom: CustomData,
+    /// The line number (starting from 1) in the test data at which each key (e.g. `stdout`) was
+    /// first specified.
+    pub key_lines: HashMap<String, usize>,
 }
 
 impl<'a> TestCmd<'a> {
     pub fn default() -> Self {
         Self {
-            status: Status::Success,
+            line: None,
+            status: None,
             stdin: None,
-            stderr: vec!["..."],
-            stdout: vec!["..."],
+            stdin_remaining: Some(0),
+            stderr: vec![Cow::Borrowed("...")],
+            stdout: vec![Cow::Borrowed("...")],
+            stderr_regex: None,
+            stdout_regex: None,
+            stderr_ignore: None,
+            stdout_ignore: None,
+            stderr_exact: None,
+            stdout_exact: None,
+            trailing_newline: None,
+            stderr_matches_file: None,
+            stdout_matches_file: None,
             args: Vec::new(),
             env: HashMap::new(),
+            env_file: None,
+            env_file_vars: Vec::new(),
+            max_rss: None,
+            max_peak_rss: None,
+            repeat: 1,
+            pty: false,
+            rerun_scope_file: false,
+            bench: false,
+            skip_remaining_on_failure: None,
+            rerun_at_most: None,
             rerun_if_status: None,
+            rerun_if_signal: None,
             rerun_if_stderr: None,
             rerun_if_stdout: None,
+            custom: CustomData::default(),
+            key_lines: HashMap::new(),
         }
     }
 }
 
 /// A collection of tests.
+#[derive(Debug)]
 pub(crate) struct Tests<'a> {
     pub ignore_if: Option<String>,
+    /// If the current architecture (as reported by `std::env::consts::ARCH`) is in this list,
+    /// the test is ignored.
+    pub ignore_if_arch: Option<Vec<String>>,
+    /// If set, the test is ignored unless the stdout of `ignore_if` matches this (fuzzy) pattern
+    /// (`ignore_if`'s exit status is then irrelevant).
+    pub ignore_unless_stdout: Option<String>,
+    /// The tags given by `tags` (empty if none were given).
+    pub tags: HashSet<String>,
     pub tests: HashMap<String, TestCmd<'a>>,
 }
 
-/// If one or more parts of a `TestCmd` fail, the parts that fail are set to `Some(...)` in an
-/// instance of this struct.
-#[derive(Debug)]
-struct TestFailure {
-    status: Option<String>,
-    stdin_remaining: usize,
-    stderr: Option<String>,
-    stderr_match: Option<FMatchError>,
-    stdout: Option<String>,
-    stdout_match: Option<FMatchError>,
+/// Why a test failed. If one or more parts of a `TestCmd` fail, the parts that fail are set to
+/// `Some(...)` in an instance of this struct. If a user-supplied function (e.g. `test_extract`)
+/// panicked, `panicked` names it.
+#[derive(Debug, Default)]
+pub struct TestFailure {
+    /// The name of the user-supplied function (e.g. `after_each`) which panicked, if any.
+    pub(crate) panicked: Option<String>,
+    /// The name of the failing command (as returned by `test_cmds`), if known.
+    pub(crate) cmd_name: Option<String>,
+    /// The line number in the test data at which the failing command's tests are specified, if
+    /// any.
+    pub(crate) cmd_line: Option<usize>,
+    /// The line number in the test data at which each of the failing command's keys is specified.
+    pub(crate) key_lines: HashMap<String, usize>,
+    /// The path of the test file, if the failure occurred while running a command.
+    pub(crate) path: Option<PathBuf>,
+    /// The number of lines in the test file preceding the test data, if known.
+    pub(crate) data_offset: Option<usize>,
+    /// If the command's exit status did not match the test's `status`, the expected status.
+    pub(crate) expected_status: Option<Status>,
+    pub(crate) status: Option<String>,
+    pub(crate) stdin_remaining: usize,
+    /// If the number of bytes of stdin the command did not consume did not match the test's
+    /// `stdin-remaining`, the expected number.
+    pub(crate) expected_stdin_remaining: Option<usize>,
+    pub(crate) stderr: Option<String>,
+    /// Why stderr did not match the expected output.
+    pub(crate) stderr_match: Option<String>,
+    pub(crate) stdout: Option<String>,
+    /// Why stdout did not match the expected output.
+    pub(crate) stdout_match: Option<String>,
+    /// If the command's output exceeded `max_output_bytes`, the limit it exceeded.
+    pub(crate) output_exceeded: Option<usize>,
+    /// If the command's peak resident set size exceeded the test's `max-peak-rss`, a tuple
+    /// `(peak_rss, max_peak_rss)` (both in bytes).
+    pub(crate) peak_rss_exceeded: Option<(u64, u64)>,
+    /// If the failing command was to be run more than once (with `repeat`), the (1-based) run
+    /// which failed and the total number of runs.
+    pub(crate) failed_repeat: Option<(u64, u64)>,
+    /// The (lower-cased) names of commands which have tests specified for them but which were not
+    /// run because an earlier command failed (as expected).
+    pub(crate) not_reached: Vec<String>,
+    /// The (lower-cased) names of commands which have no tests specified for them, if
+    /// `require_all_commands` is set.
+    pub(crate) missing_commands: Vec<String>,
 }
 
-fn write_with_colour(s: &str, colour: Color) {
-    let mut stderr = StandardStream::stderr(ColorChoice::Always);
-    stderr.set_color(ColorSpec::new().set_fg(Some(colour))).ok();
-    io::stderr().write_all(s.as_bytes()).ok();
-    stderr.reset().ok();
-}
+impl TestFailure {
+    /// Create a `TestFailure` recording that the user-supplied function `name` panicked.
+    pub(crate) fn from_panic(name: &str) -> Self {
+        TestFailure {
+            panicked: Some(name.to_owned()),
+            ..TestFailure::default()
+        }
+    }
 
-fn write_ignored(test_name: &str, message: &str, inner: Arc<LangTesterPooler>) {
-    // Grab a lock on stderr so that we can avoid the possibility of lines blurring
-    // together in confusing ways.
-    let stderr = StandardStream::stderr(ColorChoice::Always);
-    let mut handle = stderr.lock();
-    if inner.test_threads > 1 {
-        handle
-            .write_all(format!("\ntest lang_tests::{} ... ", test_name).as_bytes())
-            .ok();
+    /// If the test failed because a user-supplied function (e.g. `before_each`) panicked, the
+    /// name of that function.
+    pub fn panicked(&self) -> Option<&str> {
+        self.panicked.as_deref()
     }
-    handle
-        .set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))
-        .ok();
-    handle.write_all(b"ignored").ok();
-    handle.reset().ok();
-    if !message.is_empty() {
-        handle.write_all(format!(" ({})", message).as_bytes()).ok();
+
+    /// The (lower-cased) name of the failing command, if the failure occurred while running a
+    /// command.
+    pub fn cmd_name(&self) -> Option<&str> {
+        self.cmd_name.as_deref()
     }
-}
 
-fn usage() -> ! {
-    eprintln!("Usage: [--ignored] [--nocapture] [--test-threads=<n>] [<filter1>] [... <filtern>]");
-    process::exit(1);
-}
+    /// The line number (starting from 1) in the test data (i.e. as returned by `test_extract`) at
+    /// which the failing command's tests are specified, or `None` if the command has no tests in
+    /// the test data (e.g. because it runs with the default expectations).
+    pub fn cmd_line(&self) -> Option<usize> {
+        self.cmd_line
+    }
 
-/// Check for the case where the user has a test called `X` but `test_cmds` doesn't have a command
-/// with a matching name. This is almost certainly a bug, in the sense that the test can never,
-/// ever fire.
-fn check_names(cmd_pairs: &[(String, Command)], tests: &HashMap<String, TestCmd>) {
-    let cmd_names = cmd_pairs.iter().map(|x| &x.0).collect::<HashSet<_>>();
-    let test_names = tests.keys().collect::<HashSet<_>>();
-    let diff = test_names
-        .difference(&cmd_names)
-        .map(|x| x.as_str())
-        .collect::<Vec<_>>();
-    if !diff.is_empty() {
-        fatal(&format!(
-            "Command name(s) '{}' in tests are not found in the actual commands.",
-            diff.join(", ")
-        ));
+    /// The line number (starting from 1) in the test data at which the failing command's `key`
+    /// (e.g. `stdout`) is specified, or `None` if the key is not specified.
+    pub fn key_line(&self, key: &str) -> Option<usize> {
+        self.key_lines.get(key).copied()
     }
-}
 
-/// Run every test in `test_files`, returning a tuple `(failures, num_ignored)`.
-fn test_file(
-    test_files: Vec<PathBuf>,
-    inner: Arc<LangTesterPooler>,
-    failures: Arc<Mutex<Vec<(String, TestFailure)>>>,
-) -> usize {
-    let num_ignored = Arc::new(AtomicUsize::new(0));
-    let pool = ThreadPool::new(inner.test_threads);
-    for p in test_files {
-        let test_fname = test_fname(inner.test_dir.as_ref().unwrap(), &p);
+    /// Describe where the test data's line `line` is defined: as `<path>:<line>` if the test data
+    /// can be located within the test file, and relative to the test data otherwise.
+    pub(crate) fn defined_at(&self, line: usize) -> String {
+        match (&self.path, self.data_offset) {
+            (Some(path), Some(off)) => format!("{}:{}", path.display(), off + line),
+            (Some(path), None) => format!("line {} of the test data in {}", line, path.display()),
+            (None, _) => format!("line {} of the test data", line),
+        }
+    }
 
-        let num_ignored = num_ignored.clone();
-        let failures = failures.clone();
-        let inner = inner.clone();
-        pool.execute(move || {
-            if inner.test_threads == 1 {
-                eprint!("\ntest lang_test::{} ... ", test_fname);
-            }
-            let test_extract = inner.test_extract.as_ref().unwrap();
-            match catch_unwind(|| test_extract(p.as_path())) {
-                Ok(test_str) => {
-                    if test_str.is_empty() {
-                        write_ignored(test_fname.as_str(), "test string is empty", inner);
-                        num_ignored.fetch_add(1, Ordering::Relaxed);
-                        return;
-                    }
+    /// If the exit status of the failing command did not match that which the test expected,
+    /// returns the expected status.
+    pub fn expected_status(&self) -> Option<&Status> {
+        self.expected_status.as_ref()
+    }
 
-                    let tests = parse_tests(inner.comment_prefix.as_deref(), &test_str);
-                    let ignore = if let Some(ignore_if) = tests.ignore_if {
-                        Command::new(env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned()))
-                            .args(["-c", &ignore_if])
-                            .current_dir(env::var("CARGO_MANIFEST_DIR").unwrap())
-                            .stdin(process::Stdio::piped())
-                            .stderr(process::Stdio::piped())
-                            .stdout(process::Stdio::piped())
-                            .status()
-                            .unwrap_or_else(|_| {
-                                fatal(&format!("Couldn't run ignore-if '{ignore_if}'"))
-                            })
-                            .success()
-                    } else {
-                        false
-                    };
-                    if (inner.ignored && !ignore) || (!inner.ignored && ignore) {
-                        write_ignored(test_fname.as_str(), "", inner);
-                        num_ignored.fetch_add(1, Ordering::Relaxed);
-                        return;
-                    }
+    /// A human-readable description of the failing command's exit status (e.g. "Success" or
+    /// "Exited due to signal: 9").
+    pub fn status(&self) -> Option<&str> {
+        self.status.as_deref()
+    }
 
-                    if run_tests(Arc::clone(&inner), tests.tests, p, test_fname, failures) {
-                        num_ignored.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
-                Err(_) => {
-                    let failure = TestFailure {
-                        status: None,
-                        stdin_remaining: 0,
-                        stderr: None,
-                        stderr_match: None,
-                        stdout: None,
-                        stdout_match: None,
-                    };
-                    failures.lock().unwrap().push((test_fname, failure));
-                }
-            };
-        });
+    /// The number of bytes of the test's `stdin` which the failing command did not consume, if
+    /// that number was not what the test expected (and otherwise 0).
+    pub fn stdin_remaining(&self) -> usize {
+        self.stdin_remaining
     }
-    pool.join();
 
-    Arc::try_unwrap(num_ignored).unwrap().into_inner()
-}
+    /// If the number of bytes of the test's `stdin` which the failing command did not consume was
+    /// not what the test expected, the expected number.
+    pub fn expected_stdin_remaining(&self) -> Option<usize> {
+        self.expected_stdin_remaining
+    }
 
-/// Convert a test file name to a user-friendly test name (e.g. "lang_tests/a/b.x" might become
-/// "a::b.x").
-fn test_fname(test_dir_path: &Path, test_fpath: &Path) -> String {
-    if let Some(test_fpath) = test_fpath.as_os_str().to_str() {
-        if let Some(testdir_path) = test_dir_path.as_os_str().to_str() {
-            if test_fpath.starts_with(testdir_path) {
-                return test_fpath[testdir_path.len() + MAIN_SEPARATOR.len_utf8()..]
-                    .to_owned()
-                    .replace(MAIN_SEPARATOR, "::");
-            }
-        }
+    /// The failing command's stderr. This is returned even if stderr matched what was expected, so
+    /// that the user can see the full context of the failure.
+    pub fn stderr(&self) -> Option<&str> {
+        self.stderr.as_deref()
     }
 
-    test_fpath.file_stem().unwrap().to_str().unwrap().to_owned()
-}
+    /// If stderr did not match what was expected, a description of why.
+    pub fn stderr_match(&self) -> Option<&str> {
+        self.stderr_match.as_deref()
+    }
 
-/// Run the tests for `path`.
-fn run_tests(
-    inner: Arc<LangTesterPooler>,
-    tests: HashMap<String, TestCmd>,
-    path: PathBuf,
-    test_fname: String,
-    failures: Arc<Mutex<Vec<(String, TestFailure)>>>,
-) -> bool {
-    if !cfg!(unix) && tests.values().any(|t| t.status == Status::Signal) {
-        write_ignored(
-            test_fname.as_str(),
-            "signal termination not supported on this platform",
-            inner,
-        );
-        return true;
+    /// The failing command's stdout. This is returned even if stdout matched what was expected, so
+    /// that the user can see the full context of the failure.
+    pub fn stdout(&self) -> Option<&str> {
+        self.stdout.as_deref()
     }
 
-    let mut failure = TestFailure {
-        status: None,
-        stdin_remaining: 0,
-        stderr: None,
-        stderr_match: None,
-        stdout: None,
-        stdout_match: None,
-    };
+    /// If stdout did not match what was expected, a description of why.
+    pub fn stdout_match(&self) -> Option<&str> {
+        self.stdout_match.as_deref()
+    }
 
-    let test_cmds = inner.test_cmds.as_ref().unwrap();
-    let cmd_pairs = match catch_unwind(|| test_cmds(path.as_path())) {
-        Ok(x) => x
-            .into_iter()
-            .map(|(test_name, cmd)| (test_name.to_lowercase(), cmd))
-            .collect::<Vec<_>>(),
-        Err(_) => {
-            failures.lock().unwrap().push((test_fname, failure));
-            return false;
-        }
-    };
-    check_names(&cmd_pairs, &tests);
-
-    'a: for (cmd_name, mut cmd) in cmd_pairs {
-        let default_test = TestCmd::default();
-        let test = tests.get(&cmd_name).unwrap_or(&default_test);
-        cmd.args(&test.args);
-        cmd.envs(&test.env);
-        let mut rerun = 0;
-        loop {
-            rerun += 1;
-            let (status, stdin_remaining, stderr, stdout) =
-                run_cmd(inner.clone(), &test_fname, &mut cmd, test);
-
-            let mut meant_to_error = false;
-
-            // Give the user the option of setting options for the fuzzy matchers.
-            let stderr_str = test.stderr.join("\n");
-            let mut stderr_fmb = FMBuilder::new(&stderr_str).unwrap();
-            let stdout_str = test.stdout.join("\n");
-            let mut stdout_fmb = FMBuilder::new(&stdout_str).unwrap();
-
-            let rerun_if_stderr_str = test.rerun_if_stderr.as_ref().unwrap_or(&vec![]).join("\n");
-            let mut rerun_if_stderr_fmb = FMBuilder::new(&rerun_if_stderr_str).unwrap();
-            let rerun_if_stdout_str = test.rerun_if_stdout.as_ref().unwrap_or(&vec![]).join("\n");
-            let mut rerun_if_stdout_fmb = FMBuilder::new(&rerun_if_stdout_str).unwrap();
-            if let Some(ref fm_options) = inner.fm_options {
-                match catch_unwind(|| {
-                    (
-                        fm_options(path.as_path(), TestStream::Stderr, stderr_fmb),
-                        fm_options(path.as_path(), TestStream::Stdout, stdout_fmb),
-                        fm_options(path.as_path(), TestStream::Stderr, rerun_if_stderr_fmb),
-                        fm_options(path.as_path(), TestStream::Stdout, rerun_if_stdout_fmb),
-                    )
-                }) {
-                    Ok((a, b, c, d)) => {
-                        stderr_fmb = a;
-                        stdout_fmb = b;
-                        rerun_if_stderr_fmb = c;
-                        rerun_if_stdout_fmb = d;
-                    }
-                    Err(_) => {
-                        failures.lock().unwrap().push((test_fname, failure));
-                        return false;
+    /// If the failing command was killed because its output exceeded
+    /// [`max_output_bytes`](struct.LangTester.html#method.max_output_bytes), the limit it
+    /// exceeded.
+    pub fn output_exceeded(&self) -> Option<usize> {
+        self.output_exceeded
+    }
+
+    /// If the failing command's peak resident set size exceeded the test's `max-peak-rss`,
+    /// returns a tuple `(peak_rss, max_peak_rss)` (both in bytes).
+    pub fn peak_rss_exceeded(&self) -> Option<(u64, u64)> {
+        self.peak_rss_exceeded
+    }
+
+    /// If the failing command was to be run more than once (with `repeat`), returns a tuple
+    /// `(run, repeat)` where `run` (starting from 1) is the run which failed and `repeat` the
+    /// number of times the command was to be run.
+    pub fn failed_repeat(&self) -> Option<(u64, u64)> {
+        self.failed_repeat
+    }
+
+    /// The (lower-cased) names of commands which have tests specified for them but which were not
+    /// run because an earlier command failed as expected (see `skip-remaining-on-failure`). The
+    /// first such command is reported as the failing command.
+    pub fn not_reached(&self) -> &[String] {
+        &self.not_reached
+    }
+
+    /// The (lower-cased) names of commands which have no tests specified for them, if
+    /// [`require_all_commands`](struct.LangTester.html#method.require_all_commands) is set. If
+    /// this is non-empty, none of the test's commands were run.
+    pub fn missing_commands(&self) -> &[String] {
+        &self.missing_commands
+    }
+}
+
+/// The outcome of running a single test file.
+#[derive(Debug)]
+pub enum TestOutcome {
+    /// Every command in the test behaved as expected. `reruns` is the number of times commands
+    /// were rerun (because of a `rerun-if-*` key) before the test passed: if it is non-zero, the
+    /// test may be flaky.
+    Passed { reruns: u64 },
+    /// At least one command in the test did not behave as expected.
+    Failed(TestFailure),
+    /// Every command in the test behaved as expected, and the commands with `bench: true` took, on
+    /// average, `ns_per_iter` nanoseconds (in total) to run.
+    Measured { ns_per_iter: u64 },
+    /// The test was ignored, with a (possibly empty) reason.
+    Ignored(String),
+    /// The test was not run because it cannot be run on this platform, with a reason.
+    Unsupported(String),
+    /// The [suite timeout](struct.LangTester.html#method.suite_timeout) expired before the test
+    /// completed (or, possibly, before it started).
+    TimedOut,
+}
+
+/// The result of running a command.
+struct CmdResult {
+    status: ExitStatus,
+    /// How many bytes of the `stdin` the command was given were not consumed.
+    stdin_remaining: usize,
+    stderr: String,
+    stdout: String,
+    /// Did the command's stderr or stdout exceed `max_output_bytes`? If so, the command was
+    /// killed, and `stderr`/`stdout` are truncated.
+    output_exceeded: bool,
+    /// The command's peak resident set size in bytes, if the platform reports it.
+    peak_rss: Option<u64>,
+    /// Was the command killed because the suite timeout expired?
+    suite_timed_out: bool,
+}
+
+/// Read the environment variables of every command in `tests` with an `env-file` key (relative to
+/// the test file `test_path`) into that command's `env_file_vars`.
+fn load_env_files(test_path: &Path, tests: &mut Tests) -> Result<(), Vec<ParseError>> {
+    let mut errors = Vec::new();
+    for test in tests.tests.values_mut() {
+        if let Some((line_off, ref env_file)) = test.env_file {
+            let env_path = test_path.parent().unwrap().join(env_file);
+            match read_env_file(&env_path) {
+                Ok(vars) => test.env_file_vars = vars,
+                Err(msg) => errors.push(ParseError::new(line_off, msg)),
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        errors.sort_by_key(|e| e.line);
+        Err(errors)
+    }
+}
+
+/// Read the environment variables in the file `path` (as specified by an `env-file` key): each
+/// line is either blank, a comment beginning with `#`, or of the form `<key>=<string>`.
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let s = fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read env file '{}': {}", path.display(), e))?;
+    let mut vars = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, val)) if !key.trim().is_empty() => {
+                vars.push((key.trim().to_owned(), expand_env_vars(val.trim())))
+            }
+            _ => {
+                return Err(format!(
+                    "Line {} of env file '{}' is not in the format '<key>=<string>'.",
+                    i + 1,
+                    path.display()
+                ))
+            }
+        }
+    }
+    Ok(vars)
+}
+
+/// Replace the placeholders `$FILE`, `$DIR`, and `$NAME` in `s` with, respectively, the full path
+/// of the test file `path`, the directory containing it, and its file name.
+fn subst_placeholders(s: &str, path: &Path) -> String {
+    let file = path.to_str().unwrap_or("");
+    let dir = path.parent().and_then(|x| x.to_str()).unwrap_or("");
+    let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+    s.replace("$FILE", file)
+        .replace("$DIR", dir)
+        .replace("$NAME", name)
+}
+
+/// Return a message, suitable for showing the user, explaining that `text` did not match the
+/// fuzzy match pattern `ptn`, given `fm`'s explanation `msg`.
+fn fuzzy_match_msg(inner: &LangTesterPooler, msg: String, ptn: &str, text: &str) -> String {
+    if !inner.fuzzy_diffs {
+        return msg;
+    }
+    let diff = fuzzy_diff(ptn, text);
+    if diff.is_empty() {
+        msg
+    } else if msg.ends_with('\n') {
+        format!("{}\n{}", msg, diff)
+    } else {
+        format!("{}\n\n{}", msg, diff)
+    }
+}
+
+/// If `re` is `Some`, check that it matches `text`, returning a message suitable for showing the
+/// user if not.
+fn match_regex(re: Option<&Regex>, text: &str) -> Result<(), String> {
+    match re {
+        Some(re) if !re.is_match(text) => Err(format!(
+            "Regex:\n  {}\ndid not match:\n{}",
+            re.as_str().replace('\n', "\n  "),
+            text
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Combine the results of checking a stream in several ways. If `all` is true, the reasons for
+/// every failed check are returned (separated by blank lines); otherwise only the reason for the
+/// first failed check is returned.
+fn combine_matches(all: bool, results: Vec<Result<(), String>>) -> Result<(), String> {
+    let mut errs = results.into_iter().filter_map(|x| x.err());
+    if !all {
+        return errs.next().map_or(Ok(()), Err);
+    }
+    let errs = errs.collect::<Vec<_>>();
+    if errs.is_empty() {
+        Ok(())
+    } else {
+        Err(errs.join("\n\n"))
+    }
+}
+
+/// If `expected` is `Some`, check that it is exactly equal to `text` (after normalising `\r\n` to
+/// `\n`), returning a unified diff suitable for showing the user if not.
+fn match_exact(expected: Option<&str>, text: &str) -> Result<(), String> {
+    match expected {
+        Some(expected) => {
+            let text = text.replace("\r\n", "\n");
+            if expected == text {
+                Ok(())
+            } else {
+                Err(unified_diff(expected, &text))
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+/// If `expected` is `Some`, check that `text` (if it is not empty) does (`Some(true)`) or does not
+/// (`Some(false)`) end with a newline, returning a message suitable for showing the user if not.
+fn match_trailing_newline(expected: Option<bool>, text: &str) -> Result<(), String> {
+    match expected {
+        Some(expected) if !text.is_empty() && text.ends_with('\n') != expected => {
+            if expected {
+                Err("Expected a trailing newline, but output does not end with one.".to_owned())
+            } else {
+                Err("Expected no trailing newline, but output ends with one.".to_owned())
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// If `ptn_path` is `Some`, check that `text` matches the fuzzy match pattern in the file at
+/// `ptn_path` (after substituting placeholders relative to the test file `path`), returning a
+/// message suitable for showing the user if not. The file is read each time this is called, so it
+/// can be created while tests are running (e.g. by
+/// [`before_each`](struct.LangTester.html#method.before_each)). As with `stderr` and `stdout`, the
+/// user's `matcher` (if any) is used instead of `fm`, and the user's `fm_options` (if any) are
+/// applied.
+fn match_file(
+    inner: &LangTesterPooler,
+    path: &Path,
+    stream: TestStream,
+    ptn_path: Option<&str>,
+    text: &str,
+) -> Result<(), String> {
+    let ptn_path = match ptn_path {
+        Some(x) => subst_placeholders(x, path),
+        None => return Ok(()),
+    };
+    let ptn = fs::read_to_string(&ptn_path)
+        .map_err(|e| format!("Couldn't read pattern file '{}': {}", ptn_path, e))?;
+    // Like the patterns given in test data, the pattern does not include a trailing newline.
+    let ptn = ptn.strip_suffix('\n').unwrap_or(&ptn);
+    if let Some(ref matcher) = inner.matcher {
+        return catch_unwind(|| matcher(path, stream, ptn, text))
+            .unwrap_or_else(|_| Err("matcher panicked".to_owned()));
+    }
+    let mut fmb = inner.fm_builder(ptn);
+    if let Some(ref fm_options) = inner.fm_options {
+        fmb = catch_unwind(|| fm_options(path, stream, fmb))
+            .map_err(|_| "fm_options panicked".to_owned())?;
+    }
+    match fmb.build() {
+        Ok(x) => x
+            .matches(text)
+            .map_err(|e| fuzzy_match_msg(inner, e.to_string(), ptn, text)),
+        Err(e) => Err(format!("FM error in '{}': {}", ptn_path, e)),
+    }
+}
+
+/// The colour choice to use if the user does not pass `--color`: see <https://no-color.org/> and
+/// <https://bixense.com/clicolors/>.
+fn default_colour_choice() -> ColorChoice {
+    if env::var_os("CLICOLOR_FORCE").map(|x| x != "0") == Some(true) {
+        ColorChoice::Always
+    } else if env::var_os("NO_COLOR").map(|x| !x.is_empty()) == Some(true) {
+        ColorChoice::Never
+    } else {
+        ColorChoice::Always
+    }
+}
+
+/// Write `s` to stderr in `colour` (if `colour_choice` allows colours to be used).
+pub(crate) fn write_with_colour(s: &str, colour: Color, colour_choice: ColorChoice) {
+    let mut stderr = StandardStream::stderr(colour_choice);
+    stderr.set_color(ColorSpec::new().set_fg(Some(colour))).ok();
+    io::stderr().write_all(s.as_bytes()).ok();
+    stderr.reset().ok();
+}
+
+/// Return a [`LangTesterError::Usage`] with a usage summary, preceded by `msg` if it is not `None`.
+fn usage_error(msg: Option<&str>) -> LangTesterError {
+    let usage = "Usage: [--check] [--color=<auto|always|never>] [--dry-run] [--failed] \
+                 [--filter-regex=<regex>] [--format=<pretty|terse>] [--ignored] \
+                 [--include-ignored] [--nocapture] [--skip=<filter>] [--tag=<tag>] \
+                 [--test-threads=<n>] [--verbose] [<filter1>] [... <filtern>]";
+    match msg {
+        Some(msg) => LangTesterError::Usage(format!("{}\n{}", msg, usage)),
+        None => LangTesterError::Usage(usage.to_owned()),
+    }
+}
+
+/// Check that no two distinct paths in `paths` have the same test name: if they did, the results
+/// of one would be indistinguishable from the other, and filtering by name would silently select
+/// both.
+fn check_duplicate_names(
+    test_dir: Option<&Path>,
+    paths: &[PathBuf],
+) -> Result<(), LangTesterError> {
+    let mut names = HashMap::<String, Vec<&PathBuf>>::new();
+    for p in paths {
+        let ps = names.entry(test_fname(test_dir, p)).or_default();
+        if !ps.contains(&p) {
+            ps.push(p);
+        }
+    }
+    let mut dups = names
+        .into_iter()
+        .filter(|(_, ps)| ps.len() > 1)
+        .collect::<Vec<_>>();
+    dups.sort_unstable();
+    match dups.into_iter().next() {
+        Some((test_name, ps)) => Err(LangTesterError::DuplicateTestNames {
+            test_name,
+            paths: ps.into_iter().cloned().collect(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Check for the case where the user has a test called `X` but `test_cmds` doesn't have a command
+/// with a matching name. This is almost certainly a bug, in the sense that the test can never,
+/// ever fire.
+fn check_names<T>(
+    inner: &LangTesterPooler,
+    test_fname: &str,
+    cmd_pairs: &[(String, T)],
+    tests: &HashMap<String, TestCmd>,
+) -> Result<(), LangTesterError> {
+    let diff = unknown_command_names(cmd_pairs, tests);
+    if diff.is_empty() {
+        return Ok(());
+    }
+    if inner.strict_command_names {
+        return Err(LangTesterError::UnknownCommandNames {
+            test_name: test_fname.to_owned(),
+            names: diff,
+        });
+    }
+    // Sub-tests for unknown command names are never run, so all we need to do is warn the user.
+    let msg = format!(
+        "Command name(s) '{}' in tests are not found in the actual commands.",
+        diff.join(", ")
+    );
+    inner.reporter().warning(Some(test_fname), &msg);
+    Ok(())
+}
+
+/// Cargo's target directory, or `None` if we are not running under Cargo.
+fn target_dir() -> Option<PathBuf> {
+    match env::var_os("CARGO_TARGET_DIR") {
+        Some(x) => Some(PathBuf::from(x)),
+        None => Some(Path::new(&env::var_os("CARGO_MANIFEST_DIR")?).join("target")),
+    }
+}
+
+/// The path of the file recording which tests failed when they were last run, or `None` if we are
+/// not running under Cargo.
+fn failed_cache_path() -> Option<PathBuf> {
+    Some(target_dir()?.join("lang_tester_failed"))
+}
+
+/// Read the set of test file paths (one per line) which failed when they were last run. If the
+/// file doesn't exist (or can't be read), no tests are considered to have failed.
+fn read_failed_cache(cache_path: &Path) -> HashSet<PathBuf> {
+    fs::read_to_string(cache_path)
+        .map(|s| s.lines().map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+/// Record that, of the tests in `run`, those in `failed` failed. Since the cache may be shared by
+/// several test suites, tests which weren't run keep whatever state they had. Failing to update
+/// the cache is not considered an error, since it only affects a subsequent `--failed` run.
+fn write_failed_cache(cache_path: &Path, run: &[PathBuf], failed: &[PathBuf]) {
+    let mut cached = read_failed_cache(cache_path);
+    for p in run {
+        cached.remove(p);
+    }
+    cached.extend(failed.iter().cloned());
+    let mut cached = cached
+        .into_iter()
+        .filter_map(|x| x.to_str().map(|x| x.to_owned()))
+        .collect::<Vec<_>>();
+    cached.sort();
+    let mut s = cached.join("\n");
+    if !s.is_empty() {
+        s.push('\n');
+    }
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    fs::write(cache_path, s).ok();
+}
+
+/// The test data extracted from a test file by `test_extract`.
+struct CachedExtract {
+    /// The modification time of the test file when the test data was extracted.
+    modified: SystemTime,
+    /// The size of the test file when the test data was extracted.
+    len: u64,
+    /// The test data, or `None` if the file is not a test file.
+    test_str: Option<String>,
+}
+
+/// The path of the file caching the results of `test_extract` for the current test binary, or
+/// `None` if we are not running under Cargo.
+fn extract_cache_path() -> Option<PathBuf> {
+    let exe = env::current_exe().ok()?;
+    let exe_name = exe.file_name()?.to_str()?;
+    Some(target_dir()?.join(format!("lang_tester_extract-{}", exe_name)))
+}
+
+/// Read the cached results of `test_extract`. Each entry consists of the test file's path on one
+/// line; its modification time (as seconds and nanoseconds since the Unix epoch), size, and the
+/// length in bytes of the test data on the next; and then the test data itself followed by a
+/// newline. If the file is not a test file, the length and test data are omitted. If the file
+/// doesn't exist, can't be read, or is malformed, the cache is empty.
+fn read_extract_cache(cache_path: &Path) -> HashMap<PathBuf, CachedExtract> {
+    let s = match fs::read_to_string(cache_path) {
+        Ok(s) => s,
+        Err(_) => return HashMap::new(),
+    };
+    let mut cached = HashMap::new();
+    let mut rest = s.as_str();
+    while !rest.is_empty() {
+        match read_extract_cache_entry(rest) {
+            Some((p, entry, r)) => {
+                cached.insert(p, entry);
+                rest = r;
+            }
+            None => return HashMap::new(),
+        }
+    }
+    cached
+}
+
+/// Read a single entry from the start of `s`, returning the entry and the remainder of `s`, or
+/// `None` if the entry is malformed.
+fn read_extract_cache_entry(s: &str) -> Option<(PathBuf, CachedExtract, &str)> {
+    let (path, s) = s.split_once('\n')?;
+    let (header, s) = s.split_once('\n')?;
+    let header = header
+        .split(' ')
+        .map(|x| x.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let (secs, nanos, len, test_str, rest) = match header[..] {
+        [secs, nanos, len, str_len] => {
+            let str_len = usize::try_from(str_len).ok()?;
+            let test_str = s.get(..str_len)?;
+            let rest = s.get(str_len..)?.strip_prefix('\n')?;
+            (secs, nanos, len, Some(test_str.to_owned()), rest)
+        }
+        [secs, nanos, len] => (secs, nanos, len, None, s),
+        _ => return None,
+    };
+    let modified = UNIX_EPOCH.checked_add(Duration::new(secs, u32::try_from(nanos).ok()?))?;
+    Some((
+        PathBuf::from(path),
+        CachedExtract {
+            modified,
+            len,
+            test_str,
+        },
+        rest,
+    ))
+}
+
+/// Merge `cache` into the cached results of `test_extract` at `cache_path`. Since the cache may be
+/// shared by several `LangTester`s in the same test binary, entries for other test files are kept,
+/// unless those files no longer exist. Failing to update the cache is not considered an error,
+/// since it only means that `test_extract` will be called again in a subsequent run.
+fn write_extract_cache(cache_path: &Path, cache: &HashMap<PathBuf, CachedExtract>) {
+    let mut cached = read_extract_cache(cache_path);
+    cached.retain(|p, _| !cache.contains_key(p) && p.exists());
+    let mut entries = cached
+        .iter()
+        .chain(cache.iter())
+        .filter_map(|(p, x)| match p.to_str() {
+            Some(p) if !p.contains('\n') => Some((p, x)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(p, _)| *p);
+    let mut s = String::new();
+    for (p, x) in entries {
+        let since_epoch = match x.modified.duration_since(UNIX_EPOCH) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        match x.test_str {
+            Some(ref test_str) => s.push_str(&format!(
+                "{}\n{} {} {} {}\n{}\n",
+                p,
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos(),
+                x.len,
+                test_str.len(),
+                test_str
+            )),
+            None => s.push_str(&format!(
+                "{}\n{} {} {}\n",
+                p,
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos(),
+                x.len,
+            )),
+        }
+    }
+    // The new cache is written to a temporary file which then replaces the old cache, so that
+    // another process reading the cache never sees it partially written.
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent).ok();
+        if let Ok(mut tmp) = NamedTempFile::new_in(parent) {
+            if tmp.write_all(s.as_bytes()).is_ok() {
+                tmp.persist(cache_path).ok();
+            }
+        }
+    }
+}
+
+/// Return the (sorted) names of tests in `tests` which have no matching command in `cmd_pairs`.
+fn unknown_command_names<T>(
+    cmd_pairs: &[(String, T)],
+    tests: &HashMap<String, TestCmd>,
+) -> Vec<String> {
+    let cmd_names = cmd_pairs.iter().map(|x| &x.0).collect::<HashSet<_>>();
+    let test_names = tests.keys().collect::<HashSet<_>>();
+    let mut diff = test_names
+        .difference(&cmd_names)
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+    diff.sort_unstable();
+    diff
+}
+
+/// Return the names of commands in `cmd_pairs` (in the order they appear there) which have no
+/// tests in `tests`.
+fn missing_command_names<T>(
+    cmd_pairs: &[(String, T)],
+    tests: &HashMap<String, TestCmd>,
+) -> Vec<String> {
+    cmd_pairs
+        .iter()
+        .filter(|(cmd_name, _)| !tests.contains_key(cmd_name))
+        .map(|(cmd_name, _)| cmd_name.clone())
+        .collect()
+}
+
+/// Compile the glob pattern `ptn`, in which `*` and `?` do not match `/`.
+fn compile_glob(ptn: &str) -> Result<GlobMatcher, globset::Error> {
+    Ok(GlobBuilder::new(ptn)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher())
+}
+
+/// The name of the file listing the paths in a directory which are not to be considered tests.
+const IGNORE_FILE: &str = ".lang_tester_ignore";
+
+/// The patterns of the [`IGNORE_FILE`]s found while searching for tests.
+#[derive(Default)]
+struct IgnoreFiles {
+    /// The patterns of each directory's ignore file (empty if the directory has none), each
+    /// paired with a boolean saying whether it is matched against relative paths (`true`) or file
+    /// names (`false`).
+    patterns: HashMap<PathBuf, Vec<(GlobMatcher, bool)>>,
+    /// The first error encountered reading or parsing an ignore file, if any.
+    error: Option<LangTesterError>,
+}
+
+impl IgnoreFiles {
+    /// Is `p` matched by the patterns of the ignore file in any of its ancestor directories up to,
+    /// and including, `root`?
+    fn is_ignored(&mut self, root: &Path, p: &Path) -> bool {
+        if p.file_name().and_then(|x| x.to_str()) == Some(IGNORE_FILE) {
+            return true;
+        }
+        let name = match p.file_name() {
+            Some(x) => x.to_owned(),
+            None => return false,
+        };
+        for dir in p.ancestors().skip(1).take_while(|x| x.starts_with(root)) {
+            let rel = p.strip_prefix(dir).unwrap();
+            for (ptn, is_path) in self.patterns(dir) {
+                if ptn.is_match(if *is_path { rel } else { Path::new(&name) }) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Return the patterns of `dir`'s ignore file, reading it if it has not already been read.
+    fn patterns(&mut self, dir: &Path) -> &[(GlobMatcher, bool)] {
+        if !self.patterns.contains_key(dir) {
+            let ptns = match self.read(dir) {
+                Ok(x) => x,
+                Err(e) => {
+                    self.error.get_or_insert(e);
+                    Vec::new()
+                }
+            };
+            self.patterns.insert(dir.to_owned(), ptns);
+        }
+        &self.patterns[dir]
+    }
+
+    fn read(&self, dir: &Path) -> Result<Vec<(GlobMatcher, bool)>, LangTesterError> {
+        let path = dir.join(IGNORE_FILE);
+        let s = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(LangTesterError::Io { path, err }),
+        };
+        s.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                // As with `.gitignore` files, a trailing `/` does not make a pattern relative.
+                let ptn = l.trim_end_matches('/');
+                compile_glob(ptn.trim_start_matches('/'))
+                    .map(|x| (x, ptn.contains('/')))
+                    .map_err(|e| {
+                        LangTesterError::Config(format!(
+                            "Invalid pattern '{}' in {}: {}",
+                            l,
+                            path.display(),
+                            e
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Run every test in `test_files`, recording each test's outcome in `outcomes`. If an error occurs,
+/// no further tests are started and the (first) error is returned once running tests have
+/// completed.
+fn test_file(
+    test_files: Vec<PathBuf>,
+    inner: Arc<LangTesterPooler>,
+    outcomes: Arc<Mutex<Vec<(String, TestOutcome)>>>,
+) -> Result<(), LangTesterError> {
+    let error = Arc::new(Mutex::new(None));
+    let pool = ThreadPool::new(inner.test_threads);
+    for p in test_files {
+        let test_fname = test_fname(inner.test_dir.as_deref(), &p);
+
+        let outcomes = outcomes.clone();
+        let error = error.clone();
+        let inner = inner.clone();
+        pool.execute(move || {
+            if error.lock().unwrap().is_some() {
+                return;
+            }
+            if inner.suite_deadline_passed() {
+                outcomes
+                    .lock()
+                    .unwrap()
+                    .push((test_fname, TestOutcome::TimedOut));
+                return;
+            }
+            // Files which `test_extract` says are not test files are skipped without being
+            // reported.
+            let test_str = match inner.extract_test_str(&p) {
+                Ok(Some(x)) => Ok(x),
+                Ok(None) => return,
+                Err(e) => Err(e),
+            };
+            let reporter = inner.reporter();
+            reporter.test_started(&test_fname);
+            let start = Instant::now();
+            match test_one_file(Arc::clone(&inner), p, &test_fname, test_str) {
+                Ok(outcome) => {
+                    match outcome {
+                        TestOutcome::Passed { reruns } => {
+                            reporter.test_passed(&test_fname, start.elapsed(), reruns)
+                        }
+                        TestOutcome::Measured { ns_per_iter } => {
+                            reporter.test_measured(&test_fname, ns_per_iter)
+                        }
+                        TestOutcome::Failed(ref failure) => {
+                            reporter.test_failed(&test_fname, failure)
+                        }
+                        TestOutcome::Ignored(ref msg) => reporter.test_ignored(&test_fname, msg),
+                        TestOutcome::Unsupported(ref msg) => {
+                            reporter.test_unsupported(&test_fname, msg)
+                        }
+                        TestOutcome::TimedOut => reporter.test_timed_out(&test_fname),
+                    }
+                    if let Some(ref on_complete) = inner.on_complete {
+                        on_complete(&test_fname, &outcome);
                     }
+                    outcomes.lock().unwrap().push((test_fname, outcome));
+                }
+                Err(e) => {
+                    error.lock().unwrap().get_or_insert(e);
+                }
+            }
+        });
+    }
+    pool.join();
+
+    match Arc::try_unwrap(error).unwrap().into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Run the test in the file `p`, whose test data is `test_str` (or `Err` if `test_extract`
+/// panicked).
+fn test_one_file(
+    inner: Arc<LangTesterPooler>,
+    p: PathBuf,
+    test_fname: &str,
+    test_str: thread::Result<String>,
+) -> Result<TestOutcome, LangTesterError> {
+    let test_str = match test_str {
+        Ok(x) => x,
+        Err(_) => return Ok(TestOutcome::Failed(TestFailure::from_panic("test_extract"))),
+    };
+    if test_str.is_empty() {
+        return Ok(TestOutcome::Ignored("test string is empty".to_owned()));
+    }
+
+    let mut tests = inner
+        .parse_tests(&test_str)
+        .map_err(|e| LangTesterError::Parse {
+            path: p.clone(),
+            line: e.line,
+            msg: e.msg,
+        })?;
+    load_env_files(&p, &mut tests).map_err(|es| {
+        let e = &es[0];
+        LangTesterError::Parse {
+            path: p.clone(),
+            line: e.line,
+            msg: e.msg.clone(),
+        }
+    })?;
+    let ignore_arch = tests
+        .ignore_if_arch
+        .as_ref()
+        .map(|arches| arches.iter().any(|x| x == env::consts::ARCH))
+        .unwrap_or(false);
+    // If ignored tests are to be run alongside normal tests, there is no point in running
+    // `ignore-if`.
+    let ignore = if inner.include_ignored {
+        false
+    } else if ignore_arch {
+        true
+    } else if let Some(ignore_if) = tests.ignore_if {
+        let shell = inner.shell.clone().unwrap_or_else(default_shell);
+        let output = Command::new(&shell[0])
+            .args(&shell[1..])
+            .arg(&ignore_if)
+            .current_dir(env::var("CARGO_MANIFEST_DIR").unwrap())
+            .stdin(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .output()
+            .map_err(|err| LangTesterError::Spawn {
+                test_name: test_fname.to_owned(),
+                cmd: format!("ignore-if '{}'", ignore_if),
+                err,
+            })?;
+        match tests.ignore_unless_stdout {
+            Some(ref pattern) => {
+                let matcher = FMBuilder::new(pattern)
+                    .and_then(|x| x.build())
+                    .map_err(|e| LangTesterError::Command {
+                        test_name: test_fname.to_owned(),
+                        msg: format!("invalid ignore-unless-stdout pattern: {}", e),
+                    })?;
+                matcher
+                    .matches(&String::from_utf8_lossy(&output.stdout))
+                    .is_err()
+            }
+            None => output.status.success(),
+        }
+    } else {
+        false
+    };
+    if !inner.include_ignored && ((inner.ignored && !ignore) || (!inner.ignored && ignore)) {
+        return Ok(TestOutcome::Ignored(String::new()));
+    }
+
+    let tmpdir = if inner.test_tmpdirs {
+        let tmpdir = TempDir::with_prefix("lang_tester").map_err(|err| LangTesterError::Io {
+            path: env::temp_dir(),
+            err,
+        })?;
+        TEST_TMPDIR.with(|x| *x.borrow_mut() = Some(tmpdir.path().to_owned()));
+        Some(tmpdir)
+    } else {
+        None
+    };
+    let cmds = tests.tests;
+    TEST_CUSTOM_DATA.with(|x| {
+        *x.borrow_mut() = cmds
+            .iter()
+            .map(|(name, cmd)| (name.clone(), cmd.custom.clone()))
+            .collect()
+    });
+    let mut outcome = run_test_cmds(&inner, cmds, p.clone(), test_fname);
+    if let Ok(TestOutcome::Failed(ref mut failure)) = outcome {
+        if failure.cmd_name.is_some() {
+            failure.data_offset = test_data_offset(&p, &test_str);
+            failure.path = Some(p);
+        }
+    }
+    TEST_CUSTOM_DATA.with(|x| x.borrow_mut().clear());
+    if let Some(tmpdir) = tmpdir {
+        TEST_TMPDIR.with(|x| *x.borrow_mut() = None);
+        if inner.nocapture {
+            inner.reporter().test_message(
+                test_fname,
+                &format!("temporary directory kept at {}", tmpdir.keep().display()),
+            );
+        } else if let Err(err) = tmpdir.close() {
+            if outcome.is_ok() {
+                outcome = Err(LangTesterError::Command {
+                    test_name: test_fname.to_owned(),
+                    msg: format!("couldn't remove temporary directory: {}", err),
+                });
+            }
+        }
+    }
+    outcome
+}
+
+/// Return the number of lines in the test file `path` which precede the test data `test_str` (as
+/// returned by `test_extract`), or `None` if the test data can't be found in the file (e.g. because
+/// `test_extract` does more than remove a prefix from each line).
+fn test_data_offset(path: &Path, test_str: &str) -> Option<usize> {
+    let src = fs::read_to_string(path).ok()?;
+    let src_lines = src.lines().collect::<Vec<_>>();
+    let data_lines = test_str.lines().collect::<Vec<_>>();
+    if data_lines.is_empty() || data_lines.len() > src_lines.len() {
+        return None;
+    }
+    (0..=src_lines.len() - data_lines.len()).find(|&off| {
+        data_lines
+            .iter()
+            .zip(&src_lines[off..])
+            .all(|(d, s)| s.trim_end().ends_with(d.trim()))
+    })
+}
+
+/// Run `before_each`, the commands in `tests`, and `after_each` for the test file `p`.
+fn run_test_cmds(
+    inner: &Arc<LangTesterPooler>,
+    tests: HashMap<String, TestCmd>,
+    p: PathBuf,
+    test_fname: &str,
+) -> Result<TestOutcome, LangTesterError> {
+    if let Some(ref before_each) = inner.before_each {
+        if catch_unwind(|| before_each(p.as_path())).is_err() {
+            return Ok(TestOutcome::Failed(TestFailure::from_panic("before_each")));
+        }
+    }
+    let outcome = run_tests(Arc::clone(inner), tests, p.clone(), test_fname);
+    if let Some(ref after_each) = inner.after_each {
+        if catch_unwind(|| after_each(p.as_path())).is_err() {
+            // If the test itself failed, we don't want to lose the reason why; and if it was not
+            // run (e.g. because it was ignored), there is nothing for it to have failed.
+            if let Ok(TestOutcome::Passed { .. }) | Ok(TestOutcome::Measured { .. }) = outcome {
+                return Ok(TestOutcome::Failed(TestFailure::from_panic("after_each")));
+            }
+        }
+    }
+    outcome
+}
+
+/// Convert a test file name to a user-friendly test name (e.g. "lang_tests/a/b.x" might become
+/// "a::b.x"). If the test file is not within `test_dir_path`, its file name is used.
+fn test_fname(test_dir_path: Option<&Path>, test_fpath: &Path) -> String {
+    if let Some(test_fpath) = test_fpath.as_os_str().to_str() {
+        if let Some(testdir_path) = test_dir_path.and_then(|x| x.as_os_str().to_str()) {
+            if test_fpath.starts_with(testdir_path) {
+                return test_fpath[testdir_path.len() + MAIN_SEPARATOR.len_utf8()..]
+                    .to_owned()
+                    .replace(MAIN_SEPARATOR, "::");
+            }
+        }
+    }
+
+    test_fpath.file_name().unwrap().to_str().unwrap().to_owned()
+}
+
+/// If `path` is `~` or starts with `~/`, replace the `~` with the user's home directory (if it is
+/// known).
+fn expand_tilde(path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix('~') {
+        if rest.is_empty() || rest.starts_with('/') {
+            if let Some(home) = env::var_os("HOME") {
+                return PathBuf::from(home).join(rest.trim_start_matches('/'));
+            }
+        }
+    }
+    PathBuf::from(path)
+}
+
+/// The shell (and its arguments) used to run `ignore-if` commands if the user has not specified
+/// one.
+fn default_shell() -> Vec<String> {
+    if cfg!(windows) {
+        vec!["cmd".to_owned(), "/C".to_owned()]
+    } else {
+        vec![
+            env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned()),
+            "-c".to_owned(),
+        ]
+    }
+}
+
+/// Return a new `Command` which runs `cmd` under the program (and arguments) `wrapper`.
+fn wrap_cmd(wrapper: &[String], cmd: &Command) -> Command {
+    let mut wrapped = Command::new(&wrapper[0]);
+    wrapped
+        .args(&wrapper[1..])
+        .arg(cmd.get_program())
+        .args(cmd.get_args());
+    for (key, val) in cmd.get_envs() {
+        match val {
+            Some(val) => wrapped.env(key, val),
+            None => wrapped.env_remove(key),
+        };
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        wrapped.current_dir(dir);
+    }
+    wrapped
+}
+
+/// The extensions of the artifact files written for each command.
+const ARTIFACT_EXTS: [&str; 3] = ["status", "stderr", "stdout"];
+
+/// Return the directory within `artifact_dir` (mirroring the test's name) in which the artifacts of
+/// the command `cmd_name` for the test `test_fname` are stored, and the artifacts' common file name
+/// prefix.
+fn artifact_base(artifact_dir: &Path, test_fname: &str, cmd_name: &str) -> (PathBuf, String) {
+    let mut path = artifact_dir.to_owned();
+    path.extend(test_fname.split("::"));
+    let base = format!(
+        "{}.{}",
+        path.file_name().unwrap().to_string_lossy(),
+        cmd_name
+    );
+    (path.parent().unwrap().to_owned(), base)
+}
+
+/// Write the `status`, `stderr`, and `stdout` of the command `cmd_name` for the test `test_fname`
+/// to `artifact_dir`, in a directory hierarchy mirroring the test's name.
+fn write_artifacts(
+    artifact_dir: &Path,
+    test_fname: &str,
+    cmd_name: &str,
+    status: &ExitStatus,
+    stderr: &str,
+    stdout: &str,
+) -> Result<(), LangTesterError> {
+    let (dir, base) = artifact_base(artifact_dir, test_fname, cmd_name);
+    fs::create_dir_all(&dir).map_err(|err| LangTesterError::Io {
+        path: dir.clone(),
+        err,
+    })?;
+    for (ext, contents) in ARTIFACT_EXTS.iter().zip([
+        format!("{}\n", status),
+        stderr.to_owned(),
+        stdout.to_owned(),
+    ]) {
+        let p = dir.join(format!("{}.{}", base, ext));
+        fs::write(&p, contents).map_err(|err| LangTesterError::Io { path: p, err })?;
+    }
+    Ok(())
+}
+
+/// Remove any artifacts of the command `cmd_name` for the test `test_fname` from `artifact_dir`
+/// (e.g. those left over from a previous failing run).
+fn remove_artifacts(
+    artifact_dir: &Path,
+    test_fname: &str,
+    cmd_name: &str,
+) -> Result<(), LangTesterError> {
+    let (dir, base) = artifact_base(artifact_dir, test_fname, cmd_name);
+    for ext in ARTIFACT_EXTS {
+        let p = dir.join(format!("{}.{}", base, ext));
+        match fs::remove_file(&p) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(LangTesterError::Io { path: p, err })
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Format `cmd` as a shell command line (including its working directory and environment variable
+/// overrides, if any) which can be copied and pasted to run it by hand.
+fn fmt_cmd(cmd: &Command) -> String {
+    let mut words = Vec::new();
+    if let Some(dir) = cmd.get_current_dir() {
+        words.push("cd".to_owned());
+        words.push(shell_quote(&dir.to_string_lossy()));
+        words.push("&&".to_owned());
+    }
+    let mut envs = cmd.get_envs().collect::<Vec<_>>();
+    if !envs.is_empty() {
+        envs.sort();
+        words.push("env".to_owned());
+        for (key, val) in envs {
+            match val {
+                Some(val) => words.push(shell_quote(&format!(
+                    "{}={}",
+                    key.to_string_lossy(),
+                    val.to_string_lossy()
+                ))),
+                None => {
+                    words.push("-u".to_owned());
+                    words.push(shell_quote(&key.to_string_lossy()));
+                }
+            }
+        }
+    }
+    words.push(shell_quote(&cmd.get_program().to_string_lossy()));
+    words.extend(cmd.get_args().map(|x| shell_quote(&x.to_string_lossy())));
+    words.join(" ")
+}
+
+/// Quote `s` so that a POSIX shell treats it as a single word.
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(c))
+    {
+        s.to_owned()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Run the tests for `path`.
+fn run_tests(
+    inner: Arc<LangTesterPooler>,
+    tests: HashMap<String, TestCmd>,
+    path: PathBuf,
+    test_fname: &str,
+) -> Result<TestOutcome, LangTesterError> {
+    if !cfg!(unix)
+        && tests.values().any(|t| {
+            t.status
+                .as_ref()
+                .unwrap_or(&inner.default_status)
+                .uses_signals()
+        })
+    {
+        return Ok(TestOutcome::Unsupported(
+            "signal termination not supported on this platform".to_owned(),
+        ));
+    }
+
+    let mut failure;
+    // The total number of times any command was rerun.
+    let mut reruns = 0;
+    // The number of times the whole sequence of commands was rerun.
+    let mut file_reruns = 0;
+    let mut bench_ns: Option<u128>;
+
+    'file: loop {
+        failure = TestFailure::default();
+        // The total of the average time taken by each command with `bench: true`, in nanoseconds.
+        bench_ns = None;
+        let test_cmds = inner.test_cmds.as_ref().unwrap();
+        let cmd_pairs = match catch_unwind(|| test_cmds(path.as_path())) {
+            Ok(x) => x
+                .into_iter()
+                .map(|(test_name, cmd)| (test_name.to_lowercase(), cmd))
+                .collect::<Vec<_>>(),
+            Err(_) => return Ok(TestOutcome::Failed(TestFailure::from_panic("test_cmds"))),
+        };
+        check_names(&inner, test_fname, &cmd_pairs, &tests)?;
+        if inner.require_all_commands {
+            failure.missing_commands = missing_command_names(&cmd_pairs, &tests);
+            if !failure.missing_commands.is_empty() {
+                return Ok(TestOutcome::Failed(failure));
+            }
+        }
+
+        // The exit status, stderr, and stdout of the previous command run, if any.
+        let mut prev: Option<(ExitStatus, String, String)> = None;
+        let mut cmd_pairs = cmd_pairs.into_iter();
+        // Set to true if a command failed as expected and the remaining commands were skipped.
+        let mut skipped_remaining = false;
+        'a: for (cmd_name, cmd) in cmd_pairs.by_ref() {
+            let mut test = tests
+                .get(&cmd_name)
+                .cloned()
+                .unwrap_or_else(TestCmd::default);
+            if test.stdin.is_none() {
+                test.stdin = inner.default_stdin.get(&cmd_name).cloned();
+            }
+            let expected_status = test.status.as_ref().unwrap_or(&inner.default_status);
+            let test = &test;
+            failure.cmd_name = Some(cmd_name.clone());
+            failure.cmd_line = test.line;
+            failure.key_lines = test.key_lines.clone();
+            let mut cmd = match catch_unwind(AssertUnwindSafe(cmd)) {
+                Ok(cmd) => match inner.command_wrapper {
+                    Some(ref wrapper) => wrap_cmd(wrapper, &cmd),
+                    None => cmd,
+                },
+                Err(_) => {
+                    failure.panicked = Some("test_cmds".to_owned());
+                    return Ok(TestOutcome::Failed(failure));
+                }
+            };
+            cmd.args(&test.args);
+            cmd.env(TEST_NAME_ENV_VAR, test_fname);
+            cmd.env(TEST_PATH_ENV_VAR, &path);
+            if let Some(tmpdir) = test_tmpdir() {
+                cmd.env(TMPDIR_ENV_VAR, tmpdir);
+            }
+            cmd.envs(test.env_file_vars.iter().cloned());
+            for (key, val) in &test.env {
+                match val {
+                    Some(val) => cmd.env(key, val),
+                    None => cmd.env_remove(key),
+                };
+            }
+            if let Some(max_rss) = test.max_rss {
+                // Note that the closure runs in the child after `fork`, so it must only call
+                // async-signal-safe functions.
+                unsafe {
+                    cmd.pre_exec(move || limit_memory(max_rss));
+                }
+            }
+            if let Some(nice) = inner.child_nice {
+                // As with `limit_memory`, `set_nice` only calls async-signal-safe functions.
+                unsafe {
+                    cmd.pre_exec(move || set_nice(nice));
                 }
             }
-
-            let match_stderr = match stderr_fmb.build() {
-                Ok(x) => x.matches(&stderr),
-                Err(e) => {
-                    failure.stderr = Some(format!("FM error: {}", e));
-                    break 'a;
+            if let (Some(between_cmds), Some((status, stderr, stdout))) =
+                (inner.between_cmds.as_ref(), prev.as_ref())
+            {
+                if catch_unwind(AssertUnwindSafe(|| {
+                    between_cmds(&path, &cmd_name, &mut cmd, status, stderr, stdout)
+                }))
+                .is_err()
+                {
+                    failure.panicked = Some("between_cmds".to_owned());
+                    return Ok(TestOutcome::Failed(failure));
                 }
-            };
-            let match_stdout = match stdout_fmb.build() {
-                Ok(x) => x.matches(&stdout),
-                Err(e) => {
-                    failure.stdout = Some(format!("FM error: {}", e));
-                    break 'a;
+            }
+            if inner.dry_run {
+                inner
+                    .reporter()
+                    .test_message(test_fname, &format!("{}: {}", cmd_name, fmt_cmd(&cmd)));
+                continue;
+            }
+            let mut rerun = 0;
+            let mut repeat = 1;
+            loop {
+                rerun += 1;
+                if rerun > 1 {
+                    reruns += 1;
                 }
-            };
+                let cmd_start = Instant::now();
+                let CmdResult {
+                    status,
+                    stdin_remaining,
+                    stderr,
+                    stdout,
+                    output_exceeded,
+                    peak_rss,
+                    suite_timed_out,
+                } = run_cmd(inner.clone(), test_fname, &cmd_name, &mut cmd, test)?;
+                let cmd_elapsed = cmd_start.elapsed();
+                if suite_timed_out {
+                    return Ok(TestOutcome::TimedOut);
+                }
+                let stderr = remove_lines(test.stderr_ignore.as_ref(), stderr);
+                let stdout = remove_lines(test.stdout_ignore.as_ref(), stdout);
 
-            // First, check whether the tests passed.
-            let pass_status = match test.status {
-                Status::Success => status.success(),
-                Status::Error => {
-                    meant_to_error = true;
-                    !status.success()
+                let mut meant_to_error = false;
+
+                // Give the user the option of setting options for the fuzzy matchers.
+                let stderr_str = subst_placeholders(&test.stderr.join("\n"), &path);
+                let mut stderr_fmb = inner.fm_builder(&stderr_str);
+                let stdout_str = subst_placeholders(&test.stdout.join("\n"), &path);
+                let mut stdout_fmb = inner.fm_builder(&stdout_str);
+
+                let rerun_if_stderr_str =
+                    test.rerun_if_stderr.as_ref().unwrap_or(&vec![]).join("\n");
+                let mut rerun_if_stderr_fmb = inner.fm_builder(&rerun_if_stderr_str);
+                let rerun_if_stdout_str =
+                    test.rerun_if_stdout.as_ref().unwrap_or(&vec![]).join("\n");
+                let mut rerun_if_stdout_fmb = inner.fm_builder(&rerun_if_stdout_str);
+                if let Some(ref fm_options) = inner.fm_options {
+                    match catch_unwind(|| {
+                        (
+                            fm_options(path.as_path(), TestStream::Stderr, stderr_fmb),
+                            fm_options(path.as_path(), TestStream::Stdout, stdout_fmb),
+                        )
+                    }) {
+                        Ok((a, b)) => {
+                            stderr_fmb = a;
+                            stdout_fmb = b;
+                        }
+                        Err(_) => {
+                            failure.panicked = Some("fm_options".to_owned());
+                            return Ok(TestOutcome::Failed(failure));
+                        }
+                    }
+                }
+                if let Some(rerun_fm_options) = inner
+                    .rerun_fm_options
+                    .as_ref()
+                    .or(inner.fm_options.as_ref())
+                {
+                    match catch_unwind(|| {
+                        (
+                            rerun_fm_options(
+                                path.as_path(),
+                                TestStream::Stderr,
+                                rerun_if_stderr_fmb,
+                            ),
+                            rerun_fm_options(
+                                path.as_path(),
+                                TestStream::Stdout,
+                                rerun_if_stdout_fmb,
+                            ),
+                        )
+                    }) {
+                        Ok((a, b)) => {
+                            rerun_if_stderr_fmb = a;
+                            rerun_if_stdout_fmb = b;
+                        }
+                        Err(_) => {
+                            failure.panicked = Some("fm_options".to_owned());
+                            return Ok(TestOutcome::Failed(failure));
+                        }
+                    }
                 }
-                Status::Signal => status.signal().is_some(),
-                Status::Int(i) => status.code() == Some(i),
-            };
 
-            // Second, if a test failed, we want to print out everything which didn't match
-            // successfully (i.e. if the stderr test failed, print that out; but, equally, if
-            // stderr wasn't specified as a test, print it out, because the user can't
-            // otherwise know what it contains).
-            if !(pass_status
-                && stdin_remaining == 0
-                && match_stderr.is_ok()
-                && match_stdout.is_ok())
-            {
-                if rerun <= inner.rerun_at_most {
-                    if let Some(rerun_if_status) = &test.rerun_if_status {
-                        let rerun = match rerun_if_status {
-                            Status::Success => status.success(),
-                            Status::Error => !status.success(),
-                            Status::Signal => status.signal().is_some(),
-                            Status::Int(i) => status.code() == Some(*i),
+                let (match_stderr, match_stdout) = match inner.matcher {
+                    Some(ref matcher) => {
+                        // A test which doesn't specify a stream's output implicitly expects `...`,
+                        // which matches anything: there is then nothing for the matcher to check.
+                        let run_matcher = |stream, ptn: &[Cow<str>], expected: &str, text: &str| {
+                            if ptn == ["..."] {
+                                return Ok(());
+                            }
+                            catch_unwind(|| matcher(path.as_path(), stream, expected, text))
+                                .unwrap_or_else(|_| Err("matcher panicked".to_owned()))
                         };
-                        if rerun {
-                            continue;
-                        }
+                        (
+                            run_matcher(TestStream::Stderr, &test.stderr, &stderr_str, &stderr),
+                            run_matcher(TestStream::Stdout, &test.stdout, &stdout_str, &stdout),
+                        )
                     }
-                    if test.rerun_if_stderr.is_some() {
-                        match rerun_if_stderr_fmb.build() {
-                            Ok(x) if x.matches(&stderr).is_ok() => continue,
-                            Ok(_) => {}
+                    None => {
+                        let match_stderr = match stderr_fmb.build() {
+                            Ok(x) => x.matches(&stderr).map_err(|e| {
+                                fuzzy_match_msg(&inner, e.to_string(), &stderr_str, &stderr)
+                            }),
                             Err(e) => {
                                 failure.stderr = Some(format!("FM error: {}", e));
                                 break 'a;
                             }
-                        }
-                    }
-                    if test.rerun_if_stdout.is_some() {
-                        match rerun_if_stdout_fmb.build() {
-                            Ok(x) if x.matches(&stdout).is_ok() => continue,
-                            Ok(_) => {}
+                        };
+                        let match_stdout = match stdout_fmb.build() {
+                            Ok(x) => x.matches(&stdout).map_err(|e| {
+                                fuzzy_match_msg(&inner, e.to_string(), &stdout_str, &stdout)
+                            }),
                             Err(e) => {
                                 failure.stdout = Some(format!("FM error: {}", e));
                                 break 'a;
                             }
-                        }
+                        };
+                        (match_stderr, match_stdout)
+                    }
+                };
+
+                let stderr_exact = test
+                    .stderr_exact
+                    .as_ref()
+                    .map(|x| subst_placeholders(x, &path));
+                let stdout_exact = test
+                    .stdout_exact
+                    .as_ref()
+                    .map(|x| subst_placeholders(x, &path));
+                let match_stderr = combine_matches(
+                    inner.report_all_failures,
+                    vec![
+                        match_stderr,
+                        match_regex(test.stderr_regex.as_ref(), &stderr),
+                        match_exact(stderr_exact.as_deref(), &stderr),
+                        match_trailing_newline(test.trailing_newline, &stderr),
+                        match_file(
+                            &inner,
+                            &path,
+                            TestStream::Stderr,
+                            test.stderr_matches_file.as_deref(),
+                            &stderr,
+                        ),
+                    ],
+                );
+                let match_stdout = combine_matches(
+                    inner.report_all_failures,
+                    vec![
+                        match_stdout,
+                        match_regex(test.stdout_regex.as_ref(), &stdout),
+                        match_exact(stdout_exact.as_deref(), &stdout),
+                        match_trailing_newline(test.trailing_newline, &stdout),
+                        match_file(
+                            &inner,
+                            &path,
+                            TestStream::Stdout,
+                            test.stdout_matches_file.as_deref(),
+                            &stdout,
+                        ),
+                    ],
+                );
+
+                // First, check whether the tests passed.
+                if *expected_status == Status::Error {
+                    meant_to_error = true;
+                }
+                let pass_status = expected_status.matches(&status);
+                let pass_stdin = match test.stdin_remaining {
+                    Some(x) => x == stdin_remaining,
+                    None => true,
+                };
+                let peak_rss_exceeded = match (peak_rss, test.max_peak_rss) {
+                    (Some(peak), Some(max)) if peak > max => Some((peak, max)),
+                    _ => None,
+                };
+
+                let passed = pass_status
+                    && pass_stdin
+                    && match_stderr.is_ok()
+                    && match_stdout.is_ok()
+                    && !output_exceeded
+                    && peak_rss_exceeded.is_none();
+
+                if let Some(ref artifact_dir) = inner.artifact_dir {
+                    if inner.all_artifacts || !passed {
+                        write_artifacts(
+                            artifact_dir,
+                            test_fname,
+                            &cmd_name,
+                            &status,
+                            &stderr,
+                            &stdout,
+                        )?;
+                    } else {
+                        remove_artifacts(artifact_dir, test_fname, &cmd_name)?;
                     }
                 }
 
-                match test.status {
-                    Status::Success | Status::Error => {
-                        if status.success() {
-                            failure.status = Some("Success".to_owned());
-                        } else if status.code().is_none() {
-                            failure.status = Some(format!(
-                                "Exited due to signal: {}",
-                                status.signal().unwrap()
-                            ));
-                        } else {
-                            failure.status = Some("Error".to_owned());
+                // Second, if a test failed, we want to print out everything which didn't match
+                // successfully (i.e. if the stderr test failed, print that out; but, equally, if
+                // stderr wasn't specified as a test, print it out, because the user can't
+                // otherwise know what it contains).
+                if !passed {
+                    // A file-scoped rerun restarts the whole sequence of commands, so it is limited
+                    // by the number of times that sequence has been restarted.
+                    let rerun_at_most = test.rerun_at_most.unwrap_or(inner.rerun_at_most);
+                    let can_rerun = if test.rerun_scope_file {
+                        file_reruns < rerun_at_most
+                    } else {
+                        rerun <= rerun_at_most
+                    };
+                    // If the output was too big, the command was killed, so its status (etc.) can't
+                    // be meaningfully used to decide whether to rerun it.
+                    if can_rerun && !output_exceeded {
+                        let mut rerun_matched =
+                            test.rerun_if_status.as_ref().map(|x| x.matches(&status)) == Some(true)
+                                || (test.rerun_if_signal.is_some()
+                                    && status.signal() == test.rerun_if_signal);
+                        if !rerun_matched && test.rerun_if_stderr.is_some() {
+                            match rerun_if_stderr_fmb.build() {
+                                Ok(x) => rerun_matched = x.matches(&stderr).is_ok(),
+                                Err(e) => {
+                                    failure.stderr = Some(format!("FM error: {}", e));
+                                    break 'a;
+                                }
+                            }
+                        }
+                        if !rerun_matched && test.rerun_if_stdout.is_some() {
+                            match rerun_if_stdout_fmb.build() {
+                                Ok(x) => rerun_matched = x.matches(&stdout).is_ok(),
+                                Err(e) => {
+                                    failure.stdout = Some(format!("FM error: {}", e));
+                                    break 'a;
+                                }
+                            }
+                        }
+                        if rerun_matched {
+                            if test.rerun_scope_file {
+                                file_reruns += 1;
+                                reruns += 1;
+                                continue 'file;
+                            }
+                            continue;
                         }
                     }
-                    Status::Signal => {
-                        failure.status = Some("Exit was not due to signal".to_owned());
+
+                    if !pass_status {
+                        failure.expected_status = Some(expected_status.clone());
+                    }
+                    failure.status = Some(status_failure(expected_status, &status));
+
+                    if match_stderr.is_err() || failure.stderr.is_none() {
+                        failure.stderr = Some(stderr);
                     }
-                    Status::Int(_) => {
-                        failure.status =
-                            Some(status.code().map(|x| x.to_string()).unwrap_or_else(|| {
-                                format!("Exited due to signal: {}", status.signal().unwrap())
-                            }))
+                    if let Err(e) = match_stderr {
+                        failure.stderr_match = Some(e);
                     }
-                }
 
-                if match_stderr.is_err() || failure.stderr.is_none() {
-                    failure.stderr = Some(stderr);
-                }
-                if let Err(e) = match_stderr {
-                    failure.stderr_match = Some(e);
-                }
+                    if match_stdout.is_err() || failure.stdout.is_none() {
+                        failure.stdout = Some(stdout);
+                    }
+                    if let Err(e) = match_stdout {
+                        failure.stdout_match = Some(e);
+                    }
 
-                if match_stdout.is_err() || failure.stdout.is_none() {
-                    failure.stdout = Some(stdout);
-                }
-                if let Err(e) = match_stdout {
-                    failure.stdout_match = Some(e);
+                    if !pass_stdin {
+                        failure.stdin_remaining = stdin_remaining;
+                        failure.expected_stdin_remaining = test.stdin_remaining;
+                    }
+                    if output_exceeded {
+                        failure.output_exceeded = inner.max_output_bytes;
+                    }
+                    failure.peak_rss_exceeded = peak_rss_exceeded;
+                    if test.repeat > 1 {
+                        failure.failed_repeat = Some((repeat, test.repeat));
+                    }
+
+                    // If a sub-test failed, bail out immediately, otherwise subsequent sub-tests
+                    // will overwrite the failure output!
+                    break 'a;
                 }
 
-                failure.stdin_remaining = stdin_remaining;
+                if test.bench {
+                    *bench_ns.get_or_insert(0) += cmd_elapsed.as_nanos() / u128::from(test.repeat);
+                }
 
-                // If a sub-test failed, bail out immediately, otherwise subsequent sub-tests
-                // will overwrite the failure output!
-                break 'a;
-            }
+                if repeat < test.repeat {
+                    repeat += 1;
+                    rerun = 0;
+                    continue;
+                }
 
-            // If a command failed, and we weren't expecting it to, bail out immediately.
-            if !status.success() && meant_to_error {
-                break 'a;
+                // If a command failed, and we were expecting it to, subsequent commands (e.g.
+                // running a program which failed to compile) are skipped.
+                if !status.success() && test.skip_remaining_on_failure.unwrap_or(meant_to_error) {
+                    skipped_remaining = true;
+                    break 'a;
+                }
+                prev = Some((status, stderr, stdout));
+                break;
             }
-            break;
         }
-    }
-
-    {
-        // Grab a lock on stderr so that we can avoid the possibility of lines blurring
-        // together in confusing ways.
-        let stderr = StandardStream::stderr(ColorChoice::Always);
-        let mut handle = stderr.lock();
-        if inner.test_threads > 1 {
-            handle
-                .write_all(format!("\ntest lang_tests::{} ... ", test_fname).as_bytes())
-                .ok();
-        }
-        match failure {
-            TestFailure {
-                status: None,
-                stdin_remaining: 0,
-                stderr: None,
-                stderr_match: None,
-                stdout: None,
-                stdout_match: None,
-            } => {
-                handle
-                    .set_color(ColorSpec::new().set_fg(Some(Color::Green)))
-                    .ok();
-                handle.write_all(b"ok").ok();
+        let skipped = cmd_pairs.map(|(cmd_name, _)| cmd_name).collect::<Vec<_>>();
+        if inner.verbose {
+            for cmd_name in &skipped {
+                inner
+                    .reporter()
+                    .test_message(test_fname, &format!("{}: skipped", cmd_name));
             }
-            _ => {
-                let mut failures = failures.lock().unwrap();
-                failures.push((test_fname, failure));
-                handle
-                    .set_color(ColorSpec::new().set_fg(Some(Color::Red)))
-                    .ok();
-                handle.write_all(b"FAILED").ok();
+        }
+        // Tests specified for commands which were skipped can never be checked, which almost
+        // certainly means that the test file is not doing what its author intended.
+        if skipped_remaining {
+            failure.not_reached = skipped
+                .into_iter()
+                .filter(|x| tests.contains_key(x))
+                .collect();
+            if let Some(cmd_name) = failure.not_reached.first() {
+                failure.cmd_line = tests[cmd_name].line;
+                failure.key_lines = tests[cmd_name].key_lines.clone();
+                failure.cmd_name = Some(cmd_name.clone());
             }
         }
-        handle.reset().ok();
+        break;
     }
 
-    false
+    if inner.dry_run {
+        return Ok(TestOutcome::Ignored("dry run".to_owned()));
+    }
+    match failure {
+        TestFailure {
+            expected_status: None,
+            status: None,
+            stdin_remaining: 0,
+            stderr: None,
+            stderr_match: None,
+            stdout: None,
+            stdout_match: None,
+            output_exceeded: None,
+            peak_rss_exceeded: None,
+            failed_repeat: None,
+            ref not_reached,
+            ..
+        } if not_reached.is_empty() => match bench_ns {
+            Some(ns) => Ok(TestOutcome::Measured {
+                ns_per_iter: u64::try_from(ns).unwrap_or(u64::MAX),
+            }),
+            None => Ok(TestOutcome::Passed { reruns }),
+        },
+        _ => Ok(TestOutcome::Failed(failure)),
+    }
 }
 
 fn run_cmd(
     inner: Arc<LangTesterPooler>,
     test_fname: &str,
+    cmd_name: &str,
     cmd: &mut Command,
     test: &TestCmd,
-) -> (ExitStatus, usize, String, String) {
+) -> Result<CmdResult, LangTesterError> {
     // The basic sequence here is:
     //   1) Spawn the command
     //   2) Read everything from stderr & stdout until they are both disconnected
     //   3) wait() for the command to finish
 
-    let mut child = cmd
-        .stdin(process::Stdio::piped())
-        .stderr(process::Stdio::piped())
-        .stdout(process::Stdio::piped())
-        .spawn()
-        .unwrap_or_else(|_| fatal(&format!("Couldn't run command {:?}.", cmd)));
-
-    let mut stdin = child.stdin.take().unwrap();
-    let mut stderr = child.stderr.take().unwrap();
-    let mut stdout = child.stdout.take().unwrap();
-
-    let stdin_fd = stdin.as_raw_fd();
-    let stderr_fd = stderr.as_raw_fd();
-    let stdout_fd = stdout.as_raw_fd();
-    if let Err(e) = set_nonblock(stdin_fd)
+    // If the number of simultaneous processes is limited, wait until we're allowed to run. The
+    // permit is only released when this function returns, at which point the child has exited.
+    let _permit = inner.max_processes.as_ref().map(|x| x.acquire());
+
+    if inner.verbose {
+        inner
+            .reporter()
+            .test_message(test_fname, &format!("{}: {}", cmd_name, fmt_cmd(cmd)));
+    }
+
+    // If the command is to be run in a pseudo-terminal, this is set to (a reading handle, a
+    // writing handle) for the terminal's master side.
+    let mut pty_master = None;
+    let spawned = {
+        // We hold the lock while spawning so that, if we are interrupted, we can't miss a child
+        // which has been spawned but not yet recorded. Since other threads only spawn children
+        // while holding this lock, this also means that the pseudo-terminal's file descriptors
+        // can't leak into other children before they have been marked as close-on-exec.
+        let mut pgids = CHILD_PGIDS.lock().unwrap();
+        let spawned = if test.pty {
+            open_pty().and_then(|(master_r, master_w, slave)| {
+                cmd.stdin(slave.try_clone()?).stdout(slave);
+                pty_master = Some((master_r, master_w));
+                Ok(())
+            })
+        } else {
+            cmd.stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped());
+            Ok(())
+        }
+        .and_then(|_| cmd.stderr(process::Stdio::piped()).process_group(0).spawn());
+        if test.pty {
+            // `cmd` owns our handles to the pseudo-terminal's slave side: unless we close them,
+            // we won't see EOF on the master side once the child has exited.
+            cmd.stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped());
+        }
+        if let Ok(ref child) = spawned {
+            pgids.push(child.id() as pid_t);
+        }
+        spawned
+    };
+    let mut child = spawned.map_err(|err| LangTesterError::Spawn {
+        test_name: test_fname.to_owned(),
+        cmd: format!("{:?}", cmd),
+        err,
+    })?;
+    let pgid = child.id() as pid_t;
+
+    // We own the child's stdin/stderr/stdout: each is closed (exactly once) by setting it to
+    // `None`. While the streams are being polled, they are closed with `close_polled`.
+    let (mut stdin, mut stdout) = match pty_master {
+        Some((master_r, master_w)) => (Some(master_w), Some(master_r)),
+        None => (
+            child.stdin.take().map(|x| File::from(OwnedFd::from(x))),
+            child.stdout.take().map(|x| File::from(OwnedFd::from(x))),
+        ),
+    };
+    let mut stderr = child.stderr.take();
+
+    let stdin_fd = stdin.as_ref().unwrap().as_raw_fd();
+    let stderr_fd = stderr.as_ref().unwrap().as_raw_fd();
+    let stdout_fd = stdout.as_ref().unwrap().as_raw_fd();
+    // If an error occurs while communicating with the child, we record it here, kill the child,
+    // and return the error once the child has been reaped.
+    let mut res = set_nonblock(stdin_fd)
         .and_then(|_| set_nonblock(stderr_fd))
         .and_then(|_| set_nonblock(stdout_fd))
-    {
-        fatal(&format!(
-            "Couldn't set stdin and/or stderr and/or stdout to be non-blocking: {e:}"
-        ));
-    }
+        .map_err(|e| LangTesterError::Command {
+            test_name: test_fname.to_owned(),
+            msg: format!("Couldn't set stdin and/or stderr and/or stdout to be non-blocking: {e:}"),
+        });
 
     const POLL_STDIN: usize = 0;
     const POLL_STDERR: usize = 1;
     const POLL_STDOUT: usize = 2;
 
-    let mut cap_stderr = String::new();
-    let mut cap_stdout = String::new();
+    let capacity = min(
+        inner.output_capacity,
+        inner.max_output_bytes.unwrap_or(usize::MAX),
+    );
+    let mut cap_stderr = String::with_capacity(capacity);
+    let mut cap_stdout = String::with_capacity(capacity);
+    // A read may end part way through a multi-byte UTF-8 character: these hold the bytes of such
+    // a character until the rest of it has been read.
+    let mut stderr_partial = Vec::new();
+    let mut stdout_partial = Vec::new();
+    let mut output_exceeded = false;
+    let mut suite_timed_out = false;
     let mut stdin_off = 0;
     let mut buf = [0; READBUF];
     let start = Instant::now();
-    let mut last_warning = Instant::now();
-    let mut next_warning = last_warning
-        .checked_add(Duration::from_secs(TIMEOUT))
-        .unwrap();
+    let mut next_warning = inner.progress_interval.map(|x| start + x);
 
     // Has this file reached EOF and thus been closed?
     const STATUS_EOF: u8 = 1;
-    // Has this file hit an error and thus been closed? Note that EOF and ERR
-    // are mutually exclusive.
+    // Has this file hit an error (including its file descriptor having become invalid) and thus
+    // been closed? Note that EOF and ERR are mutually exclusive.
     const STATUS_ERR: u8 = 2;
 
-    let mut statuses: [u8; 3] = [0, 0, 0];
-    if test.stdin.is_none() {
-        unsafe {
-            close(stdin_fd);
+    // What we write to the child's stdin. Input to a pseudo-terminal is line based, so we
+    // terminate the last line with a newline (as a user pressing "enter" would) if necessary. The
+    // slave side then only sees EOF when the EOF character is written at the start of a line, so
+    // we add one of those too.
+    let stdin_bytes = if test.pty {
+        let mut bytes = test.stdin.as_deref().unwrap_or("").as_bytes().to_owned();
+        if !bytes.is_empty() && !bytes.ends_with(b"\n") {
+            bytes.push(b'\n');
         }
+        bytes.push(PTY_EOF);
+        Some(Cow::Owned(bytes))
+    } else {
+        test.stdin.as_ref().map(|x| Cow::Borrowed(x.as_bytes()))
+    };
+
+    let mut statuses: [u8; 3] = [0, 0, 0];
+    if stdin_bytes.is_none() {
+        stdin = None;
         statuses[POLL_STDIN] = STATUS_EOF;
     }
-    loop {
-        // Are all files successfully closed?
-        if statuses[POLL_STDIN] == STATUS_EOF
-            && statuses[POLL_STDERR] == STATUS_EOF
-            && statuses[POLL_STDOUT] == STATUS_EOF
-        {
-            // If there's still stuff in the buffer to write out, we've failed.
-            if let Some(stdin_str) = &test.stdin {
-                if stdin_off < stdin_str.len() {
-                    fatal(&format!("{} failed to consume all of stdin", test_fname));
-                }
+    // Streams are polled in level-triggered mode: as with `poll`, a stream is reported as ready for
+    // as long as it remains so.
+    let poller = Poller::new().and_then(|poller| {
+        // Each stream is removed from `poller` by `close_polled` before it is closed.
+        unsafe {
+            if let Some(x) = &stdin {
+                poller.add_with_mode(x, Event::writable(POLL_STDIN), PollMode::Level)?;
             }
-            break;
-        }
-
-        // Is at least one file in an error state and the other files are
-        // closed?
-        if statuses[POLL_STDIN] & (STATUS_EOF | STATUS_ERR) != 0
-            && statuses[POLL_STDERR] & (STATUS_EOF | STATUS_ERR) != 0
-            && statuses[POLL_STDOUT] & (STATUS_EOF | STATUS_ERR) != 0
-        {
-            fatal(&format!(
-                "{} has left one of stdin/stderr/stdout in an error condition",
-                test_fname
-            ));
+            poller.add_with_mode(
+                stderr.as_ref().unwrap(),
+                Event::readable(POLL_STDERR),
+                PollMode::Level,
+            )?;
+            poller.add_with_mode(
+                stdout.as_ref().unwrap(),
+                Event::readable(POLL_STDOUT),
+                PollMode::Level,
+            )?;
         }
-
-        let mut pollfds = [
-            pollfd {
-                fd: stdin_fd,
-                events: POLLOUT,
-                revents: 0,
-            },
-            pollfd {
-                fd: stderr_fd,
-                events: POLLIN,
-                revents: 0,
-            },
-            pollfd {
-                fd: stdout_fd,
-                events: POLLIN,
-                revents: 0,
-            },
-        ];
-
-        if statuses[POLL_STDIN] & (STATUS_EOF | STATUS_ERR) != 0 {
-            // If the child process won't accept further input, there's no
-            // point polling it.
-            pollfds[POLL_STDIN].fd = -1;
-        } else if let Some(stdin_str) = &test.stdin {
-            if stdin_off == stdin_str.len() {
-                // There's nothing to write to the child's stdin, but we'd still
-                // like to check whether it is closed or has suffered an error,
-                // so we don't want to set the fd to -1.
-                pollfds[POLL_STDIN].events = 0;
+        Ok(poller)
+    });
+    let poller = match poller {
+        Ok(x) => Some(x),
+        Err(e) => {
+            if res.is_ok() {
+                res = Err(LangTesterError::Command {
+                    test_name: test_fname.to_owned(),
+                    msg: format!("Couldn't poll stdin and/or stderr and/or stdout: {e:}"),
+                });
             }
+            None
         }
-
-        if statuses[POLL_STDERR] & (STATUS_EOF | STATUS_ERR) != 0 {
-            // If the child's stderr cannot produce further output, there's no
-            // point polling it.
-            pollfds[POLL_STDERR].fd = -1;
+    };
+    let mut events = Events::new();
+    'poll: while res.is_ok() {
+        let poller = poller.as_ref().unwrap();
+        if inner.suite_deadline_passed() {
+            kill_process_group(pgid);
+            suite_timed_out = true;
+            break;
+        }
+        // Are stderr and stdout both closed? If so, we have read everything the child will
+        // output. If stdin is still open, or writing to it failed (e.g. with EPIPE because the
+        // child exited without reading it), the child stopped reading its input: that is not an
+        // error in itself, since the number of bytes it did not consume is checked against the
+        // test's `stdin-remaining`.
+        if statuses[POLL_STDERR] != 0 && statuses[POLL_STDOUT] != 0 {
+            let errored = ["stderr", "stdout"]
+                .iter()
+                .zip(statuses[POLL_STDERR..].iter())
+                .filter(|(_, x)| **x == STATUS_ERR)
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>();
+            if !errored.is_empty() {
+                res = Err(LangTesterError::Command {
+                    test_name: test_fname.to_owned(),
+                    msg: format!("left {} in an error condition", errored.join(" and ")),
+                });
+            }
+            break;
         }
 
-        if statuses[POLL_STDOUT] & (STATUS_EOF | STATUS_ERR) != 0 {
-            // If the child's stdout cannot produce further output, there's no
-            // point polling it.
-            pollfds[POLL_STDOUT].fd = -1;
+        // Wake up in time to warn the user that the command is still running, or to kill it if the
+        // suite timeout expires, whichever is sooner.
+        let timeout = [next_warning, inner.suite_deadline]
+            .iter()
+            .flatten()
+            .min()
+            .map(|x| x.saturating_duration_since(Instant::now()));
+        events.clear();
+        if let Err(e) = poller.wait(&mut events, timeout) {
+            res = Err(LangTesterError::Command {
+                test_name: test_fname.to_owned(),
+                msg: format!("poll failed: {e:}"),
+            });
+            break;
         }
 
-        let timeout = i32::try_from(
-            next_warning
-                .checked_duration_since(Instant::now())
-                .map(|d| d.as_millis())
-                .unwrap_or(1000),
-        )
-        .unwrap_or(1000);
-        if unsafe { poll((&mut pollfds) as *mut _ as *mut pollfd, 3, timeout) } != -1 {
-            assert_eq!(pollfds[POLL_STDIN].revents & POLLNVAL, 0);
-            if pollfds[POLL_STDIN].revents & POLLERR != 0 {
-                assert!(test.stdin.is_some());
-                statuses[POLL_STDIN] = STATUS_ERR;
-                unsafe {
-                    close(stdin_fd);
+        for ev in events.iter() {
+            match ev.key {
+                // Once a stream has been closed, any events which were pending for it are ignored.
+                POLL_STDIN | POLL_STDERR | POLL_STDOUT if statuses[ev.key] != 0 => (),
+                POLL_STDIN if ev.is_err() == Some(true) => {
+                    close_polled(poller, &mut stdin);
+                    statuses[POLL_STDIN] = STATUS_ERR;
                 }
-            } else if pollfds[POLL_STDIN].revents & POLLOUT != 0 {
-                let stdin_str = test.stdin.as_ref().unwrap();
-                match stdin.write(&stdin_str.as_bytes()[stdin_off..]) {
-                    Ok(i) => stdin_off += i,
-                    Err(e) => {
-                        if e.kind() != io::ErrorKind::Interrupted {
-                            unsafe {
-                                close(stdin_fd);
+                POLL_STDIN => {
+                    let stdin_bytes = stdin_bytes.as_ref().unwrap();
+                    match stdin.as_mut().unwrap().write(&stdin_bytes[stdin_off..]) {
+                        Ok(i) => stdin_off += i,
+                        Err(e) => {
+                            if e.kind() != io::ErrorKind::Interrupted {
+                                close_polled(poller, &mut stdin);
+                                statuses[POLL_STDIN] = STATUS_ERR;
                             }
-                            statuses[POLL_STDIN] = STATUS_ERR;
                         }
                     }
-                }
-                debug_assert!(stdin_off <= stdin_str.len());
-                if stdin_off == stdin_str.len() {
-                    // We've fully written to the child's stdin. We close the child's stdin
-                    // explicitly otherwise some child processes will hang, waiting for more input
-                    // to be received.
-                    unsafe {
-                        close(stdin_fd);
+                    debug_assert!(stdin_off <= stdin_bytes.len());
+                    if statuses[POLL_STDIN] == 0 && stdin_off == stdin_bytes.len() {
+                        // We've fully written to the child's stdin. We close the child's stdin
+                        // explicitly otherwise some child processes will hang, waiting for more
+                        // input to be received.
+                        close_polled(poller, &mut stdin);
+                        statuses[POLL_STDIN] = STATUS_EOF;
                     }
-                    statuses[POLL_STDIN] = STATUS_EOF;
-                }
-            } else if pollfds[POLL_STDIN].revents & POLLHUP != 0 {
-                // POSiX specifies that POLLOUT and POLLHUP are mutually exclusive.
-                unsafe {
-                    close(stdin_fd);
                 }
-                statuses[POLL_STDIN] = STATUS_EOF;
-            }
-
-            assert_eq!(pollfds[POLL_STDERR].revents & POLLNVAL, 0);
-            if pollfds[POLL_STDERR].revents & POLLERR != 0 {
-                unsafe {
-                    close(stderr_fd);
+                POLL_STDERR if ev.is_err() == Some(true) => {
+                    close_polled(poller, &mut stderr);
+                    statuses[POLL_STDERR] = STATUS_ERR;
                 }
-                statuses[POLL_STDERR] = STATUS_ERR;
-            } else {
-                if pollfds[POLL_STDERR].revents & POLLIN != 0 {
-                    loop {
-                        match stderr.read(&mut buf) {
-                            Ok(i) => {
-                                if i == 0 {
-                                    // We'll pick up POLLHUP on the next poll()
-                                    break;
+                POLL_STDERR => loop {
+                    // Any incomplete character left over from the previous read is placed
+                    // before the new bytes.
+                    buf[..stderr_partial.len()].copy_from_slice(&stderr_partial);
+                    match stderr
+                        .as_mut()
+                        .unwrap()
+                        .read(&mut buf[stderr_partial.len()..])
+                    {
+                        Ok(i) => {
+                            if i == 0 {
+                                close_polled(poller, &mut stderr);
+                                statuses[POLL_STDERR] = STATUS_EOF;
+                                break;
+                            }
+                            let len = stderr_partial.len() + i;
+                            let utf8 = match split_utf8(&buf[..len]) {
+                                Some((x, incomplete)) => {
+                                    stderr_partial.clear();
+                                    stderr_partial.extend_from_slice(&buf[len - incomplete..len]);
+                                    x
                                 }
-                                let utf8 = str::from_utf8(&buf[..i]).unwrap_or_else(|_| {
-                                    fatal(&format!(
-                                        "Can't convert stderr from '{:?}' into UTF-8",
-                                        cmd
-                                    ))
-                                });
-                                cap_stderr.push_str(utf8);
-                                if inner.nocapture {
-                                    eprint!("{}", utf8);
+                                None => {
+                                    res = Err(LangTesterError::Command {
+                                        test_name: test_fname.to_owned(),
+                                        msg: format!(
+                                            "Can't convert stderr from '{:?}' into UTF-8",
+                                            cmd
+                                        ),
+                                    });
+                                    break 'poll;
                                 }
+                            };
+                            if output_exceeded {
+                                // We've already killed the child: discard any remaining
+                                // output.
+                                continue;
                             }
-                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
-                            Err(e) => {
-                                fatal(&format!("{}: failed to read stderr: {e:}", test_fname))
+                            if !push_capped(&mut cap_stderr, utf8, inner.max_output_bytes) {
+                                output_exceeded = true;
+                                kill_process_group(pgid);
+                            }
+                            if inner.nocapture {
+                                eprint!("{}", utf8);
                             }
                         }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        Err(e) => {
+                            res = Err(LangTesterError::Command {
+                                test_name: test_fname.to_owned(),
+                                msg: format!("failed to read stderr: {e:}"),
+                            });
+                            break 'poll;
+                        }
                     }
+                },
+                POLL_STDOUT if ev.is_err() == Some(true) => {
+                    close_polled(poller, &mut stdout);
+                    statuses[POLL_STDOUT] = STATUS_ERR;
                 }
-                if pollfds[POLL_STDERR].revents & POLLHUP != 0 {
-                    // Note that POLLIN and POLLHUP are not mutually exclusive.
-                    unsafe {
-                        close(stderr_fd);
-                    }
-                    statuses[POLL_STDERR] = STATUS_EOF;
-                }
-            }
-
-            assert_eq!(pollfds[POLL_STDOUT].revents & POLLNVAL, 0);
-            if pollfds[POLL_STDOUT].revents & POLLERR != 0 {
-                unsafe {
-                    close(stdout_fd);
-                }
-                statuses[POLL_STDOUT] = STATUS_ERR;
-            } else {
-                if pollfds[POLL_STDOUT].revents & POLLIN != 0 {
-                    loop {
-                        match stdout.read(&mut buf) {
-                            Ok(i) => {
-                                if i == 0 {
-                                    // We'll pick up POLLHUP on the next poll()
-                                    break;
+                POLL_STDOUT => loop {
+                    // Any incomplete character left over from the previous read is placed
+                    // before the new bytes.
+                    buf[..stdout_partial.len()].copy_from_slice(&stdout_partial);
+                    match stdout
+                        .as_mut()
+                        .unwrap()
+                        .read(&mut buf[stdout_partial.len()..])
+                    {
+                        Ok(i) => {
+                            if i == 0 {
+                                close_polled(poller, &mut stdout);
+                                statuses[POLL_STDOUT] = STATUS_EOF;
+                                break;
+                            }
+                            let len = stdout_partial.len() + i;
+                            let utf8 = match split_utf8(&buf[..len]) {
+                                Some((x, incomplete)) => {
+                                    stdout_partial.clear();
+                                    stdout_partial.extend_from_slice(&buf[len - incomplete..len]);
+                                    x
                                 }
-                                let utf8 = str::from_utf8(&buf[..i]).unwrap_or_else(|_| {
-                                    fatal(&format!(
-                                        "Can't convert stdout from '{:?}' into UTF-8",
-                                        cmd
-                                    ))
-                                });
-                                cap_stdout.push_str(utf8);
-                                if inner.nocapture {
-                                    eprint!("{}", utf8);
+                                None => {
+                                    res = Err(LangTesterError::Command {
+                                        test_name: test_fname.to_owned(),
+                                        msg: format!(
+                                            "Can't convert stdout from '{:?}' into UTF-8",
+                                            cmd
+                                        ),
+                                    });
+                                    break 'poll;
                                 }
+                            };
+                            if output_exceeded {
+                                // We've already killed the child: discard any remaining
+                                // output.
+                                continue;
                             }
-                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
-                            Err(e) => {
-                                fatal(&format!("{}: failed to read stdout: {e:}", test_fname))
+                            if !push_capped(&mut cap_stdout, utf8, inner.max_output_bytes) {
+                                output_exceeded = true;
+                                kill_process_group(pgid);
+                            }
+                            if inner.nocapture {
+                                eprint!("{}", utf8);
                             }
                         }
-                    }
-                }
-                if pollfds[POLL_STDOUT].revents & POLLHUP != 0 {
-                    // Note that POLLIN and POLLHUP are not mutually exclusive.
-                    if statuses[POLL_STDOUT] & STATUS_EOF == 0 {
-                        unsafe {
-                            close(stdout_fd);
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        Err(e) if test.pty && e.raw_os_error() == Some(EIO) => {
+                            // Reading from a pseudo-terminal's master side fails with EIO once
+                            // the slave side has been closed (i.e. the child has exited).
+                            close_polled(poller, &mut stdout);
+                            statuses[POLL_STDOUT] = STATUS_EOF;
+                            break;
+                        }
+                        Err(e) => {
+                            res = Err(LangTesterError::Command {
+                                test_name: test_fname.to_owned(),
+                                msg: format!("failed to read stdout: {e:}"),
+                            });
+                            break 'poll;
                         }
-                        statuses[POLL_STDOUT] = STATUS_EOF;
                     }
-                }
+                },
+                _ => unreachable!(),
             }
         }
 
-        if Instant::now() >= next_warning {
-            let running_for = ((Instant::now() - start).as_secs() / TIMEOUT) * TIMEOUT;
-            if inner.test_threads == 1 {
-                eprint!("running for over {} seconds... ", running_for);
-            } else {
-                eprintln!(
-                    "\nlang_tests::{} ... has been running for over {} seconds",
-                    test_fname, running_for
-                );
+        warn_if_running_long(&inner, test_fname, start, &mut next_warning);
+    }
+    if res.is_ok() && !output_exceeded {
+        // If a stream ended part way through a character, it was not valid UTF-8.
+        for (name, partial) in [("stderr", &stderr_partial), ("stdout", &stdout_partial)] {
+            if !partial.is_empty() {
+                res = Err(LangTesterError::Command {
+                    test_name: test_fname.to_owned(),
+                    msg: format!("Can't convert {} from '{:?}' into UTF-8", name, cmd),
+                });
             }
-            last_warning = next_warning;
-            next_warning = last_warning
-                .checked_add(Duration::from_secs(TIMEOUT))
-                .unwrap();
         }
     }
-    if statuses[POLL_STDIN] != 0 {
-        std::mem::forget(stdin);
+    if res.is_err() {
+        kill_process_group(pgid);
     }
-    if statuses[POLL_STDERR] != 0 {
-        std::mem::forget(stderr);
+    // Close any streams which are still open. Dropping `poller` first means that they no longer
+    // need to be removed from it.
+    drop(poller);
+    drop((stdin, stderr, stdout));
+
+    // Block until the child exits, waking up only to warn the user that the command is still
+    // running, or to kill it if the suite timeout expires. `wait4` can't wait with a timeout, so
+    // if we might need to wake up, a helper thread blocks on the child's exit for us.
+    let pid = child.id() as pid_t;
+    let mut exited: Option<mpsc::Receiver<io::Result<(ExitStatus, Option<u64>)>>> = None;
+    let waited = loop {
+        let wake = if suite_timed_out {
+            next_warning
+        } else {
+            [next_warning, inner.suite_deadline]
+                .iter()
+                .flatten()
+                .min()
+                .copied()
+        };
+        let waited = match wake {
+            None if exited.is_none() => wait_child(pid).map(Some),
+            // The helper thread always sends its result before exiting.
+            None => exited.as_ref().unwrap().recv().unwrap().map(Some),
+            Some(x) => {
+                let rx = exited.get_or_insert_with(|| {
+                    let (tx, rx) = mpsc::channel();
+                    thread::spawn(move || tx.send(wait_child(pid)).ok());
+                    rx
+                });
+                match rx.recv_timeout(x.saturating_duration_since(Instant::now())) {
+                    Ok(x) => x.map(Some),
+                    Err(RecvTimeoutError::Timeout) => Ok(None),
+                    Err(RecvTimeoutError::Disconnected) => unreachable!(),
+                }
+            }
+        };
+        match waited {
+            Ok(Some(x)) => break Ok(x),
+            Ok(None) => (),
+            Err(e) => {
+                kill_process_group(pgid);
+                break Err(LangTesterError::Command {
+                    test_name: test_fname.to_owned(),
+                    msg: format!("{:?} did not exit correctly: {:?}", cmd, e),
+                });
+            }
+        }
+
+        if inner.suite_deadline_passed() && !suite_timed_out {
+            kill_process_group(pgid);
+            suite_timed_out = true;
+        }
+        warn_if_running_long(&inner, test_fname, start, &mut next_warning);
+    };
+
+    // Since the child has been reaped, its process group ID can only be reused once any processes
+    // the child spawned have also exited: there is thus no need to keep it around.
+    CHILD_PGIDS.lock().unwrap().retain(|x| *x != pgid);
+    res?;
+    let (status, peak_rss) = waited?;
+    if inner.verbose {
+        if let Some(peak_rss) = peak_rss {
+            inner.reporter().test_message(
+                test_fname,
+                &format!("{}: peak RSS {} bytes", cmd_name, peak_rss),
+            );
+        }
     }
-    if statuses[POLL_STDOUT] != 0 {
-        std::mem::forget(stdout);
+
+    // Anything we added to a pseudo-terminal's stdin is not counted.
+    let stdin_remaining = match (&test.stdin, &stdin_bytes) {
+        (Some(stdin_str), Some(stdin_bytes)) => min(stdin_str.len(), stdin_bytes.len() - stdin_off),
+        _ => 0,
+    };
+    Ok(CmdResult {
+        status,
+        stdin_remaining,
+        stderr: cap_stderr,
+        stdout: cap_stdout,
+        output_exceeded,
+        peak_rss,
+        suite_timed_out,
+    })
+}
+
+/// Remove from `s` every line (excluding its line terminator) which `re` matches. If `re` is
+/// `None`, `s` is returned unchanged.
+fn remove_lines(re: Option<&Regex>, s: String) -> String {
+    match re {
+        Some(re) => s
+            .split_inclusive('\n')
+            .filter(|l| !re.is_match(l.trim_end_matches('\n').trim_end_matches('\r')))
+            .collect(),
+        None => s,
     }
+}
 
-    let status = {
-        // We have no idea how long it will take the child process to exit. In practise, the mere
-        // act of yielding (via sleep) for a ridiculously short period of time will often be enough
-        // for the child process to exit. So we use an exponentially increasing timeout with a very
-        // short initial period so that, in the common case, we don't waste time waiting for
-        // something that's almost certainly already occurred.
-        let mut wait_timeout = INITIAL_WAIT_TIMEOUT;
-        loop {
-            match child.try_wait() {
-                Ok(Some(s)) => break s,
-                Ok(None) => (),
-                Err(e) => fatal(&format!("{:?} did not exit correctly: {:?}", cmd, e)),
+/// Block until the child process `pid` exits, returning its exit status and (if the platform
+/// reports it) its peak resident set size in bytes. `Child::wait` does not report resource usage,
+/// so we reap the child ourselves with `wait4`.
+fn wait_child(pid: pid_t) -> io::Result<(ExitStatus, Option<u64>)> {
+    loop {
+        let mut status = 0;
+        let mut usage = unsafe { mem::zeroed::<libc::rusage>() };
+        match unsafe { wait4(pid, &mut status, 0, &mut usage) } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
             }
-
-            if Instant::now() >= next_warning {
-                let running_for = ((Instant::now() - start).as_secs() / TIMEOUT) * TIMEOUT;
-                if inner.test_threads == 1 {
-                    eprint!("running for over {} seconds... ", running_for);
+            _ => {
+                // `ru_maxrss` is in bytes on macOS, but in kilobytes on other Unix platforms.
+                let maxrss = u64::try_from(usage.ru_maxrss).ok().filter(|x| *x > 0);
+                let peak_rss = if cfg!(target_os = "macos") {
+                    maxrss
                 } else {
-                    eprintln!(
-                        "\nlang_tests::{} ... has been running for over {} seconds",
-                        test_fname, running_for
-                    );
-                }
-                last_warning = next_warning;
-                next_warning = last_warning
-                    .checked_add(Duration::from_secs(TIMEOUT))
-                    .unwrap();
+                    maxrss.map(|x| x.saturating_mul(1024))
+                };
+                return Ok((ExitStatus::from_raw(status), peak_rss));
             }
-            sleep(Duration::from_nanos(wait_timeout));
-            wait_timeout *= 2;
-            if wait_timeout > MAX_WAIT_TIMEOUT {
-                wait_timeout = MAX_WAIT_TIMEOUT;
+        }
+    }
+}
+
+/// Remove `stream` (if it is still open) from `poller`, and close it.
+fn close_polled<T: AsFd>(poller: &Poller, stream: &mut Option<T>) {
+    if let Some(x) = stream.take() {
+        poller.delete(&x).ok();
+    }
+}
+
+/// If `next_warning` has passed, warn the user that the command started at `start` is still
+/// running (showing how long it has actually been running for, which may be a little more than a
+/// multiple of the progress interval), and schedule the next warning.
+fn warn_if_running_long(
+    inner: &LangTesterPooler,
+    test_fname: &str,
+    start: Instant,
+    next_warning: &mut Option<Instant>,
+) {
+    if let (Some(nw), Some(interval)) = (*next_warning, inner.progress_interval) {
+        let now = Instant::now();
+        if now >= nw {
+            inner.reporter().test_running_long(test_fname, now - start);
+            // If we were not woken up for a while, don't issue several warnings in a row.
+            let mut nw = nw + interval;
+            while nw <= now {
+                nw += interval;
             }
+            *next_warning = Some(nw);
         }
-    };
+    }
+}
 
-    let stdin_remaining = if let Some(stdin_str) = &test.stdin {
-        stdin_str.len() - stdin_off
-    } else {
-        0
+/// Convert `buf` into a string, returning `None` if it is not valid UTF-8. Since `buf` may end part
+/// way through a multi-byte character, such an incomplete character is not treated as an error:
+/// it is excluded from the returned string, and its length in bytes is returned alongside it.
+fn split_utf8(buf: &[u8]) -> Option<(&str, usize)> {
+    match str::from_utf8(buf) {
+        Ok(s) => Some((s, 0)),
+        Err(e) if e.error_len().is_none() => {
+            let (valid, incomplete) = buf.split_at(e.valid_up_to());
+            Some((str::from_utf8(valid).unwrap(), incomplete.len()))
+        }
+        Err(_) => None,
+    }
+}
+
+/// Append `s` to `cap` unless that would cause `cap` to exceed `max` bytes, in which case as much
+/// of `s` as fits is appended and `false` is returned.
+fn push_capped(cap: &mut String, s: &str, max: Option<usize>) -> bool {
+    match max {
+        Some(max) if cap.len() + s.len() > max => {
+            let mut end = max.saturating_sub(cap.len());
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            cap.push_str(&s[..end]);
+            false
+        }
+        _ => {
+            cap.push_str(s);
+            true
+        }
+    }
+}
+
+/// A simple counting semaphore.
+struct Semaphore {
+    available: Mutex<usize>,
+    cvar: Condvar,
+}
+
+impl Semaphore {
+    fn new(available: usize) -> Self {
+        Semaphore {
+            available: Mutex::new(available),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Block until a permit is available, returning a guard which releases the permit when
+    /// dropped.
+    fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.cvar.wait(available).unwrap();
+        }
+        *available -= 1;
+        SemaphoreGuard(self)
+    }
+}
+
+struct SemaphoreGuard<'a>(&'a Semaphore);
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.cvar.notify_one();
+    }
+}
+
+/// Send `SIGKILL` to every process in the process group `pgid`.
+pub(crate) fn kill_process_group(pgid: pid_t) {
+    unsafe {
+        kill(-pgid, SIGKILL);
+    }
+}
+
+/// Kill the process groups of all commands currently being run by any `LangTester` (including any
+/// processes those commands have spawned). Since each command is run in its own process group, it
+/// does not receive signals sent to the caller's process group (e.g. from Ctrl-C): programs which
+/// call [`LangTester::try_run`](struct.LangTester.html#method.try_run) or
+/// [`LangTester::run_with_summary`](struct.LangTester.html#method.run_with_summary) should thus
+/// call this when they are interrupted. Since this takes a lock, it must not be called from within
+/// a signal handler: call it instead from a thread which waits for signals (e.g. with `sigwait`).
+pub fn kill_child_process_groups() {
+    // If another thread panicked while holding the lock, we still want to kill the children.
+    let pgids = CHILD_PGIDS.lock().unwrap_or_else(|e| e.into_inner());
+    for pgid in pgids.iter() {
+        kill_process_group(*pgid);
+    }
+}
+
+/// Since child processes are placed in their own process groups, they do not receive signals
+/// (e.g. from Ctrl-C) sent to our process group. We thus block `SIGINT` and `SIGTERM` in the
+/// calling thread (and thus in any threads it subsequently creates) and spawn a thread which waits
+/// for either: if received, all child process groups are killed before we exit. This must be
+/// called before any test threads are created.
+fn install_signal_handler() -> Result<(), LangTesterError> {
+    let mut res = Ok(());
+    SIGNAL_HANDLER.call_once(|| {
+        let set = unsafe {
+            let mut set = mem::zeroed::<sigset_t>();
+            sigemptyset(&mut set);
+            sigaddset(&mut set, SIGINT);
+            sigaddset(&mut set, SIGTERM);
+            let rtn = pthread_sigmask(SIG_BLOCK, &set, ptr::null_mut());
+            if rtn != 0 {
+                res = Err(LangTesterError::Signals(io::Error::from_raw_os_error(rtn)));
+                return;
+            }
+            set
+        };
+        thread::spawn(move || {
+            let mut sig = 0;
+            if unsafe { sigwait(&set, &mut sig) } == 0 {
+                kill_child_process_groups();
+                process::exit(128 + sig);
+            }
+        });
+    });
+    res
+}
+
+/// Open a pseudo-terminal, returning a tuple (a reading handle to the master side, a writing handle
+/// to the master side, a handle to the slave side). All handles are close-on-exec. The terminal
+/// does not echo input and does not translate `\n` in output into `\r\n`, but otherwise
+/// processes input line-by-line as normal.
+fn open_pty() -> Result<(File, File, File), io::Error> {
+    let mut master = -1;
+    let mut slave = -1;
+    let ws = winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
     };
-    (status, stdin_remaining, cap_stderr, cap_stdout)
+    // Some platforms declare `openpty`'s `termp` and `winp` arguments as `*mut`, others as
+    // `*const`: the casts keep us portable between them.
+    if unsafe {
+        openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null_mut::<termios>() as _,
+            &ws as *const winsize as _,
+        )
+    } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+        let flags = unsafe { fcntl(fd, F_GETFD) };
+        if flags == -1 || unsafe { fcntl(fd, F_SETFD, flags | FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let mut tio = unsafe { mem::zeroed::<termios>() };
+    if unsafe { tcgetattr(slave.as_raw_fd(), &mut tio) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let c_cc = tio.c_cc;
+    unsafe { cfmakeraw(&mut tio) };
+    tio.c_lflag |= ICANON | ISIG;
+    tio.c_lflag &= !ECHO;
+    tio.c_cc = c_cc;
+    tio.c_cc[VEOF] = PTY_EOF;
+    if unsafe { tcsetattr(slave.as_raw_fd(), TCSANOW, &tio) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let master_w = master.try_clone()?;
+    Ok((master, master_w, slave))
+}
+
+/// Limit the address space and data segment size of the current process to `bytes` (or the
+/// existing hard limit, if that is lower). This is intended to be called in a child process
+/// before `exec`, and only calls async-signal-safe functions.
+fn limit_memory(bytes: u64) -> Result<(), io::Error> {
+    for resource in [RLIMIT_AS, RLIMIT_DATA] {
+        let mut rlim = rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { getrlimit(resource, &mut rlim) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        rlim.rlim_cur = rlim_t::try_from(bytes)
+            .unwrap_or(rlim_t::MAX)
+            .min(rlim.rlim_max);
+        if unsafe { setrlimit(resource, &rlim) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Set the niceness of the current process to `nice`. This is intended to be called in a child
+/// process before `exec`, and only calls async-signal-safe functions.
+fn set_nice(nice: i32) -> Result<(), io::Error> {
+    if unsafe { setpriority(PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
 }
 
 fn set_nonblock(fd: c_int) -> Result<(), io::Error> {
@@ -1307,3 +4658,57 @@ fn set_nonblock(fd: c_int) -> Result<(), io::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract_cache_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let cache_path = dir.path().join("cache");
+        let a = dir.path().join("a.rs");
+        let b = dir.path().join("b.rs");
+        fs::write(&a, "").unwrap();
+        fs::write(&b, "").unwrap();
+        let entry = |len, test_str: Option<&str>| CachedExtract {
+            modified: UNIX_EPOCH + Duration::new(1_700_000_000, 123),
+            len,
+            test_str: test_str.map(|x| x.to_owned()),
+        };
+
+        let mut cache = HashMap::new();
+        cache.insert(a.clone(), entry(10, Some("Run:\n  stdout: x\n")));
+        cache.insert(b.clone(), entry(20, None));
+        write_extract_cache(&cache_path, &cache);
+        let cached = read_extract_cache(&cache_path);
+        assert_eq!(cached.len(), 2);
+        assert_eq!(cached[&a].modified, cache[&a].modified);
+        assert_eq!(cached[&a].len, 10);
+        assert_eq!(cached[&a].test_str.as_deref(), Some("Run:\n  stdout: x\n"));
+        assert_eq!(cached[&b].len, 20);
+        assert_eq!(cached[&b].test_str, None);
+
+        // Writing a cache merges it with the existing entries (for files which still exist).
+        fs::remove_file(&b).unwrap();
+        let mut cache = HashMap::new();
+        cache.insert(a.clone(), entry(30, Some("")));
+        write_extract_cache(&cache_path, &cache);
+        let cached = read_extract_cache(&cache_path);
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[&a].len, 30);
+        assert_eq!(cached[&a].test_str.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_read_extract_cache_entry() {
+        let (p, entry, rest) = read_extract_cache_entry("a.rs\n1 2 3 2\nxy\nb.rs").unwrap();
+        assert_eq!(p, PathBuf::from("a.rs"));
+        assert_eq!(entry.len, 3);
+        assert_eq!(entry.test_str.as_deref(), Some("xy"));
+        assert_eq!(rest, "b.rs");
+        assert!(read_extract_cache_entry("a.rs\n1 2 3 5\nxy\n").is_none());
+        assert!(read_extract_cache_entry("a.rs\n1 x 3\n").is_none());
+        assert!(read_extract_cache_entry("a.rs").is_none());
+    }
+}
@@ -0,0 +1,446 @@
+//! A parser for test data written in YAML, used instead of the default format if
+//! [`yaml_tests`](../struct.LangTester.html#method.yaml_tests) is set. It produces the same `Tests`
+//! as the default parser, and keys have the same meaning in both formats.
+
+use std::{
+    borrow::Cow,
+    collections::{
+        hash_map::{Entry, HashMap},
+        HashSet,
+    },
+    str::Chars,
+};
+
+use yaml_rust2::{
+    parser::{Event, Parser},
+    scanner::TScalarStyle,
+};
+
+use crate::{
+    parser::{parse_arches, parse_tags, set_key, CustomKeys, ParseError, StatusAliases},
+    tester::{TestCmd, Tests},
+};
+
+/// A YAML value. Every child value is paired with the line offset (starting from 0) at which it
+/// starts.
+enum Node {
+    Null,
+    Scalar(String),
+    Seq(Vec<(usize, Node)>),
+    Map(Vec<(usize, String, Node)>),
+}
+
+/// Parse YAML test data into a set of `Test`s.
+pub(crate) fn parse_yaml_tests(
+    custom_keys: &CustomKeys,
+    status_aliases: &StatusAliases,
+    test_str: &str,
+) -> Result<Tests<'static>, ParseError> {
+    let mut loader = Loader {
+        parser: Parser::new_from_str(test_str),
+    };
+    let root = loader.document()?;
+    let entries = match root {
+        (_, Node::Map(entries)) => entries,
+        (_, Node::Null) => Vec::new(),
+        (line_off, _) => {
+            return Err(ParseError::new(
+                line_off,
+                "Test data must be a YAML mapping.".to_owned(),
+            ))
+        }
+    };
+
+    let mut tests = HashMap::new();
+    let mut ignore_if = None;
+    let mut ignore_if_arch = None;
+    let mut ignore_unless_stdout = None;
+    let mut ignore_unless_stdout_line_off = 0;
+    let mut tags = None;
+    for (line_off, key, val) in entries {
+        match key.as_str() {
+            "ignore-if" => {
+                if ignore_if.is_some() {
+                    return Err(specified_twice(line_off, &key));
+                }
+                ignore_if = Some(scalar(line_off, &key, val)?);
+            }
+            "ignore-if-arch" => {
+                if ignore_if_arch.is_some() {
+                    return Err(specified_twice(line_off, &key));
+                }
+                // Architectures can be given as a sequence or a whitespace-separated string.
+                let val = match val {
+                    Node::Seq(items) => items
+                        .into_iter()
+                        .map(|(line_off, x)| scalar(line_off, &key, x))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .join(" "),
+                    val => scalar(line_off, &key, val)?,
+                };
+                ignore_if_arch = Some(parse_arches(&val, line_off)?);
+            }
+            "ignore-unless-stdout" => {
+                if ignore_unless_stdout.is_some() {
+                    return Err(specified_twice(line_off, &key));
+                }
+                ignore_unless_stdout_line_off = line_off;
+                ignore_unless_stdout = Some(scalar(line_off, &key, val)?);
+            }
+            "tags" => {
+                if tags.is_some() {
+                    return Err(specified_twice(line_off, &key));
+                }
+                // Tags can be given as a sequence or a comma-separated string.
+                let val = match val {
+                    Node::Seq(items) => items
+                        .into_iter()
+                        .map(|(line_off, x)| scalar(line_off, &key, x))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .join(","),
+                    val => scalar(line_off, &key, val)?,
+                };
+                tags = Some(parse_tags(&val, line_off)?);
+            }
+            _ => match tests.entry(key.to_lowercase()) {
+                Entry::Occupied(_) => {
+                    return Err(ParseError::new(
+                        line_off,
+                        format!("Command name '{}' is specified more than once.", key),
+                    ))
+                }
+                Entry::Vacant(e) => {
+                    e.insert(test_cmd(custom_keys, status_aliases, line_off, &key, val)?);
+                }
+            },
+        }
+    }
+    if ignore_unless_stdout.is_some() && ignore_if.is_none() {
+        return Err(ParseError::new(
+            ignore_unless_stdout_line_off,
+            "'ignore-unless-stdout' requires 'ignore-if' to be specified.".to_owned(),
+        ));
+    }
+    Ok(Tests {
+        ignore_if,
+        ignore_if_arch,
+        ignore_unless_stdout,
+        tags: tags.unwrap_or_default(),
+        tests,
+    })
+}
+
+/// Convert `node`, the value of the command `name` (found at line offset `line_off`), into a
+/// `TestCmd`.
+fn test_cmd(
+    custom_keys: &CustomKeys,
+    status_aliases: &StatusAliases,
+    line_off: usize,
+    name: &str,
+    node: Node,
+) -> Result<TestCmd<'static>, ParseError> {
+    let entries = match node {
+        Node::Map(entries) => entries,
+        Node::Null => Vec::new(),
+        _ => {
+            return Err(ParseError::new(
+                line_off,
+                format!("Command '{}' must be a mapping.", name),
+            ))
+        }
+    };
+    let mut testcmd = TestCmd {
+        line: Some(line_off + 1),
+        ..TestCmd::default()
+    };
+    let mut seen = HashSet::new();
+    for (key_line_off, key, val) in entries {
+        if !seen.insert(key.clone()) {
+            return Err(specified_twice(key_line_off, &key));
+        }
+        match val {
+            // Keys which can be specified more than once in the default format take a sequence.
+            Node::Seq(items)
+                if matches!(key.as_str(), "env-var" | "exec-arg" | "exec-args-split") =>
+            {
+                for (item_line_off, item) in items {
+                    let item = scalar(item_line_off, &key, item)?;
+                    set_key(
+                        &mut testcmd,
+                        custom_keys,
+                        status_aliases,
+                        &key,
+                        lines(&item),
+                        item_line_off,
+                    )?;
+                }
+            }
+            // Environment variables can also be given as a mapping, where a null value removes
+            // the variable.
+            Node::Map(vars) if key == "env-var" => {
+                for (var_line_off, var, val) in vars {
+                    let var = match val {
+                        Node::Null => var,
+                        val => format!("{}={}", var, scalar(var_line_off, &var, val)?),
+                    };
+                    set_key(
+                        &mut testcmd,
+                        custom_keys,
+                        status_aliases,
+                        &key,
+                        lines(&var),
+                        var_line_off,
+                    )?;
+                }
+            }
+            val => {
+                let val = scalar(key_line_off, &key, val)?;
+                set_key(
+                    &mut testcmd,
+                    custom_keys,
+                    status_aliases,
+                    &key,
+                    lines(&val),
+                    key_line_off,
+                )?;
+            }
+        }
+    }
+    Ok(testcmd)
+}
+
+/// Return the string value of `node` (the value of `key`, found at line offset `line_off`). A
+/// null value is treated as the empty string.
+fn scalar(line_off: usize, key: &str, node: Node) -> Result<String, ParseError> {
+    match node {
+        Node::Scalar(s) => Ok(s),
+        Node::Null => Ok(String::new()),
+        Node::Seq(_) | Node::Map(_) => Err(ParseError::new(
+            line_off,
+            format!("The value of '{}' must be a string.", key),
+        )),
+    }
+}
+
+/// Return an error noting that `key` (found at line offset `line_off`) was specified more than
+/// once.
+fn specified_twice(line_off: usize, key: &str) -> ParseError {
+    ParseError::new(line_off, format!("'{}' is specified more than once.", key))
+}
+
+/// Split `s` into lines, in the form expected by `set_key`.
+fn lines(s: &str) -> Vec<Cow<'static, str>> {
+    s.lines().map(|x| Cow::Owned(x.to_owned())).collect()
+}
+
+/// Builds `Node`s from the events of a YAML parser.
+struct Loader<'a> {
+    parser: Parser<Chars<'a>>,
+}
+
+impl Loader<'_> {
+    /// Return the next event and the line offset at which it was found.
+    fn next(&mut self) -> Result<(Event, usize), ParseError> {
+        match self.parser.next_token() {
+            Ok((ev, mark)) => Ok((ev, mark.line().saturating_sub(1))),
+            Err(e) => Err(ParseError::new(
+                e.marker().line().saturating_sub(1),
+                format!("Invalid YAML: {}.", e.info()),
+            )),
+        }
+    }
+
+    /// Parse a stream containing (at most) a single document, returning its root node and the
+    /// line offset at which it starts.
+    fn document(&mut self) -> Result<(usize, Node), ParseError> {
+        let mut root = None;
+        loop {
+            match self.next()? {
+                (Event::StreamStart, _) | (Event::DocumentEnd, _) => (),
+                (Event::StreamEnd, _) => break,
+                (Event::DocumentStart, line_off) => {
+                    if root.is_some() {
+                        return Err(ParseError::new(
+                            line_off,
+                            "Test data must contain a single YAML document.".to_owned(),
+                        ));
+                    }
+                    let (ev, line_off) = self.next()?;
+                    root = Some((line_off, self.node(ev, line_off)?));
+                }
+                (_, line_off) => return Err(ParseError::new(line_off, "Invalid YAML.".to_owned())),
+            }
+        }
+        Ok(root.unwrap_or((0, Node::Null)))
+    }
+
+    /// Convert `ev` (found at line offset `line_off`), and any events which make up its children,
+    /// into a `Node`.
+    fn node(&mut self, ev: Event, line_off: usize) -> Result<Node, ParseError> {
+        match ev {
+            Event::Scalar(s, style, _, _) => {
+                if style == TScalarStyle::Plain
+                    && matches!(s.as_str(), "" | "~" | "null" | "Null" | "NULL")
+                {
+                    Ok(Node::Null)
+                } else {
+                    Ok(Node::Scalar(s))
+                }
+            }
+            Event::SequenceStart(_, _) => {
+                let mut items = Vec::new();
+                loop {
+                    match self.next()? {
+                        (Event::SequenceEnd, _) => break,
+                        (ev, line_off) => items.push((line_off, self.node(ev, line_off)?)),
+                    }
+                }
+                Ok(Node::Seq(items))
+            }
+            Event::MappingStart(_, _) => {
+                let mut entries = Vec::new();
+                loop {
+                    match self.next()? {
+                        (Event::MappingEnd, _) => break,
+                        (Event::Scalar(key, _, _, _), key_line_off) => {
+                            let (ev, line_off) = self.next()?;
+                            entries.push((key_line_off, key, self.node(ev, line_off)?));
+                        }
+                        (_, line_off) => {
+                            return Err(ParseError::new(
+                                line_off,
+                                "Keys must be strings.".to_owned(),
+                            ))
+                        }
+                    }
+                }
+                Ok(Node::Map(entries))
+            }
+            Event::Alias(_) => Err(ParseError::new(
+                line_off,
+                "YAML aliases are not supported.".to_owned(),
+            )),
+            _ => Err(ParseError::new(line_off, "Invalid YAML.".to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tester::Status;
+
+    #[test]
+    fn test_parse_yaml_tests() {
+        let tests = parse_yaml_tests(
+            &CustomKeys::new(),
+            &StatusAliases::new(),
+            "ignore-if-arch: [x86_64, aarch64]
+tags: [smoke, regression]
+Compiler:
+  status: error
+  stderr: |
+    warning: unused variable
+    ...
+  env-var:
+    A: b
+    HOME: ~
+  exec-arg: [-v, x y]
+Run-time:
+  stdout: Hello world
+  pty: true
+  repeat: 2
+",
+        )
+        .unwrap();
+        assert_eq!(
+            tests.ignore_if_arch,
+            Some(vec!["x86_64".to_owned(), "aarch64".to_owned()])
+        );
+        assert!(tests.tags.contains("smoke") && tests.tags.contains("regression"));
+        let compiler = &tests.tests["compiler"];
+        assert_eq!(compiler.status, Some(Status::Error));
+        assert_eq!(compiler.stderr, vec!["warning: unused variable", "..."]);
+        assert_eq!(compiler.env["A"], Some("b".to_owned()));
+        assert_eq!(compiler.env["HOME"], None);
+        assert_eq!(compiler.args, vec!["-v", "x y"]);
+        let run_time = &tests.tests["run-time"];
+        assert_eq!(run_time.stdout, vec!["Hello world"]);
+        assert!(run_time.pty);
+        assert_eq!(run_time.repeat, 2);
+
+        let tests = parse_yaml_tests(&CustomKeys::new(), &StatusAliases::new(), "x:\n").unwrap();
+        assert_eq!(tests.tests["x"].status, None);
+    }
+
+    #[test]
+    fn test_parse_yaml_errors() {
+        assert_eq!(
+            parse_yaml_tests(
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: success\n  unknown: 1"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 3,
+                msg: "Unknown key 'unknown'.".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_yaml_tests(
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: [a]"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 2,
+                msg: "The value of 'status' must be a string.".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_yaml_tests(
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: 1\n  status: 2"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 3,
+                msg: "'status' is specified more than once.".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_yaml_tests(
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: 1\nX:"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 3,
+                msg: "Command name 'X' is specified more than once.".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_yaml_tests(&CustomKeys::new(), &StatusAliases::new(), "- x").unwrap_err(),
+            ParseError {
+                line: 1,
+                msg: "Test data must be a YAML mapping.".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_yaml_tests(
+                &CustomKeys::new(),
+                &StatusAliases::new(),
+                "x:\n  status: a\n    stdout: b"
+            )
+            .unwrap_err(),
+            ParseError {
+                line: 3,
+                msg: "Invalid YAML: mapping values are not allowed in this context.".to_owned()
+            }
+        );
+    }
+}